@@ -0,0 +1,335 @@
+//! The differences between two trees, found by descending only into children
+//! whose hashes differ: `O(k h)` for `k` differences in an `h`-tall tree.
+//!
+//! Differences are reported one at a time as they are found, so a diff is
+//! never held in memory as a whole. An added or removed directory is reported
+//! once, not once per file below it.
+
+use crate::protocol::{Entry, Hello};
+use crate::Client;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+/// How many directories are listed at once.
+pub const BATCH: usize = 256;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub kind: ChangeKind,
+    /// Relative to the two paths being compared; empty for the paths themselves.
+    #[serde(with = "crate::protocol::path")]
+    pub path: PathBuf,
+    /// `st_mode` on the new side, or on the old side if the entry was removed.
+    pub mode: u32,
+}
+
+/// One of the two trees being compared.
+pub trait Side {
+    /// `path` itself, named after its last component.
+    fn entry(&mut self, path: &Path) -> io::Result<Entry>;
+
+    /// The children of each of `paths` that are included in its hash, or
+    /// `None` for those that are not directories.
+    fn children(&mut self, paths: &[PathBuf]) -> io::Result<Vec<Option<Vec<Entry>>>>;
+}
+
+/// Compares `old_path` in `old` with `new_path` in `new`, calling `each` with
+/// every difference. An entry that changed between a directory and anything
+/// else is reported as removed and added again.
+///
+/// The trees are compared a level at a time, listing up to [`BATCH`]
+/// directories of a side in one call, so a remote side costs a round trip per
+/// level rather than one per directory.
+pub fn diff(
+    old: &mut impl Side,
+    old_path: &Path,
+    new: &mut impl Side,
+    new_path: &Path,
+    each: &mut impl FnMut(Change) -> io::Result<()>,
+) -> io::Result<()> {
+    let (a, b) = (old.entry(old_path)?, new.entry(new_path)?);
+    if same(&a, &b) {
+        return Ok(());
+    }
+    let mut diff = Diff {
+        old,
+        new,
+        each,
+        next: Vec::new(),
+    };
+    let root = Pair {
+        old: old_path.to_owned(),
+        new: new_path.to_owned(),
+        path: PathBuf::new(),
+        mode: b.mode,
+    };
+    diff.entry(root, a.mode)?;
+    while !diff.next.is_empty() {
+        let level = mem::take(&mut diff.next);
+        for batch in level.chunks(BATCH) {
+            diff.directories(batch)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compares `old_path` in the mount served by `old` with `new_path` in the
+/// mount served by `new`, which must hash the same way: with the same
+/// algorithm, format, ignore setting and attributes.
+pub fn between(
+    old: &mut Client,
+    old_path: &Path,
+    new: &mut Client,
+    new_path: &Path,
+    each: &mut impl FnMut(Change) -> io::Result<()>,
+) -> io::Result<()> {
+    let (a, b) = (settings(old.hello()), settings(new.hello()));
+    if a != b {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot compare {} hashes with {} hashes", a, b),
+        ));
+    }
+    diff(old, old_path, new, new_path, each)
+}
+
+/// Everything hashes depend on besides the tree, as in
+/// `sha1/plain/ignore=git/attrs=perm`.
+fn settings(hello: &Hello) -> String {
+    let mut settings = format!("{}/{}", hello.algorithm, hello.format);
+    if let Some(ignore) = &hello.ignore {
+        settings = settings + "/ignore=" + ignore;
+    }
+    if let Some(attrs) = &hello.attrs {
+        settings = settings + "/attrs=" + attrs;
+    }
+    settings
+}
+
+/// Whether two entries are the same: a format may leave part of the mode out
+/// of hashes, so the type and permissions are compared too.
+fn same(a: &Entry, b: &Entry) -> bool {
+    a.hash == b.hash && a.mode & (S_IFMT | 0o7777) == b.mode & (S_IFMT | 0o7777)
+}
+
+fn is_dir(mode: u32) -> bool {
+    mode & S_IFMT == S_IFDIR
+}
+
+/// An entry present on both sides with different hashes.
+struct Pair {
+    old: PathBuf,
+    new: PathBuf,
+    /// Relative to the paths being compared.
+    path: PathBuf,
+    /// The mode on the new side.
+    mode: u32,
+}
+
+struct Diff<'a, O, N, F> {
+    old: &'a mut O,
+    new: &'a mut N,
+    each: &'a mut F,
+    /// Directories to compare on the next level down.
+    next: Vec<Pair>,
+}
+
+impl<O: Side, N: Side, F: FnMut(Change) -> io::Result<()>> Diff<'_, O, N, F> {
+    fn entry(&mut self, pair: Pair, old_mode: u32) -> io::Result<()> {
+        if is_dir(old_mode) != is_dir(pair.mode) {
+            self.report(ChangeKind::Removed, pair.path.clone(), old_mode)?;
+            self.report(ChangeKind::Added, pair.path, pair.mode)
+        } else if is_dir(pair.mode) {
+            self.next.push(pair);
+            Ok(())
+        } else {
+            self.report(ChangeKind::Modified, pair.path, pair.mode)
+        }
+    }
+
+    fn directories(&mut self, batch: &[Pair]) -> io::Result<()> {
+        let olds: Vec<_> = batch.iter().map(|pair| pair.old.clone()).collect();
+        let news: Vec<_> = batch.iter().map(|pair| pair.new.clone()).collect();
+        let from = self.old.children(&olds)?;
+        let to = self.new.children(&news)?;
+        if from.len() != batch.len() || to.len() != batch.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "children listed for the wrong number of directories",
+            ));
+        }
+        for ((pair, from), to) in batch.iter().zip(from).zip(to) {
+            // A directory whose children are all the same differs only in its own metadata.
+            if !self.directory(pair, from.unwrap_or_default(), to.unwrap_or_default())? {
+                self.report(ChangeKind::Modified, pair.path.clone(), pair.mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the children of two directories with different hashes.
+    /// Returns whether any of them differ.
+    fn directory(
+        &mut self,
+        pair: &Pair,
+        mut from: Vec<Entry>,
+        mut to: Vec<Entry>,
+    ) -> io::Result<bool> {
+        from.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        to.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        let mut found = false;
+        let mut from = from.into_iter().peekable();
+        let mut to = to.into_iter().peekable();
+        loop {
+            let order = match (from.peek(), to.peek()) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(a), Some(b)) => a.name.cmp(&b.name),
+            };
+            match order {
+                Ordering::Less => {
+                    let entry = from.next().unwrap();
+                    self.report(ChangeKind::Removed, pair.path.join(&entry.name), entry.mode)?;
+                }
+                Ordering::Greater => {
+                    let entry = to.next().unwrap();
+                    self.report(ChangeKind::Added, pair.path.join(&entry.name), entry.mode)?;
+                }
+                Ordering::Equal => {
+                    let (a, b) = (from.next().unwrap(), to.next().unwrap());
+                    if same(&a, &b) {
+                        continue;
+                    }
+                    let child = Pair {
+                        old: pair.old.join(&a.name),
+                        new: pair.new.join(&b.name),
+                        path: pair.path.join(&b.name),
+                        mode: b.mode,
+                    };
+                    self.entry(child, a.mode)?;
+                }
+            }
+            found = true;
+        }
+        Ok(found)
+    }
+
+    fn report(&mut self, kind: ChangeKind, path: PathBuf, mode: u32) -> io::Result<()> {
+        (self.each)(Change { kind, path, mode })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::VERSION;
+    use std::collections::HashMap;
+
+    fn hello(ignore: Option<&str>, attrs: Option<&str>) -> Hello {
+        Hello {
+            server: "test".to_owned(),
+            version: VERSION,
+            algorithm: "sha1".to_owned(),
+            format: "plain".to_owned(),
+            ignore: ignore.map(str::to_owned),
+            ignore_file: None,
+            attrs: attrs.map(str::to_owned),
+            view: None,
+            views: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn settings_cover_ignore_and_attrs() {
+        assert_eq!(settings(&hello(None, None)), "sha1/plain");
+        assert_eq!(
+            settings(&hello(Some("rsync+0123456789ab"), Some("perm"))),
+            "sha1/plain/ignore=rsync+0123456789ab/attrs=perm"
+        );
+        assert_ne!(
+            settings(&hello(Some("git"), None)),
+            settings(&hello(Some("git+0123456789ab"), None))
+        );
+    }
+
+    /// A tree given as the entries of each directory, by path.
+    struct Memory(HashMap<PathBuf, Vec<Entry>>);
+
+    impl Side for Memory {
+        fn entry(&mut self, path: &Path) -> io::Result<Entry> {
+            let parent = path.parent().unwrap_or(Path::new(""));
+            let entries = self.0.get(parent).into_iter().flatten();
+            let mut found =
+                entries.filter(|entry| Some(entry.name.as_os_str()) == path.file_name());
+            found
+                .next()
+                .cloned()
+                .ok_or_else(|| io::ErrorKind::NotFound.into())
+        }
+
+        fn children(&mut self, paths: &[PathBuf]) -> io::Result<Vec<Option<Vec<Entry>>>> {
+            Ok(paths.iter().map(|path| self.0.get(path).cloned()).collect())
+        }
+    }
+
+    fn entry(name: &str, mode: u32, hash: &str) -> Entry {
+        Entry {
+            name: PathBuf::from(name),
+            mode,
+            hash: hash.to_owned(),
+        }
+    }
+
+    #[test]
+    fn modes_are_compared_even_with_equal_hashes() {
+        let tree = |mode: u32| {
+            Memory(HashMap::from([
+                (PathBuf::new(), vec![entry("root", S_IFDIR | 0o755, "r")]),
+                (
+                    PathBuf::from("root"),
+                    vec![entry("run", 0o100000 | mode, "f")],
+                ),
+            ]))
+        };
+        let mut changes = Vec::new();
+        let (root, mut old, mut new) = (Path::new("root"), tree(0o644), tree(0o755));
+        diff(&mut old, root, &mut new, root, &mut |change| {
+            changes.push(change);
+            Ok(())
+        })
+        .unwrap();
+        // The roots are the same, so nothing below them is looked at.
+        assert!(changes.is_empty());
+
+        let mut new = tree(0o755);
+        new.0.get_mut(Path::new("")).unwrap()[0].hash = "s".to_owned();
+        diff(&mut old, root, &mut new, root, &mut |change| {
+            changes.push(change);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            changes,
+            [Change {
+                kind: ChangeKind::Modified,
+                path: PathBuf::from("run"),
+                mode: 0o100755,
+            }]
+        );
+    }
+}
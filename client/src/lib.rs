@@ -0,0 +1,389 @@
+//! Client for the query socket of a running MTFS mount.
+//!
+//! The same protocol can be spoken over any byte stream, such as the standard
+//! input and output of `mtfs serve` on another machine, run through ssh.
+//!
+//! ```no_run
+//! let mut client = mtfs_client::Client::connect("/srv/store/.mtfs/socket")?;
+//! println!("{}", client.hash("src")?);
+//! for change in client.diff("release", "src")? {
+//!     println!("{:?}", change?);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! A [`Subscription`] is told whenever something in a directory changes.
+
+pub mod diff;
+pub mod protocol;
+
+use diff::{Change, Side};
+use protocol::{Changed, Entry, Hello, Request, Response, TreeStat, VERSION};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// Where the query socket is, relative to the root of a mount or private store.
+pub const SOCKET: &str = ".mtfs/socket";
+
+pub struct Client {
+    reader: Box<dyn BufRead + Send>,
+    writer: Box<dyn Write + Send>,
+    hello: Hello,
+}
+
+impl Client {
+    /// Connects to the socket at `socket` and checks that the server speaks our protocol version.
+    pub fn connect(socket: impl AsRef<Path>) -> io::Result<Self> {
+        let stream = UnixStream::connect(socket)?;
+        Client::over(stream.try_clone()?, stream)
+    }
+
+    /// Speaks to a server that reads from `writer` and writes to `reader`,
+    /// checking that it speaks our protocol version.
+    pub fn over(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let hello: Hello = receive(&mut reader)?;
+        if hello.version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "server speaks protocol version {}, not {}",
+                    hello.version, VERSION
+                ),
+            ));
+        }
+        Ok(Client {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            hello,
+        })
+    }
+
+    /// The server's name and version, as it announced itself.
+    pub fn server(&self) -> &str {
+        &self.hello.server
+    }
+
+    pub fn hello(&self) -> &Hello {
+        &self.hello
+    }
+
+    /// Switches to the view called `name`, whose hashes every later call
+    /// returns. [`Client::hello`] describes it from then on.
+    pub fn view(&mut self, name: &str) -> io::Result<()> {
+        match self.call(&Request::View {
+            name: name.to_owned(),
+        })? {
+            Response::Hello(hello) => {
+                self.hello = hello;
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The hash of `path`, as lowercase hex, computing it if needed.
+    pub fn hash(&mut self, path: impl AsRef<Path>) -> io::Result<String> {
+        match self.call(&Request::Hash {
+            path: path.as_ref().to_owned(),
+        })? {
+            Response::Hash { hash } => Ok(hash),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Whether the cached hash of `path` is valid.
+    pub fn is_valid(&mut self, path: impl AsRef<Path>) -> io::Result<bool> {
+        match self.call(&Request::IsValid {
+            path: path.as_ref().to_owned(),
+        })? {
+            Response::IsValid { valid } => Ok(valid),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn stat_tree(&mut self, path: impl AsRef<Path>) -> io::Result<TreeStat> {
+        match self.call(&Request::StatTree {
+            path: path.as_ref().to_owned(),
+        })? {
+            Response::StatTree(stat) => Ok(stat),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The hash and mode of `path`.
+    pub fn entry(&mut self, path: impl AsRef<Path>) -> io::Result<Entry> {
+        match self.call(&Request::Entry {
+            path: path.as_ref().to_owned(),
+        })? {
+            Response::Entry(entry) => Ok(entry),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The children of each of `paths` included in its hash, or `None` for
+    /// those that are not directories.
+    pub fn children(&mut self, paths: &[PathBuf]) -> io::Result<Vec<Option<Vec<Entry>>>> {
+        match self.call(&Request::Children {
+            paths: paths.to_owned(),
+        })? {
+            Response::Children { entries } => Ok(entries),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The paths below `path` changed since `since`, a token from an earlier
+    /// call, with a token to pass next time. Without a token there is nothing
+    /// to compare with, so the paths are `None`, as if anything had changed.
+    pub fn changes(&mut self, path: impl AsRef<Path>, since: Option<&str>) -> io::Result<Changed> {
+        match self.call(&Request::Changes {
+            path: path.as_ref().to_owned(),
+            since: since.map(str::to_owned),
+        })? {
+            Response::Changed(changed) => Ok(changed),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// The differences from `old` to `new` in this mount, as the server finds
+    /// them. To compare paths in two mounts, use [`diff::between`].
+    pub fn diff(&mut self, old: impl AsRef<Path>, new: impl AsRef<Path>) -> io::Result<Diff<'_>> {
+        self.send(&Request::Diff {
+            old: old.as_ref().to_owned(),
+            new: new.as_ref().to_owned(),
+        })?;
+        Ok(Diff {
+            client: self,
+            done: false,
+        })
+    }
+
+    /// Sends `request` and returns the response, turning error responses into errors.
+    pub fn call(&mut self, request: &Request) -> io::Result<Response> {
+        self.send(request)?;
+        match self.receive()? {
+            Response::Error { errno, message } => Err(error(errno, message)),
+            response => Ok(response),
+        }
+    }
+
+    fn send(&mut self, request: &Request) -> io::Result<()> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.writer.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Response> {
+        receive(&mut self.reader)
+    }
+}
+
+impl Side for Client {
+    fn entry(&mut self, path: &Path) -> io::Result<Entry> {
+        Client::entry(self, path)
+    }
+
+    fn children(&mut self, paths: &[PathBuf]) -> io::Result<Vec<Option<Vec<Entry>>>> {
+        Client::children(self, paths)
+    }
+}
+
+/// The changes of a diff, read from the server as they are iterated. Dropping
+/// it early reads and discards the rest, so the client can be used again.
+pub struct Diff<'a> {
+    client: &'a mut Client,
+    done: bool,
+}
+
+impl Iterator for Diff<'_> {
+    type Item = io::Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let response = self.client.receive();
+        self.done = !matches!(response, Ok(Response::Change(_)));
+        match response {
+            Ok(Response::Change(change)) => Some(Ok(change)),
+            Ok(Response::Done) => None,
+            Ok(Response::Error { errno, message }) => Some(Err(error(errno, message))),
+            Ok(response) => Some(Err(unexpected(response))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+impl Drop for Diff<'_> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
+/// A connection on which the server sends the paths that change below a
+/// directory, as they change.
+///
+/// Its file descriptor can be registered with `poll`, epoll or an async
+/// runtime after [`Subscription::set_nonblocking`]. When it is readable, call
+/// [`Subscription::recv`] until it fails with [`io::ErrorKind::WouldBlock`],
+/// since one read may bring several paths.
+pub struct Subscription {
+    stream: UnixStream,
+    /// Read but not yet returned.
+    buffer: Vec<u8>,
+}
+
+impl Subscription {
+    /// Connects to the socket at `socket` and subscribes to `path`, to be
+    /// told only of changes that can change hashes in the view called `view`,
+    /// or in the default one.
+    pub fn connect(
+        socket: impl AsRef<Path>,
+        view: Option<&str>,
+        path: impl AsRef<Path>,
+    ) -> io::Result<Self> {
+        Subscription::over(UnixStream::connect(socket)?, view, path)
+    }
+
+    /// Subscribes like [`Subscription::connect`], to a server at the other
+    /// end of `stream`.
+    pub fn over(
+        stream: UnixStream,
+        view: Option<&str>,
+        path: impl AsRef<Path>,
+    ) -> io::Result<Self> {
+        let mut subscription = Subscription {
+            stream,
+            buffer: Vec::new(),
+        };
+        let hello: Hello = serde_json::from_slice(&subscription.line()?)?;
+        if hello.version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "server speaks protocol version {}, not {}",
+                    hello.version, VERSION
+                ),
+            ));
+        }
+        if let Some(view) = view {
+            subscription.send(&Request::View {
+                name: view.to_owned(),
+            })?;
+            match subscription.response()? {
+                Response::Hello(_) => {}
+                response => return Err(unexpected(response)),
+            }
+        }
+        subscription.send(&Request::Subscribe {
+            path: path.as_ref().to_owned(),
+        })?;
+        match subscription.response()? {
+            Response::Subscribed => Ok(subscription),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn send(&mut self, request: &Request) -> io::Result<()> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.stream.write_all(&line)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    /// The next path below which something changed, relative to the path
+    /// subscribed to and empty if it may all have changed. Waits for one,
+    /// unless the subscription is nonblocking.
+    pub fn recv(&mut self) -> io::Result<PathBuf> {
+        match self.response()? {
+            Response::Invalidated { path } => Ok(path),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn response(&mut self) -> io::Result<Response> {
+        match serde_json::from_slice(&self.line()?)? {
+            Response::Error { errno, message } => Err(error(errno, message)),
+            response => Ok(response),
+        }
+    }
+
+    /// The next line from the server. A read that would block leaves whatever
+    /// was read so far for the next call.
+    fn line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line = self.buffer.drain(..=newline).collect();
+                return Ok(line);
+            }
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.buffer.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+impl AsFd for Subscription {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.stream.as_fd()
+    }
+}
+
+impl AsRawFd for Subscription {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// Finds the mount or private store `path` is in, by looking for its query
+/// socket in `path` and its ancestors. Returns the socket and `path` relative
+/// to the root it was found in.
+pub fn locate(path: impl AsRef<Path>) -> io::Result<(PathBuf, PathBuf)> {
+    let path = fs::canonicalize(path)?;
+    for root in path.ancestors() {
+        let socket = root.join(SOCKET);
+        if socket.exists() {
+            let rel = path.strip_prefix(root).unwrap().to_owned();
+            return Ok((socket, rel));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} is not in an MTFS mount", path.display()),
+    ))
+}
+
+fn receive<T: serde::de::DeserializeOwned>(reader: &mut impl BufRead) -> io::Result<T> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn error(errno: i32, message: String) -> io::Error {
+    if errno == 0 {
+        io::Error::other(message)
+    } else {
+        io::Error::from_raw_os_error(errno)
+    }
+}
+
+fn unexpected(response: Response) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response {:?}", response),
+    )
+}
@@ -0,0 +1,252 @@
+//! Wire format of the MTFS query socket.
+//!
+//! On connecting, the server sends a [`Hello`]. After that, every [`Request`]
+//! is a line of JSON answered by a line of JSON holding a [`Response`], except
+//! that [`Request::Diff`] is answered by any number of [`Response::Change`]s
+//! ended by [`Response::Done`] or [`Response::Error`], and
+//! [`Request::Subscribe`] by [`Response::Subscribed`] and then an
+//! [`Response::Invalidated`] for every change, for as long as the connection
+//! stays open. Paths are relative to the root of the mount.
+//!
+//! Hashes come from the mount's default view until [`Request::View`] switches
+//! the connection to another, which is answered with a [`Hello`] for it.
+
+use crate::diff::Change;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+
+/// Version of the protocol described here. A server only answers clients
+/// speaking the same version, so it is raised whenever a request, a response
+/// or a field of [`Hello`] is added or changed.
+pub const VERSION: u32 = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub server: String,
+    pub version: u32,
+    /// Hashes from two servers are only comparable if these match.
+    pub algorithm: String,
+    pub format: String,
+    /// How entries are left out of hashes, such as `git`, if any are. Rules
+    /// read from outside the tree add `+` and a digest of them, as in
+    /// `rsync+0123456789ab`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore: Option<String>,
+    /// The file outside the tree the ignore rules were read from, such as
+    /// git's global excludes file, so that a directory on the same machine
+    /// can be hashed the same way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore_file: Option<PathBuf>,
+    /// Attributes hashed along with contents, such as `perm+owner`, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attrs: Option<String>,
+    /// The view the above describe, if not the default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub view: Option<String>,
+    /// The views other than the default that can be switched to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub views: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// The hash of `path`, computing it if it is not valid.
+    Hash {
+        #[serde(with = "path")]
+        path: PathBuf,
+    },
+    /// Whether the cached hash of `path` is valid, without computing anything.
+    IsValid {
+        #[serde(with = "path")]
+        path: PathBuf,
+    },
+    /// What the cache holds for `path` and below it.
+    StatTree {
+        #[serde(with = "path")]
+        path: PathBuf,
+    },
+    /// The hash and mode of `path`.
+    Entry {
+        #[serde(with = "path")]
+        path: PathBuf,
+    },
+    /// The children of each of `paths` that are included in its hash, with
+    /// their hashes. Listing many directories at once saves round trips when
+    /// comparing trees a level at a time.
+    Children {
+        #[serde(with = "paths")]
+        paths: Vec<PathBuf>,
+    },
+    /// The differences from `old` to `new`, streamed as they are found.
+    Diff {
+        #[serde(with = "path")]
+        old: PathBuf,
+        #[serde(with = "path")]
+        new: PathBuf,
+    },
+    /// The paths below `path` changed through the mount since `since`, a
+    /// token from an earlier answer. Without one, only a token is returned.
+    Changes {
+        #[serde(with = "path")]
+        path: PathBuf,
+        since: Option<String>,
+    },
+    /// Turns the connection into a stream of the changes below `path` that
+    /// can change hashes in the connection's view.
+    Subscribe {
+        #[serde(with = "path")]
+        path: PathBuf,
+    },
+    /// Answers the requests after this from the view called `name`.
+    View { name: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Hash {
+        hash: String,
+    },
+    IsValid {
+        valid: bool,
+    },
+    StatTree(TreeStat),
+    Entry(Entry),
+    /// One list per path asked for, `None` if it is not a directory.
+    Children {
+        entries: Vec<Option<Vec<Entry>>>,
+    },
+    Change(Change),
+    Changed(Changed),
+    /// The end of a diff.
+    Done,
+    Subscribed,
+    /// The view switched to.
+    Hello(Hello),
+    /// Everything below `path`, relative to the path subscribed to, may have
+    /// changed. Changes that arrive while the client is not reading are
+    /// merged, so that only the highest of the paths they touched is sent.
+    Invalidated {
+        #[serde(with = "path")]
+        path: PathBuf,
+    },
+    /// `errno` is 0 for errors that have no errno, such as malformed requests.
+    Error {
+        errno: i32,
+        message: String,
+    },
+}
+
+impl Response {
+    pub fn error(err: &io::Error) -> Self {
+        Response::Error {
+            errno: err.raw_os_error().unwrap_or(0),
+            message: err.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeStat {
+    /// The cached hash, if it is valid.
+    pub hash: Option<String>,
+    pub valid: bool,
+    pub generation: u64,
+    /// Nodes the cache tracks below and including this one.
+    pub nodes: u64,
+    /// How many of those are invalid.
+    pub invalid: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Changed {
+    /// Asks for the changes after these.
+    pub token: String,
+    /// Relative to the path asked about, or `None` if the server cannot tell
+    /// what changed, so anything may have.
+    pub paths: Option<Vec<ChangedPath>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangedPath {
+    #[serde(with = "path")]
+    pub path: PathBuf,
+    /// Whether everything below `path` may have changed too, as when a
+    /// directory is renamed.
+    pub subtree: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(with = "path")]
+    pub name: PathBuf,
+    /// `st_mode`.
+    pub mode: u32,
+    pub hash: String,
+}
+
+/// Paths go over the wire as strings, or as arrays of bytes if they are not UTF-8.
+pub(crate) mod path {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::ffi::OsString;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::{Path, PathBuf};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Str(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => serializer.collect_seq(path.as_os_str().as_bytes()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Str(path) => PathBuf::from(path),
+            Repr::Bytes(bytes) => PathBuf::from(OsString::from_vec(bytes)),
+        })
+    }
+}
+
+/// Lists of paths, each written as by [`path`].
+pub(crate) mod paths {
+    use serde::ser::SerializeSeq;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::{Path, PathBuf};
+
+    struct Item<'a>(&'a Path);
+
+    impl Serialize for Item<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::path::serialize(self.0, serializer)
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Owned(#[serde(with = "super::path")] PathBuf);
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(paths.len()))?;
+        for path in paths {
+            seq.serialize_element(&Item(path))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PathBuf>, D::Error> {
+        Ok(Vec::<Owned>::deserialize(deserializer)?
+            .into_iter()
+            .map(|Owned(path)| path)
+            .collect())
+    }
+}
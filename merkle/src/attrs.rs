@@ -0,0 +1,218 @@
+//! Which attributes of a node go into its hash along with its contents.
+//!
+//! As in the README, a node's hash only covers its contents and children by
+//! default. The `attrs=` mount option adds any of `type`, `perm`, `owner`,
+//! `mtime` and `xattrs`, joined by `+`, so that a lost executable bit or a
+//! changed SELinux label changes the hash too. The chosen fields of the node's
+//! [`FileAttr`] are written out a line each, in that order, after the hash the
+//! format computed, and the node's hash is the hash of the whole. Extended
+//! attributes MTFS keeps for itself are left out.
+
+use crate::control::is_internal_xattr;
+use crate::passthrough::{cstr, read_sized};
+use fuser::{FileAttr, FileType};
+use std::fmt::Write;
+use std::io;
+use std::ops::BitOr;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// A set of attributes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Attrs(u8);
+
+pub const TYPE: Attrs = Attrs(1);
+pub const PERM: Attrs = Attrs(2);
+pub const OWNER: Attrs = Attrs(4);
+pub const MTIME: Attrs = Attrs(8);
+pub const XATTRS: Attrs = Attrs(16);
+
+/// Names accepted by the `attrs=` mount option, in the order they are hashed.
+pub static FIELDS: [(&str, Attrs); 5] = [
+    ("type", TYPE),
+    ("perm", PERM),
+    ("owner", OWNER),
+    ("mtime", MTIME),
+    ("xattrs", XATTRS),
+];
+
+impl Attrs {
+    pub const NONE: Attrs = Attrs(0);
+
+    /// Parses `none` or fields joined by `+`, such as `perm+owner`.
+    pub fn parse(value: &str) -> Result<Attrs, String> {
+        if value == "none" {
+            return Ok(Attrs::NONE);
+        }
+        value.split('+').try_fold(Attrs::NONE, |attrs, name| {
+            let (_, field) = FIELDS
+                .iter()
+                .find(|(known, _)| *known == name)
+                .ok_or_else(|| {
+                    let known: Vec<_> = FIELDS.iter().map(|(name, _)| *name).collect();
+                    format!(
+                        "unknown attribute {:?} (expected none or some of {} joined by +)",
+                        name,
+                        known.join(", ")
+                    )
+                })?;
+            Ok(attrs | *field)
+        })
+    }
+
+    pub fn is_empty(self) -> bool {
+        self == Attrs::NONE
+    }
+
+    /// Whether any attribute is in both sets.
+    pub fn intersects(self, other: Attrs) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// The fields joined by `+`, or `None` if there are none, as recorded in
+    /// the private store.
+    pub fn setting(self) -> Option<String> {
+        let names: Vec<_> = FIELDS
+            .iter()
+            .filter(|(_, field)| self.intersects(*field))
+            .map(|(name, _)| *name)
+            .collect();
+        (!names.is_empty()).then(|| names.join("+"))
+    }
+
+    /// The attributes that differ between `before` and `after`, as far as a
+    /// `setattr` can change them.
+    pub fn changed(before: &FileAttr, after: &FileAttr) -> Attrs {
+        let mut changed = Attrs::NONE;
+        for (field, differs) in [
+            (TYPE, before.kind != after.kind),
+            (PERM, before.perm != after.perm),
+            (OWNER, (before.uid, before.gid) != (after.uid, after.gid)),
+            (MTIME, before.mtime != after.mtime),
+        ] {
+            if differs {
+                changed = changed | field;
+            }
+        }
+        changed
+    }
+
+    /// Writes out these attributes of the node at `path`, whose [`FileAttr`]
+    /// is `attr`.
+    pub fn encode(self, attr: &FileAttr, path: &Path) -> io::Result<Vec<u8>> {
+        let mut out = String::new();
+        if self.intersects(TYPE) {
+            let kind = match attr.kind {
+                FileType::NamedPipe => "fifo",
+                FileType::CharDevice => "char",
+                FileType::BlockDevice => "block",
+                FileType::Directory => "dir",
+                FileType::RegularFile => "file",
+                FileType::Symlink => "symlink",
+                FileType::Socket => "socket",
+            };
+            writeln!(out, "type {}", kind).unwrap();
+        }
+        if self.intersects(PERM) {
+            writeln!(out, "perm {:04o}", attr.perm).unwrap();
+        }
+        if self.intersects(OWNER) {
+            writeln!(out, "owner {} {}", attr.uid, attr.gid).unwrap();
+        }
+        if self.intersects(MTIME) {
+            let mtime = attr.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+            writeln!(out, "mtime {}.{:09}", mtime.as_secs(), mtime.subsec_nanos()).unwrap();
+        }
+        let mut out = out.into_bytes();
+        if self.intersects(XATTRS) {
+            for (name, value) in xattrs(path)? {
+                out.extend_from_slice(b"xattr ");
+                out.extend_from_slice(&name);
+                out.push(b' ');
+                out.extend_from_slice(hex(&value).as_bytes());
+                out.push(b'\n');
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl BitOr for Attrs {
+    type Output = Attrs;
+
+    fn bitor(self, other: Attrs) -> Attrs {
+        Attrs(self.0 | other.0)
+    }
+}
+
+/// The extended attributes of `path` that are not MTFS's own, sorted by name.
+fn xattrs(path: &Path) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let path = cstr(path)?;
+    let names = read_sized(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), len) })?;
+    let mut xattrs = Vec::new();
+    for name in names.split_inclusive(|&b| b == 0) {
+        if is_internal_xattr(name) {
+            continue;
+        }
+        let value = read_sized(|buf, len| unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr().cast(), buf.cast(), len)
+        });
+        let value = match value {
+            Ok(value) => value,
+            // Removed since it was listed.
+            Err(err) if err.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(err) => return Err(err),
+        };
+        xattrs.push((name[..name.len() - 1].to_vec(), value));
+    }
+    xattrs.sort_unstable();
+    Ok(xattrs)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        write!(hex, "{:02x}", b).unwrap();
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Attrs::parse("none"), Ok(Attrs::NONE));
+        assert_eq!(Attrs::parse("perm+owner"), Ok(PERM | OWNER));
+        // Repeating a field changes nothing.
+        assert_eq!(Attrs::parse("perm+perm"), Ok(PERM));
+        assert_eq!(
+            Attrs::parse("perm+size"),
+            Err(
+                "unknown attribute \"size\" (expected none or some of type, perm, \
+                 owner, mtime, xattrs joined by +)"
+                    .to_owned()
+            )
+        );
+        assert!(Attrs::parse("").is_err());
+        assert!(Attrs::parse("none+perm").is_err());
+        assert!(Attrs::parse("Perm").is_err());
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        assert_eq!(Attrs::NONE.setting(), None);
+        assert_eq!(
+            (MTIME | TYPE | PERM).setting().as_deref(),
+            Some("type+perm+mtime")
+        );
+        // Servers send the setting in their greeting, and clients parse it
+        // back, taking no setting for none.
+        for bits in 0..32 {
+            let attrs = Attrs(bits);
+            let setting = attrs.setting();
+            let parsed = Attrs::parse(setting.as_deref().unwrap_or("none"));
+            assert_eq!(parsed, Ok(attrs), "{:?}", setting);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+//! The change log: a sequence number for every mutation that goes through the
+//! mount, and the paths it changed, so that a client holding a token from an
+//! earlier query can be told exactly what changed since.
+//!
+//! This is what answers git's fsmonitor hook. Unlike a watcher that starts
+//! with the daemon, the log is kept in `<store>/.mtfs/changes` across remounts,
+//! so tokens stay good as long as nothing reaches the store behind the mount's
+//! back. A log that was not closed cleanly cannot be trusted to be complete, so
+//! it is started afresh under a new id, which makes every older token ask for a
+//! full rescan.
+//!
+//! The file is a line holding the id and the sequence number before its first
+//! entry, followed by store-relative paths terminated by NUL bytes, each
+//! written with a single `write` once the mutation has been applied. A path
+//! with a trailing slash stands for everything below it too, as when a
+//! directory is renamed. A `changes.clean` file next to it marks a clean close.
+//!
+//! Changes are also sent as they are recorded to whoever subscribed to a
+//! directory above them, which is how the query socket pushes invalidations.
+//! Subscribers are told which attributes changed when nothing else did, so
+//! that they can pass over changes their hashes leave out.
+
+use crate::attrs::Attrs;
+use crate::store::Store;
+use mtfs_client::protocol::{Changed, ChangedPath};
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+/// Size past which the older half of the log is dropped.
+const MAX_LEN: u64 = 1 << 20;
+
+/// A changed path as subscribers receive it, with the attributes that changed
+/// if only attributes did.
+pub type Notice = (PathBuf, Option<Attrs>);
+
+pub struct Changes {
+    root: PathBuf,
+    path: PathBuf,
+    clean: PathBuf,
+    log: Mutex<Log>,
+    /// The store-relative directory each subscription is for.
+    subscribers: Mutex<Vec<(PathBuf, Sender<Notice>)>>,
+}
+
+struct Log {
+    file: File,
+    len: u64,
+    /// Tokens from other logs are not ours to answer.
+    id: String,
+    /// The sequence number before the first of `entries`.
+    base: u64,
+    /// Store-relative paths, with a trailing slash for whole subtrees.
+    entries: Vec<Vec<u8>>,
+    /// The last sequence number handed out in a token.
+    handed_out: u64,
+    /// Whether a change may have gone unrecorded, so the log must not be
+    /// trusted after a remount.
+    lost: bool,
+}
+
+impl Changes {
+    pub fn open(store: &Store) -> io::Result<Self> {
+        let path = store.meta_path("changes")?;
+        let clean = store.meta_path("changes.clean")?;
+        let was_clean = match fs::remove_file(&clean) {
+            Ok(()) => true,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err),
+        };
+        let log = match was_clean.then(|| Log::load(&path)).transpose()?.flatten() {
+            Some(log) => log,
+            None => Log::rewrite(&path, new_id(), 0, Vec::new())?,
+        };
+        Ok(Changes {
+            root: store.root().to_owned(),
+            path,
+            clean,
+            log: Mutex::new(log),
+            subscribers: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Receives the paths that change below the store-relative `root` from now
+    /// on, relative to `root`. A path stands for everything below it, and is
+    /// empty if all of `root` may have changed.
+    pub fn subscribe(&self, root: &Path) -> Receiver<Notice> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push((root.to_owned(), sender));
+        receiver
+    }
+
+    /// Sends a recorded change to the subscriptions it concerns, dropping
+    /// those nobody receives anymore.
+    fn notify(&self, path: &Path, subtree: bool, attrs: Option<Attrs>) {
+        self.subscribers.lock().unwrap().retain(|(root, sender)| {
+            let relative = match path.strip_prefix(root) {
+                Ok(relative) => relative.to_owned(),
+                Err(_) if subtree && root.starts_with(path) => PathBuf::new(),
+                Err(_) => return true,
+            };
+            sender.send((relative, attrs)).is_ok()
+        });
+    }
+
+    /// Records that the store paths in `paths` changed, and everything below
+    /// them too if `subtree`, or only their attributes `attrs` if given. Call
+    /// this once the change has been applied.
+    pub fn record(&self, paths: &[&Path], subtree: bool, attrs: Option<Attrs>) {
+        let mut log = self.log.lock().unwrap();
+        for path in paths {
+            let relative = path.strip_prefix(&self.root).unwrap_or(path);
+            self.notify(relative, subtree, attrs);
+            let mut entry = relative.as_os_str().as_bytes().to_vec();
+            if subtree && !entry.is_empty() {
+                entry.push(b'/');
+            }
+            if let Err(err) = log.append(entry, &self.path) {
+                eprintln!("mtfs: cannot record change: {}", err);
+                // Nobody can be told what changed, so start again.
+                log.id = new_id();
+                log.base += log.entries.len() as u64;
+                log.entries.clear();
+                log.lost = true;
+            }
+        }
+    }
+
+    /// The paths changed below the store-relative `root` since `token`, made
+    /// relative to `root`, or `None` for the paths if the log cannot tell,
+    /// along with a token for asking again.
+    pub fn since(&self, root: &Path, token: Option<&str>) -> Changed {
+        let mut log = self.log.lock().unwrap();
+        let current = log.base + log.entries.len() as u64;
+        log.handed_out = current;
+        let paths = token
+            .and_then(|token| log.parse(token))
+            .filter(|&seq| seq >= log.base && seq <= current)
+            .and_then(|seq| log.paths(root, (seq - log.base) as usize));
+        Changed {
+            token: format!("mtfs:{}:{}", log.id, current),
+            paths,
+        }
+    }
+
+    /// Marks the log as complete, if it is. Only call this once nothing else
+    /// can change the store through the mount.
+    pub fn close(&self) -> io::Result<()> {
+        let log = self.log.lock().unwrap();
+        if log.lost {
+            return Ok(());
+        }
+        log.file.sync_data()?;
+        File::create(&self.clean).map(drop)
+    }
+}
+
+impl Log {
+    /// Reads the log at `path`, or returns `None` if it is missing or unreadable.
+    fn load(path: &Path) -> io::Result<Option<Self>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let Some(newline) = data.iter().position(|&b| b == b'\n') else {
+            return Ok(None);
+        };
+        let header = String::from_utf8_lossy(&data[..newline]);
+        let Some((id, base)) = header.split_once(' ') else {
+            return Ok(None);
+        };
+        let Ok(base) = base.parse() else {
+            return Ok(None);
+        };
+        let body = &data[newline + 1..];
+        // The log was closed cleanly, so every entry is complete.
+        let entries: Vec<Vec<u8>> = body
+            .split(|&b| b == 0)
+            .take(body.iter().filter(|&&b| b == 0).count())
+            .map(<[u8]>::to_vec)
+            .collect();
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Some(Log {
+            file,
+            len: data.len() as u64,
+            id: id.to_owned(),
+            base,
+            handed_out: base + entries.len() as u64,
+            entries,
+            lost: false,
+        }))
+    }
+
+    /// Writes a log holding `entries` to `path`, replacing whatever was there.
+    fn rewrite(path: &Path, id: String, base: u64, entries: Vec<Vec<u8>>) -> io::Result<Self> {
+        let mut data = format!("{} {}\n", id, base).into_bytes();
+        for entry in &entries {
+            data.extend_from_slice(entry);
+            data.push(0);
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, &data)?;
+        fs::rename(&tmp, path)?;
+        Ok(Log {
+            file: OpenOptions::new().append(true).open(path)?,
+            len: data.len() as u64,
+            id,
+            base,
+            handed_out: base + entries.len() as u64,
+            entries,
+            lost: false,
+        })
+    }
+
+    fn append(&mut self, entry: Vec<u8>, path: &Path) -> io::Result<()> {
+        // Repeated writes to one file need not be told apart, unless someone
+        // was handed a token in between.
+        let current = self.base + self.entries.len() as u64;
+        if current > self.handed_out && self.entries.last() == Some(&entry) {
+            return Ok(());
+        }
+        if self.len > MAX_LEN {
+            let keep = self.entries.split_off(self.entries.len() / 2);
+            let base = self.base + self.entries.len() as u64;
+            let (handed_out, lost) = (self.handed_out, self.lost);
+            *self = Log::rewrite(path, self.id.clone(), base, keep)?;
+            self.handed_out = handed_out;
+            self.lost = lost;
+        }
+        let mut line = entry.clone();
+        line.push(0);
+        self.file.write_all(&line)?;
+        self.len += line.len() as u64;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// The sequence number in `token`, if it is one of ours.
+    fn parse(&self, token: &str) -> Option<u64> {
+        let (id, seq) = token.strip_prefix("mtfs:")?.rsplit_once(':')?;
+        (id == self.id).then(|| seq.parse().ok()).flatten()
+    }
+
+    /// The paths of `entries[from..]` below `root`, or `None` if one of them
+    /// covers `root` itself.
+    fn paths(&self, root: &Path, from: usize) -> Option<Vec<ChangedPath>> {
+        // Deduplicated, and a whole subtree wins over a single path.
+        let mut paths = BTreeMap::new();
+        for entry in &self.entries[from..] {
+            let (path, subtree) = match entry.strip_suffix(b"/") {
+                Some(path) => (path, true),
+                None => (&entry[..], false),
+            };
+            let path = PathBuf::from(OsString::from_vec(path.to_vec()));
+            match path.strip_prefix(root) {
+                Ok(relative) if relative.as_os_str().is_empty() => {
+                    if subtree {
+                        return None;
+                    }
+                }
+                Ok(relative) => *paths.entry(relative.to_owned()).or_default() |= subtree,
+                Err(_) if subtree && root.starts_with(&path) => return None,
+                Err(_) => {}
+            }
+        }
+        Some(
+            paths
+                .into_iter()
+                .map(|(path, subtree)| ChangedPath { path, subtree })
+                .collect(),
+        )
+    }
+}
+
+fn new_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs;
+
+    /// The paths changed below `root` since `token`, as `path` or `path/`.
+    fn since(changes: &Changes, root: &str, token: &str) -> Option<Vec<String>> {
+        let changed = changes.since(Path::new(root), Some(token));
+        changed.paths.map(|paths| {
+            paths
+                .into_iter()
+                .map(|changed| {
+                    let path = changed.path.display().to_string();
+                    if changed.subtree {
+                        path + "/"
+                    } else {
+                        path
+                    }
+                })
+                .collect()
+        })
+    }
+
+    fn record(changes: &Changes, root: &Path, path: &str, subtree: bool) {
+        changes.record(&[&root.join(path)], subtree, None);
+    }
+
+    #[test]
+    fn paths_since_a_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().to_owned()).unwrap();
+        let changes = Changes::open(&store).unwrap();
+        let root = dir.path();
+        let token = changes.since(Path::new(""), None).token;
+
+        record(&changes, root, "a/x", false);
+        record(&changes, root, "a/x", false);
+        record(&changes, root, "b", false);
+        record(&changes, root, "a/y", true);
+        record(&changes, root, "a/y", false);
+        assert_eq!(since(&changes, "", &token).unwrap(), ["a/x", "a/y/", "b"]);
+        assert_eq!(since(&changes, "a", &token).unwrap(), ["x", "y/"]);
+        // A subtree above the root covers all of it.
+        assert_eq!(since(&changes, "a/y/z", &token), None);
+
+        let token = changes.since(Path::new(""), None).token;
+        assert_eq!(since(&changes, "", &token).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn writes_after_a_token_are_not_merged_into_those_before() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().to_owned()).unwrap();
+        let changes = Changes::open(&store).unwrap();
+        record(&changes, dir.path(), "x", false);
+        let token = changes.since(Path::new(""), None).token;
+        record(&changes, dir.path(), "x", false);
+        assert_eq!(since(&changes, "", &token).unwrap(), ["x"]);
+    }
+
+    #[test]
+    fn foreign_and_future_tokens_ask_for_a_rescan() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().to_owned()).unwrap();
+        let changes = Changes::open(&store).unwrap();
+        let token = changes.since(Path::new(""), None).token;
+        let (prefix, seq) = token.rsplit_once(':').unwrap();
+        let future = format!("{}:{}", prefix, seq.parse::<u64>().unwrap() + 1);
+        assert_eq!(since(&changes, "", &future), None);
+        assert_eq!(since(&changes, "", "mtfs:0000000000000000:0"), None);
+        assert_eq!(since(&changes, "", "c:1234:5"), None);
+    }
+
+    #[test]
+    fn tokens_survive_only_a_clean_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().to_owned()).unwrap();
+        let changes = Changes::open(&store).unwrap();
+        let token = changes.since(Path::new(""), None).token;
+        record(&changes, dir.path(), "x", false);
+        changes.close().unwrap();
+        drop(changes);
+
+        let changes = Changes::open(&store).unwrap();
+        assert_eq!(since(&changes, "", &token).unwrap(), ["x"]);
+        drop(changes);
+
+        // Not closed this time, so something may have gone unrecorded.
+        let changes = Changes::open(&store).unwrap();
+        assert_eq!(since(&changes, "", &token), None);
+    }
+
+    #[test]
+    fn subscribers_get_paths_below_their_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().to_owned()).unwrap();
+        let changes = Changes::open(&store).unwrap();
+        let receiver = changes.subscribe(Path::new("a"));
+        record(&changes, dir.path(), "a/x", false);
+        record(&changes, dir.path(), "b", false);
+        record(&changes, dir.path(), "", true);
+        changes.record(&[&dir.path().join("a/y")], false, Some(attrs::PERM));
+        let received: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(
+            received,
+            [
+                (PathBuf::from("x"), None),
+                (PathBuf::new(), None),
+                (PathBuf::from("y"), Some(attrs::PERM)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,488 @@
+//! What the mount shows of MTFS itself: a `.mtfs` control directory at the
+//! root, which lets applications detect MTFS and find its query socket, and
+//! extended attributes holding the hash of every file and directory.
+//!
+//! The control directory takes the place of the private store's metadata
+//! directory, which the mount hides, and is served from memory: nothing in it
+//! exists in the store, it is read-only and, like the metadata directory, it is
+//! never hashed.
+//!
+//! - `socket` is a symlink to the query socket, so it can be connected to directly.
+//! - `version` is the version of MTFS.
+//! - `algorithm`, `format`, `ignore` and `attrs` say how hashes are computed.
+//! - `root-hash` is the hash of the root, computed when it is opened.
+//!
+//! `user.mtfs.hash` is the hash of a node, computed when it is read, and
+//! `user.mtfs.valid` is `1` if it is already cached and `0` if not. They are
+//! not listed, so that tools copying every attribute do not hash everything.
+//!
+//! The files and attributes above describe the default view. Those of another
+//! view have its name in front: `<view>.root-hash` in the control directory
+//! and `user.mtfs.<view>.hash` on every node.
+//!
+//! For tools that can only read files, the `hash_files` mount option makes
+//! `name@@mtfs-hash` in any directory a read-only file holding the hash of
+//! `name` in the default view. These are not listed either, and shadow any
+//! real file of that name.
+
+use crate::inode::ROOT_INO;
+use crate::passthrough::to_attr;
+use crate::store::{backing, is_reserved, view_meta, Store};
+use crate::tree::Tree;
+use fuser::{FileAttr, FileType};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Inode numbers from here up are synthetic. The inode table allocates upwards
+/// from the root, so it never gets this far.
+const BASE: u64 = 1 << 63;
+
+pub const DIR_INO: u64 = BASE;
+
+/// The hash file of inode `ino` is `HASH_FILES | ino`.
+const HASH_FILES: u64 = BASE | 1 << 62;
+
+const HASH_SUFFIX: &[u8] = b"@@mtfs-hash";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum File {
+    Socket,
+    Version,
+    Algorithm,
+    Format,
+    Ignore,
+    Attrs,
+    RootHash,
+    /// The hash file of an inode in the store.
+    Hash(u64),
+}
+
+/// The files of the control directory. Views other than the default have
+/// only those after `version`, under names starting with their own, and are
+/// numbered after the default one in turn.
+const FILES: [(&str, File); 7] = [
+    ("socket", File::Socket),
+    ("version", File::Version),
+    ("algorithm", File::Algorithm),
+    ("format", File::Format),
+    ("ignore", File::Ignore),
+    ("attrs", File::Attrs),
+    ("root-hash", File::RootHash),
+];
+
+/// Where the files of every view start in [`FILES`].
+const VIEW_FILES: usize = 2;
+
+const XATTR_PREFIX: &str = "user.mtfs.";
+
+/// Whether `name` is an extended attribute MTFS may keep for itself in the
+/// store. The mount neither shows nor forwards them.
+pub fn is_internal_xattr(name: &[u8]) -> bool {
+    name.starts_with(b"user.mtfs.") || name.starts_with(b"trusted.mtfs.")
+}
+
+/// Whether `ino` is the control directory or something in it.
+pub fn is_virtual(ino: u64) -> bool {
+    ino >= BASE
+}
+
+pub struct Control {
+    store: Arc<Store>,
+    tree: Arc<Tree>,
+    socket: PathBuf,
+    hash_files: bool,
+}
+
+impl Control {
+    /// With `hash_files`, `name@@mtfs-hash` can be looked up in any directory.
+    pub fn new(store: Arc<Store>, tree: Arc<Tree>, socket: PathBuf, hash_files: bool) -> Self {
+        Control {
+            store,
+            tree,
+            socket,
+            hash_files,
+        }
+    }
+
+    /// Looks up `name` in `parent` if it is part of the control directory or
+    /// a hash file, returning `None` if the lookup is one for the store.
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Option<io::Result<FileAttr>> {
+        if is_reserved(parent, name) {
+            return Some(self.attr(DIR_INO));
+        }
+        if self.hash_files && !is_virtual(parent) {
+            let stem = name.as_bytes().strip_suffix(HASH_SUFFIX);
+            if let Some(stem) = stem.filter(|stem| !stem.is_empty()) {
+                return Some(self.hash_file(parent, OsStr::from_bytes(stem)));
+            }
+        }
+        if parent != DIR_INO {
+            return is_virtual(parent).then(|| Err(io::Error::from_raw_os_error(libc::ENOENT)));
+        }
+        let found = self
+            .files()
+            .find(|(_, file)| name == file.as_str())
+            .map(|(ino, _)| self.attr(ino));
+        Some(found.unwrap_or_else(|| Err(io::Error::from_raw_os_error(libc::ENOENT))))
+    }
+
+    /// The inode and name of every file in the control directory.
+    fn files(&self) -> impl Iterator<Item = (u64, String)> + '_ {
+        self.tree.views().iter().enumerate().flat_map(|(i, view)| {
+            let skip = if i == 0 { 0 } else { VIEW_FILES };
+            (1..).zip(FILES).skip(skip).map(move |(j, (name, _))| {
+                let ino = BASE + (i * FILES.len()) as u64 + j;
+                (ino, view_meta(view.name(), name))
+            })
+        })
+    }
+
+    /// The file `ino` is, and the view it describes.
+    fn file(&self, ino: u64) -> io::Result<(usize, File)> {
+        if ino >= HASH_FILES {
+            return Ok((0, File::Hash(ino & !HASH_FILES)));
+        }
+        if ino == DIR_INO {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        let i = ino.checked_sub(BASE + 1).map(|i| i as usize);
+        match i.map(|i| (i / FILES.len(), i % FILES.len())) {
+            Some((view, i)) if view < self.tree.views().len() && (view == 0 || i >= VIEW_FILES) => {
+                Ok((view, FILES[i].1))
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+
+    fn hash_file(&self, parent: u64, name: &OsStr) -> io::Result<FileAttr> {
+        let meta = fs::symlink_metadata(self.store.child_path(parent, name)?)?;
+        let ino = self
+            .store
+            .inodes()
+            .link(parent, name, backing(&meta), meta.is_dir());
+        self.tree.ensure_attached(parent, ino);
+        self.attr(HASH_FILES | ino)
+    }
+
+    pub fn attr(&self, ino: u64) -> io::Result<FileAttr> {
+        // Times and ownership are those of the root of the store, or of the
+        // node whose hash a hash file holds.
+        let owner = match self.file(ino) {
+            Ok((_, File::Hash(real))) => self.store.path(real)?,
+            _ => self.store.root().to_owned(),
+        };
+        let mut attr = to_attr(ino, &fs::symlink_metadata(owner)?);
+        attr.blocks = 0;
+        attr.rdev = 0;
+        if ino == DIR_INO {
+            attr.perm = 0o555;
+            attr.nlink = 2;
+            attr.size = 0;
+            return Ok(attr);
+        }
+        let (view, file) = self.file(ino)?;
+        attr.nlink = 1;
+        if file == File::Socket {
+            attr.kind = FileType::Symlink;
+            attr.perm = 0o777;
+        } else {
+            attr.kind = FileType::RegularFile;
+            attr.perm = 0o444;
+        }
+        attr.size = match file {
+            // Known without hashing anything, so that stat stays cheap.
+            File::RootHash | File::Hash(_) => {
+                let hasher = self.tree.views()[view].hasher();
+                hasher.digest(b"").as_bytes().len() as u64 * 2 + 1
+            }
+            _ => self.contents(ino)?.len() as u64,
+        };
+        Ok(attr)
+    }
+
+    /// The entries of the control directory, as `(ino, kind, name)`.
+    pub fn entries(&self, ino: u64) -> io::Result<Vec<(u64, FileType, OsString)>> {
+        if ino != DIR_INO {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        let mut entries = vec![
+            (DIR_INO, FileType::Directory, OsString::from(".")),
+            (ROOT_INO, FileType::Directory, OsString::from("..")),
+        ];
+        for (ino, name) in self.files() {
+            let kind = match self.file(ino)?.1 {
+                File::Socket => FileType::Symlink,
+                _ => FileType::RegularFile,
+            };
+            entries.push((ino, kind, OsString::from(name)));
+        }
+        Ok(entries)
+    }
+
+    /// The value of the virtual extended attribute `name` of `ino`, or `None`
+    /// if `name` is not one of ours.
+    pub fn xattr(&self, ino: u64, name: &OsStr) -> Option<io::Result<Vec<u8>>> {
+        let name = name.to_str()?.strip_prefix(XATTR_PREFIX)?;
+        let (view, attr) = match name.split_once('.') {
+            Some((view, attr)) => (self.tree.view(view).ok()?, attr),
+            None => (0, name),
+        };
+        if attr != "hash" && attr != "valid" {
+            return None;
+        }
+        if is_virtual(ino) {
+            return Some(Err(io::Error::from_raw_os_error(libc::ENODATA)));
+        }
+        Some(if attr == "hash" {
+            self.tree
+                .hash(&*self.store, view, ino)
+                .map(|hash| hash.to_string().into_bytes())
+        } else {
+            Ok(if self.tree.is_valid(view, ino) {
+                b"1"
+            } else {
+                b"0"
+            }
+            .to_vec())
+        })
+    }
+
+    /// The contents of a file in the control directory, or the target of `socket`.
+    pub fn contents(&self, ino: u64) -> io::Result<Vec<u8>> {
+        let line = |value: &str| format!("{}\n", value).into_bytes();
+        let (view, file) = self.file(ino)?;
+        let hash = |ino| self.tree.hash(&*self.store, view, ino);
+        let view = &self.tree.views()[view];
+        Ok(match file {
+            File::Socket => self.socket.as_os_str().as_bytes().to_vec(),
+            File::Version => line(env!("CARGO_PKG_VERSION")),
+            File::Algorithm => line(view.hasher().name()),
+            File::Format => line(view.format().name()),
+            File::Ignore => line(view.ignore().map_or("none", |ignore| ignore.name())),
+            File::Attrs => line(view.attrs().setting().as_deref().unwrap_or("none")),
+            File::RootHash => line(&hash(ROOT_INO)?.to_string()),
+            File::Hash(real) => line(&hash(real)?.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs::{self, Attrs};
+    use crate::format::{GIT, PLAIN};
+    use crate::hasher::{SHA1, SHA256};
+    use crate::tree::{View, DEFAULT_VIEW};
+    use std::path::Path;
+
+    /// A control directory for a store with a file `f`, described by the
+    /// default view and by one called `alt`.
+    fn control(dir: &Path, hash_files: bool) -> Control {
+        fs::write(dir.join("f"), "f").unwrap();
+        let store = Arc::new(Store::open(dir.to_owned()).unwrap());
+        let tree = Arc::new(Tree::new(vec![
+            View::new(
+                DEFAULT_VIEW.to_owned(),
+                &SHA1,
+                &PLAIN,
+                None,
+                Attrs::NONE,
+                None,
+            ),
+            View::new("alt".to_owned(), &SHA256, &GIT, None, attrs::PERM, None),
+        ]));
+        Control::new(store, tree, PathBuf::from("/run/mtfs.sock"), hash_files)
+    }
+
+    fn errno(result: io::Result<impl Sized>) -> Option<i32> {
+        result.err().and_then(|err| err.raw_os_error())
+    }
+
+    #[test]
+    fn every_entry_has_attributes_and_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let control = control(dir.path(), false);
+        let root_hash = |view| {
+            let hash = control.tree.hash(&*control.store, view, ROOT_INO).unwrap();
+            format!("{}\n", hash)
+        };
+        let expected = [
+            ("socket", "/run/mtfs.sock".to_owned()),
+            ("version", format!("{}\n", env!("CARGO_PKG_VERSION"))),
+            ("algorithm", "sha1\n".to_owned()),
+            ("format", "plain\n".to_owned()),
+            ("ignore", "none\n".to_owned()),
+            ("attrs", "none\n".to_owned()),
+            ("root-hash", root_hash(0)),
+            ("alt.algorithm", "sha256\n".to_owned()),
+            ("alt.format", "git\n".to_owned()),
+            ("alt.ignore", "none\n".to_owned()),
+            ("alt.attrs", "perm\n".to_owned()),
+            ("alt.root-hash", root_hash(1)),
+        ];
+
+        let entries = control.entries(DIR_INO).unwrap();
+        let names: Vec<_> = entries.iter().map(|(_, _, name)| name.clone()).collect();
+        let mut expected_names = vec![OsString::from("."), OsString::from("..")];
+        expected_names.extend(expected.iter().map(|(name, _)| OsString::from(name)));
+        assert_eq!(names, expected_names);
+        assert_eq!(entries[0].0, DIR_INO);
+        assert_eq!(entries[1].0, ROOT_INO);
+
+        for ((ino, kind, name), (_, contents)) in entries[2..].iter().zip(&expected) {
+            assert!(is_virtual(*ino));
+            let attr = control.attr(*ino).unwrap();
+            assert_eq!(attr.ino, *ino);
+            assert_eq!(attr.kind, *kind, "{:?}", name);
+            assert_eq!(control.contents(*ino).unwrap(), contents.as_bytes());
+            assert_eq!(attr.size, contents.len() as u64, "{:?}", name);
+            assert_eq!(control.lookup(DIR_INO, name).unwrap().unwrap().ino, *ino);
+        }
+        assert_eq!(entries[2].1, FileType::Symlink);
+
+        // The slots of the files only the default view has stay empty, and
+        // there is nothing after the last view.
+        assert_eq!(
+            errno(control.attr(BASE + FILES.len() as u64 + 1)),
+            Some(libc::ENOENT)
+        );
+        let last = entries.last().unwrap().0;
+        assert_eq!(errno(control.attr(last + 1)), Some(libc::ENOENT));
+        assert_eq!(errno(control.contents(DIR_INO)), Some(libc::EISDIR));
+        assert_eq!(errno(control.entries(ROOT_INO)), Some(libc::ENOTDIR));
+        assert_eq!(
+            errno(control.lookup(DIR_INO, OsStr::new("alt.version")).unwrap()),
+            Some(libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn hash_and_valid_xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let control = control(dir.path(), false);
+        let f = control.store.walk(Path::new("f"), |_, _| {}).unwrap();
+        let xattr = |ino, name: &str| control.xattr(ino, OsStr::new(name));
+        let value =
+            |ino, name: &str| String::from_utf8(xattr(ino, name).unwrap().unwrap()).unwrap();
+        let hash = |view| {
+            control
+                .tree
+                .hash(&*control.store, view, f)
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(value(f, "user.mtfs.valid"), "0");
+        assert_eq!(value(f, "user.mtfs.alt.valid"), "0");
+        assert_eq!(value(f, "user.mtfs.hash"), hash(0));
+        assert_eq!(value(f, "user.mtfs.valid"), "1");
+        assert_eq!(value(f, "user.mtfs.alt.valid"), "0");
+        assert_eq!(value(f, "user.mtfs.alt.hash"), hash(1));
+        assert_ne!(hash(0), hash(1));
+        assert_eq!(value(f, "user.mtfs.alt.valid"), "1");
+        // The default view can be named too.
+        assert_eq!(value(f, "user.mtfs.default.hash"), hash(0));
+        assert_eq!(value(ROOT_INO, "user.mtfs.hash"), {
+            let root = control.tree.hash(&*control.store, 0, ROOT_INO).unwrap();
+            root.to_string()
+        });
+
+        for ino in [DIR_INO, DIR_INO + 1, HASH_FILES | f] {
+            assert_eq!(
+                errno(xattr(ino, "user.mtfs.hash").unwrap()),
+                Some(libc::ENODATA)
+            );
+            assert_eq!(
+                errno(xattr(ino, "user.mtfs.alt.valid").unwrap()),
+                Some(libc::ENODATA)
+            );
+        }
+        for name in [
+            "user.mtfs.nope.hash",
+            "user.mtfs.size",
+            "user.mtfs.alt.size",
+            "user.other",
+            "trusted.mtfs.hash",
+        ] {
+            assert!(xattr(f, name).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn hash_files_shadow_real_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("f@@mtfs-hash"), "real").unwrap();
+        fs::write(dir.path().join("@@mtfs-hash"), "real").unwrap();
+        let control = control(dir.path(), true);
+        let lookup = |parent, name: &str| control.lookup(parent, OsStr::new(name));
+        let f = control.store.walk(Path::new("f"), |_, _| {}).unwrap();
+
+        let attr = lookup(ROOT_INO, "f@@mtfs-hash").unwrap().unwrap();
+        assert_eq!(attr.ino, HASH_FILES | f);
+        assert_eq!(attr.perm, 0o444);
+        let hash = control.tree.hash(&*control.store, 0, f).unwrap();
+        assert_eq!(
+            control.contents(attr.ino).unwrap(),
+            format!("{}\n", hash).into_bytes()
+        );
+
+        // Without a stem, it is only a real file.
+        assert!(lookup(ROOT_INO, "@@mtfs-hash").is_none());
+        assert!(lookup(ROOT_INO, "f").is_none());
+        assert_eq!(
+            errno(lookup(ROOT_INO, "g@@mtfs-hash").unwrap()),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(lookup(ROOT_INO, ".mtfs").unwrap().unwrap().ino, DIR_INO);
+
+        // Nor are there any in the control directory.
+        for (parent, name) in [
+            (DIR_INO, "root-hash@@mtfs-hash"),
+            (DIR_INO, "f@@mtfs-hash"),
+            (DIR_INO + 1, "f@@mtfs-hash"),
+            (DIR_INO + 1, "f"),
+        ] {
+            assert_eq!(errno(lookup(parent, name).unwrap()), Some(libc::ENOENT));
+        }
+
+        let control = Control::new(
+            control.store.clone(),
+            control.tree.clone(),
+            PathBuf::new(),
+            false,
+        );
+        assert!(control
+            .lookup(ROOT_INO, OsStr::new("f@@mtfs-hash"))
+            .is_none());
+    }
+
+    #[test]
+    fn hash_sizes_match_contents() {
+        for hasher in crate::hasher::ALGORITHMS {
+            let dir = tempfile::tempdir().unwrap();
+            fs::write(dir.path().join("f"), "f").unwrap();
+            let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+            let view = View::new(
+                DEFAULT_VIEW.to_owned(),
+                hasher,
+                &PLAIN,
+                None,
+                Attrs::NONE,
+                None,
+            );
+            let tree = Arc::new(Tree::new(vec![view]));
+            let control = Control::new(store, tree, PathBuf::new(), true);
+            let hash_file = control.lookup(ROOT_INO, OsStr::new("f@@mtfs-hash"));
+            let root_hash = control.lookup(DIR_INO, OsStr::new("root-hash"));
+            for attr in [hash_file, root_hash] {
+                let attr = attr.unwrap().unwrap();
+                let contents = control.contents(attr.ino).unwrap();
+                assert_eq!(attr.size, contents.len() as u64, "{:?}", hasher);
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! `mtfs diff`: lists the differences between two trees by comparing their
+//! hashes a level at a time, so only differing subtrees are ever listed.
+//!
+//! Operands are written like rsync's: `host:path` is `path` on `host`, reached
+//! by running `mtfs serve path` there through the remote shell (`ssh` unless
+//! `-e` says otherwise), and `:path` runs `mtfs serve path` locally through a
+//! pipe, which exercises the same protocol. Anything else is a path in a local
+//! mount, found through its query socket.
+//!
+//! Every change is printed as it is found, as `A`, `D` or `M`, a tab and the
+//! path, with a slash after directories and `.` for the trees themselves. The
+//! exit status follows diff(1): 0 if the trees are the same, 1 if they differ
+//! and 2 if comparing them failed.
+//!
+//! With `--view`, both trees are compared in that view of their mounts.
+
+use crate::usage_error;
+use mtfs_client::diff::{self, Change, ChangeKind};
+use mtfs_client::{locate, Client};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+
+pub fn main(mut args: impl Iterator<Item = OsString>) -> ! {
+    let mut rsh = OsString::from("ssh");
+    let mut view = None;
+    let mut operands = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "-e" || arg == "--rsh" {
+            rsh = args
+                .next()
+                .unwrap_or_else(|| usage_error("-e requires an argument"));
+        } else if arg == "--view" {
+            view = Some(
+                args.next()
+                    .and_then(|view| view.into_string().ok())
+                    .unwrap_or_else(|| usage_error("--view requires a view name")),
+            );
+        } else {
+            operands.push(Operand::parse(arg));
+        }
+    }
+    let [old, new] = <[Operand; 2]>::try_from(operands)
+        .unwrap_or_else(|_| usage_error("expected two trees to compare"));
+
+    match compare(old, new, &rsh, view.as_deref()) {
+        Ok(false) => process::exit(0),
+        Ok(true) => process::exit(1),
+        Err(err) => {
+            eprintln!("mtfs diff: {}", err);
+            process::exit(2)
+        }
+    }
+}
+
+enum Operand {
+    Local(PathBuf),
+    /// `host` is `None` for a server run locally through a pipe.
+    Remote {
+        host: Option<OsString>,
+        path: OsString,
+    },
+}
+
+impl Operand {
+    /// Like rsync, only a colon before the first slash separates a host.
+    fn parse(arg: OsString) -> Self {
+        let bytes = arg.as_bytes();
+        match bytes.iter().position(|&b| b == b':' || b == b'/') {
+            Some(colon) if bytes[colon] == b':' => Operand::Remote {
+                host: (colon > 0).then(|| OsStr::from_bytes(&bytes[..colon]).to_owned()),
+                path: OsStr::from_bytes(&bytes[colon + 1..]).to_owned(),
+            },
+            _ => Operand::Local(arg.into()),
+        }
+    }
+
+    /// Connects to the server of the tree, returning the path of the tree on
+    /// it and the server process if one was started.
+    fn open(self, rsh: &OsStr) -> io::Result<(Client, PathBuf, Option<Child>)> {
+        let (host, path) = match self {
+            Operand::Local(path) => {
+                let (socket, path) = locate(path)?;
+                return Ok((Client::connect(socket)?, path, None));
+            }
+            Operand::Remote { host, path } => (host, path),
+        };
+        let mut command = match host {
+            Some(host) => {
+                let rsh = rsh
+                    .to_str()
+                    .unwrap_or_else(|| usage_error("-e must be UTF-8"));
+                let mut words = rsh.split_whitespace();
+                let program = words
+                    .next()
+                    .unwrap_or_else(|| usage_error("-e requires a command"));
+                let mut command = Command::new(program);
+                command.args(words).arg(host).arg("mtfs");
+                command
+            }
+            None => Command::new(env::current_exe()?),
+        };
+        let mut server = command
+            .arg("serve")
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let client = Client::over(server.stdout.take().unwrap(), server.stdin.take().unwrap())?;
+        Ok((client, PathBuf::new(), Some(server)))
+    }
+}
+
+/// Prints the differences from `old` to `new`, in `view` if not the default,
+/// returning whether there were any.
+fn compare(old: Operand, new: Operand, rsh: &OsStr, view: Option<&str>) -> io::Result<bool> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut found = false;
+    let mut print = |change: Change| {
+        found = true;
+        print(&mut out, &change)
+    };
+
+    let (mut old, old_path, old_server) = old.open(rsh)?;
+    let (mut new, new_path, new_server) = new.open(rsh)?;
+    if let Some(view) = view {
+        old.view(view)?;
+        new.view(view)?;
+    }
+    diff::between(&mut old, &old_path, &mut new, &new_path, &mut print)?;
+    out.flush()?;
+
+    // Closing the pipes lets the servers finish.
+    drop((old, new));
+    for mut server in [old_server, new_server].into_iter().flatten() {
+        server.wait()?;
+    }
+    Ok(found)
+}
+
+/// Writes `change` as a line of `mtfs diff` output.
+pub(crate) fn print(out: &mut impl Write, change: &Change) -> io::Result<()> {
+    let kind = match change.kind {
+        ChangeKind::Added => b'A',
+        ChangeKind::Removed => b'D',
+        ChangeKind::Modified => b'M',
+    };
+    out.write_all(&[kind, b'\t'])?;
+    match change.path.as_os_str() {
+        path if path.is_empty() => out.write_all(b".")?,
+        path => out.write_all(path.as_bytes())?,
+    }
+    if change.mode & libc::S_IFMT == libc::S_IFDIR {
+        out.write_all(b"/")?;
+    }
+    out.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs::Attrs;
+    use crate::format::{Format, GIT, PLAIN};
+    use crate::hasher::SHA1;
+    use crate::server::{Local, Server};
+    use crate::store::Store;
+    use crate::tree::{Tree, View, DEFAULT_VIEW};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// The changes from `old` to `new` in a store holding both, as printed.
+    fn changes(root: &Path, format: &'static dyn Format, old: &str, new: &str) -> String {
+        let store = Arc::new(Store::open(root.to_owned()).unwrap());
+        let view = View::new(
+            DEFAULT_VIEW.to_owned(),
+            &SHA1,
+            format,
+            None,
+            Attrs::NONE,
+            None,
+        );
+        let server = Server::new(store, Arc::new(Tree::new(vec![view])), None);
+        let mut out = Vec::new();
+        diff::diff(
+            &mut Local(&server, 0),
+            Path::new(old),
+            &mut Local(&server, 0),
+            Path::new(new),
+            &mut |change| print(&mut out, &change),
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn trees(root: &Path) {
+        for side in ["old", "new"] {
+            fs::create_dir_all(root.join(side).join("dir")).unwrap();
+            fs::write(root.join(side).join("dir/same"), "same").unwrap();
+        }
+    }
+
+    #[test]
+    fn renamed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        trees(dir.path());
+        fs::write(dir.path().join("old/dir/x"), "hi").unwrap();
+        fs::write(dir.path().join("new/dir/y"), "hi").unwrap();
+        for format in [&PLAIN as &dyn Format, &GIT] {
+            assert_eq!(
+                changes(dir.path(), format, "old", "new"),
+                "D\tdir/x\nA\tdir/y\n"
+            );
+        }
+    }
+
+    #[test]
+    fn chmodded_file() {
+        let dir = tempfile::tempdir().unwrap();
+        trees(dir.path());
+        for side in ["old", "new"] {
+            fs::write(dir.path().join(side).join("dir/run"), "#!/bin/sh\n").unwrap();
+        }
+        let run = dir.path().join("new/dir/run");
+        fs::set_permissions(&run, fs::Permissions::from_mode(0o755)).unwrap();
+        for format in [&PLAIN as &dyn Format, &GIT] {
+            assert_eq!(changes(dir.path(), format, "old", "new"), "M\tdir/run\n");
+        }
+        // Git only records the executable bit.
+        fs::set_permissions(&run, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(changes(dir.path(), &PLAIN, "old", "new"), "M\tdir/run\n");
+        assert_eq!(changes(dir.path(), &GIT, "old", "new"), "");
+    }
+
+    #[test]
+    fn identical_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        trees(dir.path());
+        assert_eq!(changes(dir.path(), &PLAIN, "old", "new"), "");
+    }
+}
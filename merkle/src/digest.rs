@@ -0,0 +1,54 @@
+use std::fmt;
+
+/// Longest digest any supported hash algorithm produces.
+pub const MAX_LEN: usize = 32;
+
+/// A hash value, stored inline so that nodes of the Merkle tree stay `Copy`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Digest {
+    len: u8,
+    bytes: [u8; MAX_LEN],
+}
+
+impl Digest {
+    /// Panics if `bytes` is longer than [`MAX_LEN`].
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut digest = Digest {
+            len: bytes.len() as u8,
+            bytes: [0; MAX_LEN],
+        };
+        digest.bytes[..bytes.len()].copy_from_slice(bytes);
+        digest
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    /// Parses the lowercase hex produced by `Display`.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if !hex.len().is_multiple_of(2) || hex.len() > 2 * MAX_LEN {
+            return None;
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Digest::new(&bytes))
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
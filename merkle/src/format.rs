@@ -0,0 +1,267 @@
+//! How a node's data and its children's hashes are encoded before hashing.
+//!
+//! The plain format is the README's `hash(concat(sorted child hashes, hash(data)))`,
+//! except that every child hash is preceded by the child's mode and name, so
+//! that renaming or chmodding a child changes its parent's hash.
+//! The git format produces git object ids instead: files hash as blobs and
+//! directories as trees, so the root hash of a clean checkout equals the output
+//! of `git write-tree`.
+
+use crate::attrs::{self, Attrs};
+use crate::digest::Digest;
+use crate::hasher::MerkleHasher;
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+
+/// A node's own data, with its length known up front.
+pub struct Data {
+    pub len: u64,
+    pub reader: Box<dyn Read + Send>,
+}
+
+/// A child of a directory, after it has been hashed.
+pub struct Entry {
+    pub name: OsString,
+    /// `st_mode` of the child.
+    pub mode: u32,
+    pub hash: Digest,
+}
+
+pub trait Format: Send + Sync {
+    /// Name accepted by the `format=` mount option and recorded in the private store.
+    fn name(&self) -> &'static str;
+
+    /// Whether the hasher can be used with this format.
+    fn supports(&self, _hasher: &dyn MerkleHasher) -> bool {
+        true
+    }
+
+    /// Whether a child called `name` takes part in its parent's hash at all.
+    /// Excluded children are not even hashed.
+    fn includes(&self, _name: &OsStr) -> bool {
+        true
+    }
+
+    /// Attributes of a child that go into its parent's hash, besides its
+    /// own hash, so that changing them must invalidate the parent.
+    fn attrs(&self) -> Attrs {
+        Attrs::NONE
+    }
+
+    /// Hashes a node. `children` is `None` for anything but a directory;
+    /// `data` is only called if the format needs the node's own data.
+    fn hash(
+        &self,
+        hasher: &dyn MerkleHasher,
+        children: Option<Vec<Entry>>,
+        data: &dyn Fn() -> io::Result<Data>,
+    ) -> io::Result<Digest>;
+}
+
+impl fmt::Debug for dyn Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub static PLAIN: PlainFormat = PlainFormat;
+pub static GIT: GitFormat = GitFormat;
+
+pub static FORMATS: [&dyn Format; 2] = [&PLAIN, &GIT];
+
+pub fn by_name(name: &str) -> Option<&'static dyn Format> {
+    FORMATS.iter().copied().find(|format| format.name() == name)
+}
+
+fn hash_data(hasher: &dyn MerkleHasher, prefix: &[u8], data: Data) -> io::Result<Digest> {
+    let mut state = hasher.begin();
+    state.update(prefix);
+    let copied = io::copy(&mut data.reader.take(data.len), &mut *state)?;
+    if copied != data.len {
+        // Truncated while we were reading it; the generation check will throw
+        // this hash away, but don't pretend it is complete either.
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(state.finish())
+}
+
+pub struct PlainFormat;
+
+impl Format for PlainFormat {
+    fn name(&self) -> &'static str {
+        "plain"
+    }
+
+    /// The type and permissions of every child.
+    fn attrs(&self) -> Attrs {
+        attrs::TYPE | attrs::PERM
+    }
+
+    fn hash(
+        &self,
+        hasher: &dyn MerkleHasher,
+        children: Option<Vec<Entry>>,
+        data: &dyn Fn() -> io::Result<Data>,
+    ) -> io::Result<Digest> {
+        let mut state = hasher.begin();
+        if let Some(mut children) = children {
+            children.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            for child in &children {
+                let mode = child.mode & (libc::S_IFMT | 0o7777);
+                state.update(format!("{:o} ", mode).as_bytes());
+                state.update(child.name.as_bytes());
+                state.update(&[0]);
+                state.update(child.hash.as_bytes());
+            }
+        }
+        state.update(hash_data(hasher, b"", data()?)?.as_bytes());
+        Ok(state.finish())
+    }
+}
+
+/// Git blob and tree objects, in either of git's object formats (SHA-1 or SHA-256).
+///
+/// Only directories, regular files and symlinks are part of a git tree, and
+/// directories with nothing in them are left out, as git cannot store them.
+pub struct GitFormat;
+
+impl GitFormat {
+    fn tree_mode(mode: u32) -> Option<&'static [u8]> {
+        match mode & libc::S_IFMT {
+            libc::S_IFDIR => Some(b"40000"),
+            libc::S_IFLNK => Some(b"120000"),
+            libc::S_IFREG if mode & libc::S_IXUSR != 0 => Some(b"100755"),
+            libc::S_IFREG => Some(b"100644"),
+            _ => None,
+        }
+    }
+
+    /// Git sorts tree entries as if directory names ended in `/`.
+    fn compare(a: &Entry, b: &Entry) -> Ordering {
+        fn key(entry: &Entry) -> impl Iterator<Item = u8> + '_ {
+            let slash = (entry.mode & libc::S_IFMT == libc::S_IFDIR).then_some(b'/');
+            entry.name.as_bytes().iter().copied().chain(slash)
+        }
+        key(a).cmp(key(b))
+    }
+}
+
+impl Format for GitFormat {
+    fn name(&self) -> &'static str {
+        "git"
+    }
+
+    fn supports(&self, hasher: &dyn MerkleHasher) -> bool {
+        matches!(hasher.name(), "sha1" | "sha256")
+    }
+
+    fn includes(&self, name: &OsStr) -> bool {
+        name != ".git"
+    }
+
+    /// The executable bit and the type of every entry.
+    fn attrs(&self) -> Attrs {
+        attrs::TYPE | attrs::PERM
+    }
+
+    fn hash(
+        &self,
+        hasher: &dyn MerkleHasher,
+        children: Option<Vec<Entry>>,
+        data: &dyn Fn() -> io::Result<Data>,
+    ) -> io::Result<Digest> {
+        let Some(mut children) = children else {
+            let data = data()?;
+            return hash_data(hasher, format!("blob {}\0", data.len).as_bytes(), data);
+        };
+        let empty_tree = hasher.digest(b"tree 0\0");
+        children.retain(|child| match child.mode & libc::S_IFMT {
+            libc::S_IFDIR => child.hash != empty_tree,
+            _ => Self::tree_mode(child.mode).is_some(),
+        });
+        children.sort_unstable_by(Self::compare);
+        let mut body = Vec::new();
+        for child in &children {
+            body.extend_from_slice(Self::tree_mode(child.mode).unwrap_or_default());
+            body.push(b' ');
+            body.extend_from_slice(child.name.as_bytes());
+            body.push(0);
+            body.extend_from_slice(child.hash.as_bytes());
+        }
+        let mut state = hasher.begin();
+        state.update(format!("tree {}\0", body.len()).as_bytes());
+        state.update(&body);
+        Ok(state.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs::Attrs;
+    use crate::hasher::{SHA1, SHA256};
+    use crate::inode::ROOT_INO;
+    use crate::store::Store;
+    use crate::tree::{Tree, View, DEFAULT_VIEW};
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::Path;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    fn root_hash(dir: &Path, hasher: &'static dyn MerkleHasher) -> String {
+        let store = Store::open(dir.to_owned()).unwrap();
+        let view = View::new(
+            DEFAULT_VIEW.to_owned(),
+            hasher,
+            &GIT,
+            None,
+            Attrs::NONE,
+            None,
+        );
+        let tree = Tree::new(vec![view]);
+        tree.hash(&store, 0, ROOT_INO).unwrap().to_string()
+    }
+
+    /// Files, an executable, a symlink, nested and empty directories, and
+    /// names that only sort differently once directories get their slash.
+    fn populate(dir: &Path) {
+        fs::write(dir.join("README"), "hello\n").unwrap();
+        fs::write(dir.join("run.sh"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("README", dir.join("link")).unwrap();
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("a/b/c"), "").unwrap();
+        fs::write(dir.join("a.txt"), "a\n").unwrap();
+        fs::write(dir.join("a-"), "dash\n").unwrap();
+        fs::create_dir(dir.join("empty")).unwrap();
+    }
+
+    #[test]
+    fn git_format_matches_write_tree() {
+        for (hasher, object_format) in [(&SHA1 as &dyn MerkleHasher, "sha1"), (&SHA256, "sha256")] {
+            let dir = tempfile::tempdir().unwrap();
+            populate(dir.path());
+            let object_format = format!("--object-format={}", object_format);
+            git(dir.path(), &["init", "-q", &object_format]);
+            git(dir.path(), &["add", "-A"]);
+            assert_eq!(
+                root_hash(dir.path(), hasher),
+                git(dir.path(), &["write-tree"])
+            );
+        }
+    }
+}
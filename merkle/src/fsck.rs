@@ -0,0 +1,407 @@
+//! `mtfs fsck`: checks the persisted hashes of an unmounted private store
+//! against the README's `check_invariant`, and optionally repairs them.
+//!
+//! Every node is rehashed from scratch. A node persisted as valid must have
+//! the hash that was just computed (hash validity), and every child included
+//! in its hash must be persisted as valid too (recursive validity), as must
+//! not be above a hard-linked file. Repairing rewrites wrong hashes of nodes
+//! that may stay valid and clears the valid bit of those that may not.
+//!
+//! The default view is checked, and so is every view named with `view=` in
+//! the options, each with the metadata backend given after its name.
+//!
+//! The exit status follows fsck(8): 0 if nothing was wrong, 1 if everything
+//! wrong was repaired, 4 if problems were left alone and 8 if checking failed.
+
+use crate::attrs::Attrs;
+use crate::ignore::{self, Ignore};
+use crate::inode::ROOT_INO;
+use crate::journal::Journal;
+use crate::metadata::{self, MetadataStore};
+use crate::options::{Options, ViewOptions};
+use crate::store::{view_meta, Store};
+use crate::tree::{self, Source, Tree, DEFAULT_VIEW};
+use crate::{format, hasher, usage_error};
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+
+pub fn main(mut args: impl Iterator<Item = OsString>) -> ! {
+    let mut options = Options::default();
+    let mut repair = false;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--repair" {
+            repair = true;
+        } else if arg == "-o" {
+            let list = args
+                .next()
+                .unwrap_or_else(|| usage_error("-o requires an argument"));
+            let list = list
+                .to_str()
+                .unwrap_or_else(|| usage_error("options must be UTF-8"));
+            options.parse(list).unwrap_or_else(|err| usage_error(&err));
+        } else {
+            paths.push(arg);
+        }
+    }
+    let [store] = <[OsString; 1]>::try_from(paths)
+        .unwrap_or_else(|_| usage_error("expected a private store"));
+
+    let result = fsck(store.into(), &options, repair, &mut io::stdout().lock());
+    match &result {
+        Ok(Summary { found: 0, .. }) => {}
+        Ok(Summary { found, repaired }) => {
+            eprintln!("mtfs fsck: {} problems found, {} repaired", found, repaired);
+        }
+        Err(err) => eprintln!("mtfs fsck: {}", err),
+    }
+    process::exit(status(&result))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Summary {
+    found: usize,
+    repaired: usize,
+}
+
+/// The exit status fsck(8) gives for `result`.
+fn status(result: &io::Result<Summary>) -> i32 {
+    match result {
+        Ok(Summary { found: 0, .. }) => 0,
+        Ok(Summary { found, repaired }) if repaired == found => 1,
+        Ok(_) => 4,
+        Err(_) => 8,
+    }
+}
+
+/// Checks the store at `root`, writing a line for every problem to `out`.
+fn fsck(
+    root: PathBuf,
+    options: &Options,
+    repair: bool,
+    out: &mut dyn Write,
+) -> io::Result<Summary> {
+    let store = Arc::new(Store::open(root)?);
+    let journal = Journal::open(&store)?.pending()?;
+    if !journal.is_empty() {
+        eprintln!(
+            "mtfs fsck: {} journal entries pending, the store was not unmounted cleanly",
+            journal.len()
+        );
+    }
+    let mut summary = Summary {
+        found: 0,
+        repaired: 0,
+    };
+    for view in &options.views {
+        let found = check_view(&store, view, repair, out)?;
+        summary.found += found.found;
+        summary.repaired += found.repaired;
+    }
+    Ok(summary)
+}
+
+/// Checks the records of one view, which must have been used on the store.
+fn check_view(
+    store: &Arc<Store>,
+    view: &ViewOptions,
+    repair: bool,
+    out: &mut dyn Write,
+) -> io::Result<Summary> {
+    // The stored hashes were made with the recorded settings, whatever the defaults are now.
+    let setting = |name: &str| -> io::Result<String> {
+        let name = view_meta(&view.name, name);
+        store
+            .recorded(&name)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no {} recorded", name)))
+    };
+    let invalid = |what: &str, name: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown {} {:?}", what, name),
+        )
+    };
+    let algorithm = setting("algorithm")?;
+    let hasher = hasher::by_name(&algorithm).ok_or_else(|| invalid("algorithm", algorithm))?;
+    let format_name = setting("format")?;
+    let format = format::by_name(&format_name).ok_or_else(|| invalid("format", format_name))?;
+    let ignore = match store.recorded(&view_meta(&view.name, "ignore"))?.as_deref() {
+        None | Some("none") => None,
+        Some(recorded) => {
+            let mode = recorded.split('+').next().unwrap_or(recorded);
+            let ignore = ignore::open(mode, store, view)?
+                .ok_or_else(|| invalid("ignore mode", mode.to_owned()))?;
+            if ignore.setting() != recorded {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "hashes were made with ignore setting {}, but its files now give {}",
+                        recorded,
+                        ignore.setting()
+                    ),
+                ));
+            }
+            Some(ignore)
+        }
+    };
+    let attrs = match store.recorded(&view_meta(&view.name, "attrs"))? {
+        None => Attrs::NONE,
+        Some(recorded) => Attrs::parse(&recorded).map_err(|_| invalid("attributes", recorded))?,
+    };
+    let setting = ignore.as_ref().map(Ignore::setting);
+    let Some(metadata) = metadata::open(
+        view.metadata,
+        &view.name,
+        store,
+        hasher,
+        format,
+        setting.as_deref(),
+        attrs,
+        repair,
+    )?
+    else {
+        return Ok(Summary {
+            found: 0,
+            repaired: 0,
+        });
+    };
+
+    // A tree without persistence recomputes everything.
+    let tree = Tree::new(vec![tree::View::new(
+        view.name.clone(),
+        hasher,
+        format,
+        ignore,
+        attrs,
+        None,
+    )]);
+    tree.hash(&**store, 0, ROOT_INO)?;
+    let mut fsck = Fsck {
+        store,
+        view: match view.name.as_str() {
+            DEFAULT_VIEW => String::new(),
+            name => format!(" in view {}", name),
+        },
+        tree: &tree,
+        metadata: &*metadata,
+        repair,
+        out,
+        summary: Summary {
+            found: 0,
+            repaired: 0,
+        },
+    };
+    fsck.check(ROOT_INO, PathBuf::from("/"))?;
+    Ok(fsck.summary)
+}
+
+struct Fsck<'a> {
+    store: &'a Store,
+    /// Said after every path, to tell views apart.
+    view: String,
+    tree: &'a Tree,
+    metadata: &'a dyn MetadataStore,
+    repair: bool,
+    out: &'a mut dyn Write,
+    summary: Summary,
+}
+
+impl Fsck<'_> {
+    /// Checks the subtree at `ino`, returning whether `ino` is persisted as
+    /// valid once any repair is done.
+    fn check(&mut self, ino: u64, path: PathBuf) -> io::Result<bool> {
+        let mut invalid_child = None;
+        if let Some(children) = self.store.children(ino)? {
+            for child in children {
+                if !self.tree.includes(0, ino, &child)? {
+                    continue;
+                }
+                let valid = self.check(child.ino, path.join(&child.name))?;
+                if !valid && invalid_child.is_none() {
+                    invalid_child = Some(child.name);
+                }
+            }
+        }
+
+        let Some(record) = self.metadata.load(ino)?.filter(|record| record.valid) else {
+            return Ok(false);
+        };
+        let cache = self
+            .tree
+            .node(ino)
+            .map(|node| node.caches[0])
+            .unwrap_or_default();
+        let expected = match (invalid_child, cache.hash) {
+            (Some(name), _) => Err(format!("valid, but its child {:?} is not", name)),
+            // Gone since the tree was hashed, so there is nothing to compare.
+            (None, None) => Err("valid, but could not be hashed".to_owned()),
+            (None, Some(_)) if !cache.durable => Err("valid above a hard-linked file".to_owned()),
+            (None, Some(hash)) => Ok(hash),
+        };
+        let expected = match expected {
+            Ok(expected) => expected,
+            Err(problem) => {
+                writeln!(self.out, "{}{}: {}", path.display(), self.view, problem)?;
+                self.summary.found += 1;
+                if self.repair {
+                    self.metadata.invalidated(ino, record.generation + 1)?;
+                    self.summary.repaired += 1;
+                }
+                return Ok(!self.repair);
+            }
+        };
+        if record.hash != expected {
+            writeln!(
+                self.out,
+                "{}{}: stored {}, expected {}",
+                path.display(),
+                self.view,
+                record.hash,
+                expected
+            )?;
+            self.summary.found += 1;
+            if self.repair {
+                self.metadata.validated(ino, expected, record.generation)?;
+                self.summary.repaired += 1;
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Format, PLAIN};
+    use crate::hasher::{MerkleHasher, SHA1};
+    use crate::metadata::Record;
+    use std::fs;
+    use std::path::Path;
+
+    fn check(root: &Path, repair: bool) -> (io::Result<Summary>, String) {
+        let mut options = Options::default();
+        options.parse("metadata=sidecar").unwrap();
+        let mut out = Vec::new();
+        let result = fsck(root.to_owned(), &options, repair, &mut out);
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    fn open_sidecar(store: &Arc<Store>) -> Box<dyn MetadataStore> {
+        metadata::open(
+            "sidecar",
+            DEFAULT_VIEW,
+            store,
+            &SHA1,
+            &PLAIN,
+            None,
+            Attrs::NONE,
+            true,
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn reports_and_repairs_broken_records() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("c"), "c").unwrap();
+        fs::create_dir(dir.path().join("d")).unwrap();
+        fs::write(dir.path().join("d/a"), "a").unwrap();
+
+        let c_hash = {
+            let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+            store
+                .record(&view_meta(DEFAULT_VIEW, "algorithm"), SHA1.name())
+                .unwrap();
+            store
+                .record(&view_meta(DEFAULT_VIEW, "format"), PLAIN.name())
+                .unwrap();
+            let tree = Tree::new(vec![tree::View::new(
+                DEFAULT_VIEW.to_owned(),
+                &SHA1,
+                &PLAIN,
+                None,
+                Attrs::NONE,
+                None,
+            )]);
+            tree.hash(&*store, 0, ROOT_INO).unwrap();
+            let sidecar = open_sidecar(&store);
+            for path in ["", "c", "d", "d/a"] {
+                let ino = store.walk(Path::new(path), |_, _| {}).unwrap();
+                let hash = tree.node(ino).unwrap().caches[0].hash.unwrap();
+                sidecar.validated(ino, hash, 1).unwrap();
+            }
+            drop(sidecar);
+            assert_eq!(status(&check(dir.path(), false).0), 0);
+
+            let sidecar = open_sidecar(&store);
+            let c = store.walk(Path::new("c"), |_, _| {}).unwrap();
+            sidecar.validated(c, SHA1.digest(b"wrong"), 1).unwrap();
+            let a = store.walk(Path::new("d/a"), |_, _| {}).unwrap();
+            sidecar.invalidated(a, 2).unwrap();
+            tree.node(c).unwrap().caches[0].hash.unwrap()
+        };
+
+        let (result, out) = check(dir.path(), false);
+        assert!(out.contains("/c: stored "), "{}", out);
+        assert!(
+            out.contains("/d: valid, but its child \"a\" is not"),
+            "{}",
+            out
+        );
+        assert_eq!(out.lines().count(), 2, "{}", out);
+        assert_eq!(status(&result), 4);
+        assert_eq!(
+            result.unwrap(),
+            Summary {
+                found: 2,
+                repaired: 0
+            }
+        );
+
+        // Invalidating d leaves the root above an invalid child too.
+        let (result, out) = check(dir.path(), true);
+        assert!(
+            out.contains("/: valid, but its child \"d\" is not"),
+            "{}",
+            out
+        );
+        assert_eq!(status(&result), 1);
+        assert_eq!(
+            result.unwrap(),
+            Summary {
+                found: 3,
+                repaired: 3
+            }
+        );
+
+        let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+        let sidecar = open_sidecar(&store);
+        let load = |path: &str| -> Record {
+            let ino = store.walk(Path::new(path), |_, _| {}).unwrap();
+            sidecar.load(ino).unwrap().unwrap()
+        };
+        let c = load("c");
+        assert!(c.valid);
+        assert_eq!(c.hash, c_hash);
+        assert!(!load("d").valid);
+        assert!(!load("").valid);
+        drop(sidecar);
+
+        let (result, out) = check(dir.path(), false);
+        assert_eq!(out, "");
+        assert_eq!(status(&result), 0);
+    }
+
+    #[test]
+    fn status_follows_fsck() {
+        let summary = |found, repaired| Ok(Summary { found, repaired });
+        assert_eq!(status(&summary(0, 0)), 0);
+        assert_eq!(status(&summary(2, 2)), 1);
+        assert_eq!(status(&summary(2, 1)), 4);
+        assert_eq!(status(&Err(io::Error::other("broken"))), 8);
+    }
+}
@@ -0,0 +1,62 @@
+//! `mtfs fsmonitor-hook`: a git fsmonitor hook speaking protocol version 2,
+//! answered from the change log of the mount the work tree is in.
+//!
+//! Set it up with `git config core.fsmonitor "mtfs fsmonitor-hook"`. Git runs
+//! it in the root of the work tree as `mtfs fsmonitor-hook 2 <token>`, and it
+//! prints a new token and the paths changed since the old one, each followed
+//! by a NUL byte, with a slash after directories whose contents may all have
+//! changed. A lone `/` tells git to rescan everything, which is the answer to
+//! a token that is missing, from before a remount after a crash, or too old.
+
+use crate::usage_error;
+use mtfs_client::{locate, Client};
+use std::ffi::OsString;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::process;
+
+pub fn main(args: impl Iterator<Item = OsString>) -> ! {
+    let args: Vec<OsString> = args.collect();
+    let (version, token) = match &args[..] {
+        [version] => (version, None),
+        [version, token] => (version, Some(token)),
+        _ => usage_error("expected a protocol version and a token"),
+    };
+    if version != "2" {
+        eprintln!(
+            "mtfs fsmonitor-hook: unsupported protocol version {}",
+            version.to_string_lossy()
+        );
+        process::exit(1);
+    }
+    // Git sends an empty token the first time.
+    let token = token
+        .and_then(|token| token.to_str())
+        .filter(|token| !token.is_empty());
+    if let Err(err) = answer(token) {
+        eprintln!("mtfs fsmonitor-hook: {}", err);
+        process::exit(1);
+    }
+    process::exit(0)
+}
+
+fn answer(token: Option<&str>) -> io::Result<()> {
+    let (socket, root) = locate(".")?;
+    let changed = Client::connect(socket)?.changes(root, token)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    out.write_all(changed.token.as_bytes())?;
+    out.write_all(b"\0")?;
+    match changed.paths {
+        Some(paths) => {
+            for changed in paths {
+                out.write_all(changed.path.as_os_str().as_bytes())?;
+                if changed.subtree {
+                    out.write_all(b"/")?;
+                }
+                out.write_all(b"\0")?;
+            }
+        }
+        None => out.write_all(b"/\0")?,
+    }
+    out.flush()
+}
@@ -0,0 +1,236 @@
+//! Shell-style wildcards over slash-separated paths, as git and rsync match
+//! them: `*` and `?` match within a component, `**` matches across
+//! components, and `**/` also matches no components at all, so `a/**/b`
+//! matches `a/b`. `[...]` matches one character of a set, negated by a leading
+//! `!` or `^`, and a backslash makes the next character literal.
+//!
+//! Each wildcard tries every place the rest of the pattern could start, so
+//! the places already found not to match are remembered: otherwise a pattern
+//! with many stars would take time exponential in their number.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Compare ASCII letters case-insensitively.
+    pub fold_case: bool,
+    /// Keep wildcards from matching a dot at the start of a component, as
+    /// Watchman does unless asked to include dotfiles.
+    pub hide_dotfiles: bool,
+}
+
+/// Whether `pattern` matches all of `path`.
+pub fn matches(pattern: &[u8], path: &[u8], options: Options) -> bool {
+    Matcher {
+        path,
+        options,
+        failed: RefCell::new(HashSet::new()),
+    }
+    .at(pattern, 0)
+}
+
+struct Matcher<'a> {
+    path: &'a [u8],
+    options: Options,
+    /// The `(pattern.len(), i)` of every call to [`Matcher::at`] that failed.
+    failed: RefCell<HashSet<(usize, usize)>>,
+}
+
+impl Matcher<'_> {
+    /// Whether `pattern`, which ends the whole pattern, matches `path[i..]`.
+    fn at(&self, pattern: &[u8], i: usize) -> bool {
+        let key = (pattern.len(), i);
+        if self.failed.borrow().contains(&key) {
+            return false;
+        }
+        let matched = self.from(pattern, i);
+        if !matched {
+            self.failed.borrow_mut().insert(key);
+        }
+        matched
+    }
+
+    fn from(&self, mut pattern: &[u8], mut i: usize) -> bool {
+        while let Some(&p) = pattern.first() {
+            match p {
+                b'*' => {
+                    let stars = pattern.iter().take_while(|&&b| b == b'*').count();
+                    let rest = &pattern[stars..];
+                    if stars == 1 {
+                        // Up to the end of this component.
+                        let end = self.path[i..]
+                            .iter()
+                            .position(|&b| b == b'/')
+                            .map_or(self.path.len(), |n| i + n);
+                        return (i..=end)
+                            .take_while(|&j| j == i || !self.hidden(i))
+                            .any(|j| self.at(rest, j));
+                    }
+                    if rest.first() == Some(&b'/') && self.at(&rest[1..], i) {
+                        return true;
+                    }
+                    return (i..=self.path.len())
+                        .take_while(|&j| j == i || !self.hidden(j - 1))
+                        .any(|j| self.at(rest, j));
+                }
+                b'?' => {
+                    if !self.wildcard(i) {
+                        return false;
+                    }
+                    pattern = &pattern[1..];
+                    i += 1;
+                }
+                b'[' => {
+                    if !self.wildcard(i) {
+                        return false;
+                    }
+                    match class(&pattern[1..], self.path[i], self.options.fold_case) {
+                        Some((matched, len)) if matched => {
+                            pattern = &pattern[1 + len..];
+                            i += 1;
+                        }
+                        Some(_) => return false,
+                        // An unterminated class is a literal bracket.
+                        None if self.path[i] == b'[' => {
+                            pattern = &pattern[1..];
+                            i += 1;
+                        }
+                        None => return false,
+                    }
+                }
+                _ => {
+                    let (literal, len) = match p {
+                        b'\\' if pattern.len() > 1 => (pattern[1], 2),
+                        _ => (p, 1),
+                    };
+                    match self.path.get(i) {
+                        Some(&c) if self.same(literal, c) => {
+                            pattern = &pattern[len..];
+                            i += 1;
+                        }
+                        _ => return false,
+                    }
+                }
+            }
+        }
+        i == self.path.len()
+    }
+
+    /// Whether a single-character wildcard can match `path[i]`.
+    fn wildcard(&self, i: usize) -> bool {
+        matches!(self.path.get(i), Some(&c) if c != b'/') && !self.hidden(i)
+    }
+
+    /// Whether `path[i]` is a dot wildcards must leave alone.
+    fn hidden(&self, i: usize) -> bool {
+        self.options.hide_dotfiles
+            && self.path.get(i) == Some(&b'.')
+            && (i == 0 || self.path[i - 1] == b'/')
+    }
+
+    fn same(&self, a: u8, b: u8) -> bool {
+        a == b || (self.options.fold_case && a.eq_ignore_ascii_case(&b))
+    }
+}
+
+/// Matches `c` against the class starting after a `[`, returning whether it
+/// matched and the length of the class including the `]`, or `None` if the
+/// class is never closed.
+fn class(pattern: &[u8], c: u8, fold_case: bool) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.first(), Some(b'!' | b'^'));
+    let mut i = negated as usize;
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut low = *pattern.get(i)?;
+        if low == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        if low == b'\\' {
+            i += 1;
+            low = *pattern.get(i)?;
+        }
+        i += 1;
+        let mut high = low;
+        if pattern.get(i) == Some(&b'-') && pattern.get(i + 1).is_some_and(|&b| b != b']') {
+            high = pattern[i + 1];
+            if high == b'\\' {
+                high = *pattern.get(i + 2)?;
+                i += 1;
+            }
+            i += 2;
+        }
+        let within = |c: u8| low <= c && c <= high;
+        matched |= within(c)
+            || (fold_case && (within(c.to_ascii_lowercase()) || within(c.to_ascii_uppercase())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, path: &str) -> bool {
+        matches(pattern.as_bytes(), path.as_bytes(), Options::default())
+    }
+
+    #[test]
+    fn stars() {
+        assert!(glob("*.o", "a.o"));
+        assert!(glob("*.o", ".o"));
+        assert!(!glob("*.o", "dir/a.o"));
+        assert!(glob("dir/*", "dir/a"));
+        assert!(!glob("dir/*", "dir/a/b"));
+        assert!(glob("**/a.o", "a.o"));
+        assert!(glob("**/a.o", "x/y/a.o"));
+        assert!(glob("a/**/b", "a/b"));
+        assert!(glob("a/**/b", "a/x/y/b"));
+        assert!(!glob("a/**/b", "ab"));
+        assert!(glob("a/**", "a/x/y"));
+        assert!(glob("**", "x/y"));
+    }
+
+    #[test]
+    fn single_characters_and_classes() {
+        assert!(glob("a?c", "abc"));
+        assert!(!glob("a?c", "a/c"));
+        assert!(glob("[a-c]x", "bx"));
+        assert!(!glob("[!a-c]x", "bx"));
+        assert!(glob("[^a-c]x", "dx"));
+        assert!(glob("[]]", "]"));
+        assert!(glob("[a-]", "-"));
+        assert!(glob("[", "["));
+        assert!(glob("\\*", "*"));
+        assert!(!glob("\\*", "a"));
+    }
+
+    #[test]
+    fn options() {
+        let fold = Options {
+            fold_case: true,
+            ..Options::default()
+        };
+        assert!(matches(b"*.TXT", b"a.txt", fold));
+        assert!(matches(b"[A-C]", b"b", fold));
+        assert!(!glob("*.TXT", "a.txt"));
+
+        let hide = Options {
+            hide_dotfiles: true,
+            ..Options::default()
+        };
+        assert!(!matches(b"*", b".git", hide));
+        assert!(!matches(b"**/config", b".git/config", hide));
+        assert!(matches(b".*", b".git", hide));
+        assert!(matches(b"*.c", b"a.c", hide));
+    }
+
+    #[test]
+    fn many_stars_do_not_backtrack_forever() {
+        let path = "a".repeat(60);
+        assert!(!glob(&("*a".repeat(30) + "b"), &path));
+        assert!(!glob(&("**a".repeat(30) + "b"), &path));
+        assert!(glob(&"*a".repeat(30), &path));
+    }
+}
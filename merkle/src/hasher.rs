@@ -0,0 +1,187 @@
+//! Hash algorithms the Merkle tree can be built with.
+//!
+//! SHA-1 matches git, SHA-256 and BLAKE3 are collision resistant, and xxHash3
+//! is the fastest when the tree only needs to detect accidental changes.
+
+use crate::digest::Digest;
+use sha1::Sha1;
+use sha2::Sha256;
+use std::fmt;
+use std::io;
+
+pub trait MerkleHasher: Send + Sync {
+    /// Name accepted by the `hash=` mount option and recorded in the private store.
+    fn name(&self) -> &'static str;
+
+    fn begin(&self) -> Box<dyn HashState>;
+
+    fn digest(&self, data: &[u8]) -> Digest {
+        let mut state = self.begin();
+        state.update(data);
+        state.finish()
+    }
+}
+
+/// An in-progress hash computation.
+pub trait HashState: Send {
+    fn update(&mut self, data: &[u8]);
+
+    fn finish(self: Box<Self>) -> Digest;
+}
+
+impl io::Write for dyn HashState {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for dyn MerkleHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub static SHA1: Sha1Hasher = Sha1Hasher;
+pub static SHA256: Sha256Hasher = Sha256Hasher;
+pub static BLAKE3: Blake3Hasher = Blake3Hasher;
+pub static XXH3: Xxh3Hasher = Xxh3Hasher;
+
+pub static ALGORITHMS: [&dyn MerkleHasher; 4] = [&SHA1, &SHA256, &BLAKE3, &XXH3];
+
+pub fn by_name(name: &str) -> Option<&'static dyn MerkleHasher> {
+    ALGORITHMS
+        .iter()
+        .copied()
+        .find(|hasher| hasher.name() == name)
+}
+
+impl<D: sha2::Digest + Send + 'static> HashState for D {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Digest {
+        Digest::new(&self.finalize())
+    }
+}
+
+pub struct Sha1Hasher;
+
+impl MerkleHasher for Sha1Hasher {
+    fn name(&self) -> &'static str {
+        "sha1"
+    }
+
+    fn begin(&self) -> Box<dyn HashState> {
+        Box::new(<Sha1 as sha1::Digest>::new())
+    }
+}
+
+pub struct Sha256Hasher;
+
+impl MerkleHasher for Sha256Hasher {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn begin(&self) -> Box<dyn HashState> {
+        Box::new(<Sha256 as sha2::Digest>::new())
+    }
+}
+
+pub struct Blake3Hasher;
+
+struct Blake3State(blake3::Hasher);
+
+impl HashState for Blake3State {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self: Box<Self>) -> Digest {
+        Digest::new(self.0.finalize().as_bytes())
+    }
+}
+
+impl MerkleHasher for Blake3Hasher {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn begin(&self) -> Box<dyn HashState> {
+        Box::new(Blake3State(blake3::Hasher::new()))
+    }
+}
+
+/// The 128-bit variant of xxHash3.
+pub struct Xxh3Hasher;
+
+struct Xxh3State(xxhash_rust::xxh3::Xxh3);
+
+impl HashState for Xxh3State {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(self: Box<Self>) -> Digest {
+        Digest::new(&self.0.digest128().to_be_bytes())
+    }
+}
+
+impl MerkleHasher for Xxh3Hasher {
+    fn name(&self) -> &'static str {
+        "xxh3"
+    }
+
+    fn begin(&self) -> Box<dyn HashState> {
+        Box::new(Xxh3State(xxhash_rust::xxh3::Xxh3::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answers() {
+        for (hasher, abc) in [
+            (
+                &SHA1 as &dyn MerkleHasher,
+                "a9993e364706816aba3e25717850c26c9cd0d89d",
+            ),
+            (
+                &SHA256,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                &BLAKE3,
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+            (&XXH3, "06b05ab6733a618578af5f94892f3950"),
+        ] {
+            assert_eq!(hasher.digest(b"abc").to_string(), abc, "{:?}", hasher);
+            // Fed in pieces, the state gives the same digest.
+            let mut state = hasher.begin();
+            state.update(b"a");
+            state.update(b"");
+            state.update(b"bc");
+            assert_eq!(state.finish().to_string(), abc, "{:?}", hasher);
+        }
+    }
+
+    #[test]
+    fn by_name() {
+        for hasher in ALGORITHMS {
+            assert_eq!(super::by_name(hasher.name()).unwrap().name(), hasher.name());
+        }
+        assert_eq!(super::by_name("sha256").unwrap().name(), "sha256");
+        assert!(super::by_name("SHA1").is_none());
+        assert!(super::by_name("md5").is_none());
+        assert!(super::by_name("").is_none());
+    }
+}
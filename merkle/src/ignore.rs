@@ -0,0 +1,756 @@
+//! Leaving ignored entries out of hashes, so that the hash of a tree only
+//! changes when something git would see, or rsync would send, changes.
+//!
+//! With the `ignore=` mount option, the children of a directory that the rules
+//! in force there exclude are not part of its hash, and nothing below an
+//! excluded directory can be taken back in. The rules are a list tried in
+//! order, where the first match decides, and which has the rules of files
+//! found in every directory, such as `.gitignore`, in their place. Those are
+//! the rules of the file in the entry's directory first, then of the ones
+//! above it. Rules with a slash in them are anchored to the directory of the
+//! file they come from.
+//!
+//! - `git` reads the rules of `.gitignore`, then of `.git/info/exclude`, then
+//!   of a global excludes file, and always leaves out `.git`. Rules from above
+//!   the root of a work tree, the nearest directory holding a `.git`, do not
+//!   apply in it. See [`git`].
+//! - `rsync` reads the filter rules given with `filter=`, as rsync's
+//!   `--filter='merge FILE'` would, or else does what `-F` does and reads the
+//!   rules in `.rsync-filter` in every directory. The mount then hashes
+//!   exactly what `rsync -a` from its root would send. See [`rsync`].
+//!
+//! The rules of each directory are read when it is first hashed. When one of
+//! the files holding them changes, or a directory moves so that rules
+//! anchored above it see it at a new path, the valid directories below are
+//! listed again and invalidated if the rules now leave out different children.
+//! A directory whose hash was loaded from before the mount cannot be told
+//! apart that way, so it is invalidated anyway. Files that are not in the
+//! store, such as the global excludes file, are only read at mount time.
+
+mod git;
+mod rsync;
+
+use crate::digest::Digest;
+use crate::glob;
+use crate::hasher::{MerkleHasher, SHA1};
+use crate::inode::InodeTable;
+use crate::options::ViewOptions;
+use crate::store::{is_reserved, Store};
+use crate::tree::Tree;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Values accepted by the `ignore=` mount option.
+pub static MODES: [&str; 3] = ["none", "git", "rsync"];
+
+/// Opens the ignore mode called `mode` for `store`, with the files `options`
+/// name, or returns `None` for `none`.
+pub fn open(mode: &str, store: &Arc<Store>, options: &ViewOptions) -> io::Result<Option<Ignore>> {
+    Ok(match mode {
+        "git" => {
+            let excludes_file = options.excludes_file.clone().or_else(default_excludes_file);
+            Some(Ignore::git(store.clone(), excludes_file.as_deref())?)
+        }
+        "rsync" => Some(Ignore::rsync(store.clone(), options.filter.as_deref())?),
+        _ => None,
+    })
+}
+
+/// Where git looks for the global excludes file unless configured otherwise.
+pub fn default_excludes_file() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(config) => Some(PathBuf::from(config).join("git/ignore")),
+        None => Some(PathBuf::from(env::var_os("HOME")?).join(".config/git/ignore")),
+    }
+}
+
+pub struct Ignore {
+    store: Arc<Store>,
+    name: &'static str,
+    /// What every entry is tried against, in order.
+    items: Vec<Item>,
+    /// The files read in every directory, which [`Item::PerDir`] refers to.
+    per_dir: Vec<PerDir>,
+    /// The file outside the store the items were read from, if any: git's
+    /// global excludes file or rsync's filter file.
+    file: Option<PathBuf>,
+    /// Digest of the files outside the store the items were read from, if any.
+    digest: Option<Digest>,
+    /// The rules in force in each directory hashed so far, by inode.
+    levels: Mutex<HashMap<u64, Arc<Level>>>,
+}
+
+enum Item {
+    Rule(Rule),
+    /// The rules of `per_dir[index]` in the entry's directory, then in those
+    /// above it if they are inherited.
+    PerDir(usize),
+}
+
+struct PerDir {
+    /// Path of the file relative to each directory.
+    path: PathBuf,
+    /// Whether its rules also apply below the directory.
+    inherit: bool,
+    /// How rsync is told to read it, or `None` for a `.gitignore`.
+    modifiers: Option<rsync::Modifiers>,
+}
+
+#[derive(Default)]
+struct Rules {
+    rules: Vec<Rule>,
+    /// Whether the rules inherited from above were cleared.
+    cleared: bool,
+}
+
+/// The rules in force in a directory.
+struct Level {
+    ino: u64,
+    /// Store-relative path of the directory when its rules were read.
+    path: PathBuf,
+    /// The directory above, unless this one is the root of a work tree.
+    parent: Option<Arc<Level>>,
+    /// The rules of each of [`Ignore::per_dir`] in this directory.
+    files: Vec<Rules>,
+    /// The files the rules were read from, as they were then.
+    stamp: Stamp,
+}
+
+struct Rule {
+    pattern: Vec<u8>,
+    /// For a pattern ending in `/***`, the pattern matching everything below
+    /// what `pattern` matches, which is then only matched as a directory.
+    contents: Option<Vec<u8>>,
+    /// Whether a match takes the entry in rather than leaving it out.
+    include: bool,
+    /// Whether the rule applies where the pattern does not match.
+    negate: bool,
+    /// Whether only directories match, as with a trailing slash.
+    dir_only: bool,
+    scope: Scope,
+}
+
+/// What a pattern is matched against.
+#[derive(Clone, Copy)]
+enum Scope {
+    /// The name of the entry.
+    Name,
+    /// Its path below the directory of the rule's file.
+    Path,
+    /// Any trailing part of its path below the top directory, starting at a
+    /// component, as rsync matches unanchored patterns with slashes.
+    Tail,
+}
+
+/// Identifies a version of a file: device, inode, mtime and size.
+type FileId = (u64, u64, i64, i64, u64);
+
+#[derive(PartialEq, Eq)]
+struct Stamp {
+    files: Vec<Option<FileId>>,
+    /// Whether the directory is the root of a git work tree.
+    root: bool,
+}
+
+impl Ignore {
+    /// Ignores entries of `store` the way git does, with the global rules in
+    /// `excludes_file`, if it exists.
+    pub fn git(store: Arc<Store>, excludes_file: Option<&Path>) -> io::Result<Self> {
+        let global = match excludes_file {
+            Some(path) => read(path)?,
+            None => None,
+        };
+        let per_dir = [".gitignore", ".git/info/exclude"].map(|path| PerDir {
+            path: PathBuf::from(path),
+            inherit: true,
+            modifiers: None,
+        });
+        let mut items = vec![Item::PerDir(0), Item::PerDir(1)];
+        let rules = global.as_deref().map(git::parse).unwrap_or_default();
+        items.extend(rules.into_iter().map(Item::Rule));
+        let file = excludes_file.map(Path::to_owned);
+        Ok(Ignore::new(
+            store,
+            "git",
+            items,
+            per_dir.into(),
+            file,
+            global,
+        ))
+    }
+
+    /// Ignores entries of `store` the way rsync excludes them, with the filter
+    /// rules in `filter_file`, or those in `.rsync-filter` files without one.
+    pub fn rsync(store: Arc<Store>, filter_file: Option<&Path>) -> io::Result<Self> {
+        let (items, per_dir, data) = match filter_file {
+            Some(path) => {
+                let loaded = rsync::load(path)?;
+                (loaded.items, loaded.per_dir, Some(loaded.data))
+            }
+            None => {
+                let loaded = rsync::per_dir_only();
+                (loaded.items, loaded.per_dir, None)
+            }
+        };
+        let file = filter_file.map(Path::to_owned);
+        Ok(Ignore::new(store, "rsync", items, per_dir, file, data))
+    }
+
+    fn new(
+        store: Arc<Store>,
+        name: &'static str,
+        items: Vec<Item>,
+        per_dir: Vec<PerDir>,
+        file: Option<PathBuf>,
+        data: Option<Vec<u8>>,
+    ) -> Self {
+        Ignore {
+            store,
+            name,
+            items,
+            per_dir,
+            file,
+            digest: data.map(|data| SHA1.digest(&data)),
+            levels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The value of the `ignore=` option this was made for.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The file outside the store the rules were read from, if any.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    /// What hashes depend on besides the files in the store: the mode, and the
+    /// files outside the store it read rules from, if any.
+    pub fn setting(&self) -> String {
+        match self.digest {
+            // Shortened like a commit id; it only has to tell files apart.
+            Some(digest) => format!("{}+{}", self.name, &digest.to_string()[..12]),
+            None => self.name.to_owned(),
+        }
+    }
+
+    /// Whether the child `name` of the directory `dir`, with `st_mode` `mode`,
+    /// is left out of the hash of `dir`.
+    pub fn ignores(&self, dir: u64, name: &OsStr, mode: u32) -> io::Result<bool> {
+        let is_dir = mode & libc::S_IFMT == libc::S_IFDIR;
+        Ok(self.level(dir)?.ignores(self, name, is_dir))
+    }
+
+    /// The rules in force in the directory `ino`, read if they are not yet.
+    fn level(&self, ino: u64) -> io::Result<Arc<Level>> {
+        if let Some(level) = self.levels.lock().unwrap().get(&ino) {
+            return Ok(level.clone());
+        }
+        let (path, parent) = {
+            let inodes = self.store.inodes();
+            (inodes.path(ino), inodes.parent(ino))
+        };
+        let path = path.ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let dir = self.store.root().join(&path);
+        let stamp = self.stamp(&dir)?;
+        let parent = match parent {
+            Some(parent) if !stamp.root => Some(self.level(parent)?),
+            _ => None,
+        };
+        let files = self
+            .per_dir
+            .iter()
+            .map(|file| {
+                let path = dir.join(&file.path);
+                let Some(data) = read(&path)? else {
+                    return Ok(Rules::default());
+                };
+                match &file.modifiers {
+                    None => Ok(Rules {
+                        rules: git::parse(&data),
+                        cleared: false,
+                    }),
+                    Some(modifiers) => rsync::parse_per_dir(&data, *modifiers).map_err(|err| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{}: {}", path.display(), err),
+                        )
+                    }),
+                }
+            })
+            .collect::<io::Result<_>>()?;
+        let level = Arc::new(Level {
+            ino,
+            path,
+            parent,
+            files,
+            stamp,
+        });
+        self.levels.lock().unwrap().insert(ino, level.clone());
+        Ok(level)
+    }
+
+    fn stamp(&self, dir: &Path) -> io::Result<Stamp> {
+        Ok(Stamp {
+            files: self
+                .per_dir
+                .iter()
+                .map(|file| identify(&dir.join(&file.path)))
+                .collect::<io::Result<_>>()?,
+            root: self.name == "git" && identify(&dir.join(".git"))?.is_some(),
+        })
+    }
+
+    /// Called after `ino` was modified, in case it holds rules. These hooks
+    /// invalidate what they must in `view`, the view of `tree` with these rules.
+    pub fn modified(&self, tree: &Tree, view: usize, ino: u64) {
+        let mut dirs = Vec::new();
+        {
+            let inodes = self.store.inodes();
+            for (parent, name) in inodes.links(ino) {
+                for file in &self.per_dir {
+                    if file.path.file_name() == Some(&name) {
+                        let within = file.path.parent().unwrap_or(Path::new(""));
+                        dirs.extend(holder(&inodes, parent, within));
+                    }
+                }
+            }
+        }
+        for dir in dirs {
+            self.report(self.changed(tree, view, dir));
+        }
+    }
+
+    /// Called after `ino` was linked into `parent`.
+    pub fn linked(&self, tree: &Tree, view: usize, parent: u64, ino: u64) {
+        self.relinked(tree, view, parent);
+        // A directory that moved is seen by rules anchored above it at a new path.
+        if self.levels.lock().unwrap().contains_key(&ino) {
+            self.report(self.recheck(tree, view, ino));
+        }
+    }
+
+    /// Called after `ino` was unlinked from `parent`.
+    pub fn unlinked(&self, tree: &Tree, view: usize, parent: u64, ino: u64) {
+        self.relinked(tree, view, parent);
+        if self.store.inodes().links(ino).is_empty() {
+            self.levels
+                .lock()
+                .unwrap()
+                .retain(|_, level| !level.within(ino));
+        }
+    }
+
+    /// Rechecks the directories whose rules a link added to or removed from
+    /// `parent` may have changed: `parent` itself, and the directory holding a
+    /// file of rules in a subdirectory, such as `.git/info/exclude`.
+    fn relinked(&self, tree: &Tree, view: usize, parent: u64) {
+        let mut dirs = Vec::new();
+        {
+            let inodes = self.store.inodes();
+            for file in &self.per_dir {
+                for within in file.path.parent().into_iter().flat_map(Path::ancestors) {
+                    dirs.extend(holder(&inodes, parent, within));
+                }
+            }
+        }
+        dirs.sort_unstable();
+        dirs.dedup();
+        for dir in dirs {
+            self.report(self.changed(tree, view, dir));
+        }
+    }
+
+    fn report(&self, result: io::Result<()>) {
+        if let Err(err) = result {
+            eprintln!("mtfs: cannot recheck ignore rules: {}", err);
+        }
+    }
+
+    /// Rechecks what the rules read in the directory `dir` apply to, if the
+    /// files they came from changed.
+    fn changed(&self, tree: &Tree, view: usize, dir: u64) -> io::Result<()> {
+        let Some(level) = self.levels.lock().unwrap().get(&dir).cloned() else {
+            return Ok(());
+        };
+        let path = self.store.path(dir)?;
+        if self.stamp(&path)? != level.stamp {
+            self.recheck(tree, view, dir)?;
+        }
+        Ok(())
+    }
+
+    /// Rereads the rules at and below the directory `dir`, and invalidates
+    /// every valid directory there whose rules now leave out different children.
+    fn recheck(&self, tree: &Tree, view: usize, dir: u64) -> io::Result<()> {
+        let old: HashMap<u64, Arc<Level>> = {
+            let mut levels = self.levels.lock().unwrap();
+            let stale: Vec<u64> = levels
+                .iter()
+                .filter(|(_, level)| level.within(dir))
+                .map(|(&ino, _)| ino)
+                .collect();
+            stale
+                .into_iter()
+                .filter_map(|ino| levels.remove_entry(&ino))
+                .collect()
+        };
+        for ino in tree.valid_below(view, dir) {
+            let path = match self.store.path(ino) {
+                Ok(path) => path,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            if !fs::symlink_metadata(&path)?.is_dir() {
+                continue;
+            }
+            let Some(old) = old.get(&ino) else {
+                // Rules below another work tree root are not affected, but
+                // there is no telling what rules a loaded hash was made with.
+                if !self.levels.lock().unwrap().contains_key(&ino) {
+                    tree.invalidate_view(view, ino);
+                }
+                continue;
+            };
+            let new = self.level(ino)?;
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                if is_reserved(ino, &name) {
+                    continue;
+                }
+                let is_dir = entry.file_type()?.is_dir();
+                if old.ignores(self, &name, is_dir) != new.ignores(self, &name, is_dir) {
+                    tree.invalidate_view(view, ino);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Ignore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+/// The directory holding rules in a file at `within` below it, if the
+/// directory `dir` is at `within`.
+fn holder(inodes: &InodeTable, dir: u64, within: &Path) -> Option<u64> {
+    if !inodes.path(dir)?.ends_with(within) {
+        return None;
+    }
+    within
+        .components()
+        .try_fold(dir, |dir, _| inodes.parent(dir))
+}
+
+impl Level {
+    /// Whether the child `name` of this directory is ignored.
+    fn ignores(&self, ignore: &Ignore, name: &OsStr, is_dir: bool) -> bool {
+        if ignore.name == "git" && name == ".git" {
+            return true;
+        }
+        let path = self.path.join(name);
+        let mut top = self;
+        while let Some(parent) = &top.parent {
+            top = parent;
+        }
+        let from_top = relative(&path, &top.path);
+        for item in &ignore.items {
+            let found = match item {
+                Item::Rule(rule) => rule.matches(from_top, from_top, is_dir).then_some(rule),
+                Item::PerDir(index) => self.per_dir(
+                    *index,
+                    ignore.per_dir[*index].inherit,
+                    &path,
+                    from_top,
+                    is_dir,
+                ),
+            };
+            if let Some(rule) = found {
+                return !rule.include;
+            }
+        }
+        false
+    }
+
+    /// The first rule of `files[index]` here or, if `inherit`, above that
+    /// matches `path`.
+    fn per_dir(
+        &self,
+        index: usize,
+        inherit: bool,
+        path: &Path,
+        from_top: &[u8],
+        is_dir: bool,
+    ) -> Option<&Rule> {
+        let mut level = self;
+        loop {
+            let rules = &level.files[index];
+            let from_base = relative(path, &level.path);
+            if let Some(rule) = rules
+                .rules
+                .iter()
+                .find(|rule| rule.matches(from_base, from_top, is_dir))
+            {
+                return Some(rule);
+            }
+            if !inherit || rules.cleared {
+                return None;
+            }
+            level = level.parent.as_deref()?;
+        }
+    }
+
+    /// Whether this is the directory `ino` or below it, in the same work tree.
+    fn within(&self, ino: u64) -> bool {
+        self.ino == ino
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.within(ino))
+    }
+}
+
+fn relative<'a>(path: &'a Path, base: &Path) -> &'a [u8] {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .as_os_str()
+        .as_bytes()
+}
+
+impl Rule {
+    /// Whether the rule applies to an entry at `from_base` below the directory
+    /// of its file and at `from_top` below the top directory.
+    fn matches(&self, from_base: &[u8], from_top: &[u8], is_dir: bool) -> bool {
+        let matched = (!self.dir_only || is_dir)
+            && match self.scope {
+                Scope::Name => {
+                    let name = from_base.rsplit(|&b| b == b'/').next().unwrap_or(from_base);
+                    self.fits(name, is_dir)
+                }
+                Scope::Path => self.fits(from_base, is_dir),
+                Scope::Tail => (0..from_top.len())
+                    .filter(|&i| i == 0 || from_top[i - 1] == b'/')
+                    .any(|i| self.fits(&from_top[i..], is_dir)),
+            };
+        matched != self.negate
+    }
+
+    fn fits(&self, subject: &[u8], is_dir: bool) -> bool {
+        let options = glob::Options::default();
+        match &self.contents {
+            Some(contents) => {
+                (is_dir && glob::matches(&self.pattern, subject, options))
+                    || glob::matches(contents, subject, options)
+            }
+            None => glob::matches(&self.pattern, subject, options),
+        }
+    }
+}
+
+fn identify(path: &Path) -> io::Result<Option<FileId>> {
+    match fs::metadata(path) {
+        Ok(meta) => Ok(Some((
+            meta.dev(),
+            meta.ino(),
+            meta.mtime(),
+            meta.mtime_nsec(),
+            meta.size(),
+        ))),
+        Err(err) if missing(&err) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// The contents of `path`, or `None` if there is no such file.
+fn read(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if missing(&err) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn missing(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes each `(path, contents)` below `root`, making directories on the way.
+    fn files(root: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    /// Whether `ignore` leaves out `path`, a directory if it ends in a slash.
+    fn ignored(ignore: &Ignore, path: &str) -> bool {
+        let (path, mode) = match path.strip_suffix('/') {
+            Some(path) => (Path::new(path), libc::S_IFDIR),
+            None => (Path::new(path), libc::S_IFREG),
+        };
+        let dir = ignore
+            .store
+            .walk(path.parent().unwrap(), |_, _| {})
+            .unwrap();
+        ignore
+            .ignores(dir, path.file_name().unwrap(), mode)
+            .unwrap()
+    }
+
+    #[test]
+    fn git_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("store");
+        files(
+            &root,
+            &[
+                (
+                    ".gitignore",
+                    "# *.c\n*.o\n!keep.o\nbuild/\n/top\ndoc/*.html\ntrailing\\ \nspaces  \r\n",
+                ),
+                (".git/info/exclude", "excluded\n!keep.log\n"),
+                ("sub/.gitignore", "!x.o\n"),
+                ("doc/deep/.keep", ""),
+                ("nested/.git/HEAD", ""),
+            ],
+        );
+        let global = dir.path().join("global");
+        fs::write(&global, "*.log\n").unwrap();
+        let store = Arc::new(Store::open(root).unwrap());
+        let ignore = Ignore::git(store, Some(&global)).unwrap();
+
+        for path in [
+            ".git/",
+            "a.o",
+            "build/",
+            "top",
+            "doc/a.html",
+            "trailing ",
+            "spaces",
+            "excluded",
+            "a.log",
+            "sub/a.o",
+            "sub/a.log",
+        ] {
+            assert!(ignored(&ignore, path), "{} should be ignored", path);
+        }
+        for path in [
+            "a.c",
+            "keep.o",
+            "build",
+            "sub/top",
+            "doc/deep/a.html",
+            "trailing",
+            "keep.log",
+            "sub/x.o",
+            // Not in the work tree the rules above come from.
+            "nested/a.o",
+        ] {
+            assert!(!ignored(&ignore, path), "{} should not be ignored", path);
+        }
+        assert!(ignored(&ignore, "nested/a.log"));
+    }
+
+    #[test]
+    fn rsync_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("store");
+        files(
+            dir.path(),
+            &[
+                (
+                    "filter",
+                    "# comment\n; comment\n+ /lib/***\n+ keep.o\n- *.o\nexclude /top\n\
+                     - build/\n- sub/deep\nH hidden\nP protected\n-r received\n\
+                     merge more\ndir-merge,ne .rules\n:- .list\n- /l*\n",
+                ),
+                ("more", "- merged\n"),
+                ("store/.rules", "- local\n"),
+                ("store/sub/.rules", "- a\n!\n- b\n"),
+                ("store/sub/.list", "word\n"),
+                ("store/lib/x", ""),
+            ],
+        );
+        let store = Arc::new(Store::open(root).unwrap());
+        let ignore = Ignore::rsync(store, Some(&dir.path().join("filter"))).unwrap();
+
+        for path in [
+            "a.o", "top", "build/", "sub/deep", "hidden", "merged", "local", ".rules", "sub/b",
+            "sub/word", "libc",
+        ] {
+            assert!(ignored(&ignore, path), "{} should be ignored", path);
+        }
+        for path in [
+            "keep.o",
+            "sub/top",
+            "build",
+            "deep",
+            "protected",
+            "received",
+            "sub/local",
+            "sub/a",
+            "lib/",
+            "lib/a.o",
+        ] {
+            assert!(!ignored(&ignore, path), "{} should not be ignored", path);
+        }
+    }
+
+    #[test]
+    fn rsync_filter_files_in_every_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        files(
+            dir.path(),
+            &[
+                (".rsync-filter", "- *.tmp\n"),
+                ("sub/.rsync-filter", "+ keep.tmp\n"),
+            ],
+        );
+        let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+        let ignore = Ignore::rsync(store, None).unwrap();
+        assert!(ignored(&ignore, "a.tmp"));
+        assert!(ignored(&ignore, "sub/a.tmp"));
+        assert!(!ignored(&ignore, "sub/keep.tmp"));
+        assert!(!ignored(&ignore, ".rsync-filter"));
+    }
+
+    #[test]
+    fn rsync_refuses_what_it_cannot_follow() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+        let filter = dir.path().join("filter");
+        for rules in [
+            "x foo\n",
+            "-/ /abs\n",
+            "-C\n",
+            "merge filter\n",
+            "dir-merge\n",
+        ] {
+            fs::write(&filter, rules).unwrap();
+            let err = Ignore::rsync(store.clone(), Some(&filter)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", rules);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! The syntax of `.gitignore`: one pattern per line, `#` starting a comment,
+//! `!` taking back in what an earlier pattern left out, a trailing slash
+//! matching only directories, and a slash anywhere else anchoring the pattern
+//! to the directory of the file. Later lines win over earlier ones.
+//!
+//! Unlike git, which keeps tracking a file once it is added whatever the
+//! rules say, the mount has no index to tell tracked files apart, so the rules
+//! leave out tracked and untracked files alike. The root hash of a work tree
+//! with ignored files committed in it therefore differs from what
+//! `git write-tree` gives, even with `format=git`.
+
+use super::{Rule, Scope};
+
+/// Parses the lines of a `.gitignore` into rules in the order they are tried,
+/// which is last line first.
+pub(super) fn parse(data: &[u8]) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for line in data.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.first().is_none_or(|&b| b == b'#') {
+            continue;
+        }
+        // Trailing spaces do not count unless escaped with a backslash.
+        let mut end = line.len();
+        while end > 0 && line[end - 1] == b' ' && !(end > 1 && line[end - 2] == b'\\') {
+            end -= 1;
+        }
+        let mut pattern = &line[..end];
+        let include = pattern.first() == Some(&b'!');
+        if include {
+            pattern = &pattern[1..];
+        }
+        let dir_only = pattern.last() == Some(&b'/');
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+        if pattern.is_empty() {
+            continue;
+        }
+        let scope = match pattern.contains(&b'/') {
+            true => Scope::Path,
+            false => Scope::Name,
+        };
+        rules.push(Rule {
+            pattern: pattern.strip_prefix(b"/").unwrap_or(pattern).to_vec(),
+            contents: None,
+            include,
+            negate: false,
+            dir_only,
+            scope,
+        });
+    }
+    rules.reverse();
+    rules
+}
@@ -0,0 +1,342 @@
+//! rsync's filter rules, as described under FILTER RULES in rsync(1).
+//!
+//! Each line is a rule such as `- *.o`, `+ /src/***` or `dir-merge .rules`,
+//! in short or long form, with modifiers after the rule name or a comma:
+//! `-!` applies where the pattern does not match, `:n` keeps the rules of a
+//! per-directory file from being inherited, `:e` excludes the file itself,
+//! `:w` splits it into words rather than lines, and `:-` or `:+` make every
+//! line a bare pattern to exclude or include, as `--exclude-from` does.
+//! A lone `!` clears the rules so far. Comments start with `#` or `;`.
+//!
+//! A pattern starting with a slash is anchored to the root of the transfer,
+//! or to the directory of a per-directory file. One with a slash or `**`
+//! elsewhere matches the end of the path, and one without only the name. A
+//! trailing slash only matches directories, and a trailing `/***` matches a
+//! directory and everything in it.
+//!
+//! Only what decides which files are sent is supported: receiver-side rules
+//! such as `protect` are skipped, and rules rsync could only apply to the
+//! absolute paths of a particular transfer, or with `--cvs-exclude`, are refused.
+
+use super::{Item, PerDir, Rule, Rules, Scope};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// How deep `merge` rules may nest before a loop is assumed.
+const MAX_DEPTH: usize = 16;
+
+/// How the rules of a merge file are read.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct Modifiers {
+    /// Whether every line is a pattern to include, or to exclude, rather than a rule.
+    only: Option<bool>,
+    /// Whether rules are separated by whitespace rather than lines.
+    words: bool,
+}
+
+/// What has been read of a filter file and those it merges.
+#[derive(Default)]
+pub(super) struct Loaded {
+    pub(super) items: Vec<Item>,
+    pub(super) per_dir: Vec<PerDir>,
+    /// The contents of every file read.
+    pub(super) data: Vec<u8>,
+}
+
+/// What a line holds.
+enum Line {
+    Rule(Rule),
+    Clear,
+    Merge {
+        path: PathBuf,
+        modifiers: Modifiers,
+        exclude_self: bool,
+    },
+    DirMerge {
+        path: PathBuf,
+        modifiers: Modifiers,
+        inherit: bool,
+        exclude_self: bool,
+    },
+}
+
+/// Reads the filter rules in `path` and the files it merges.
+pub(super) fn load(path: &Path) -> io::Result<Loaded> {
+    let mut loaded = Loaded::default();
+    merge(path, Modifiers::default(), &mut loaded, 0)?;
+    Ok(loaded)
+}
+
+/// What `rsync -F` reads: the rules of `.rsync-filter` in every directory.
+pub(super) fn per_dir_only() -> Loaded {
+    Loaded {
+        items: vec![Item::PerDir(0)],
+        per_dir: vec![PerDir {
+            path: PathBuf::from(".rsync-filter"),
+            inherit: true,
+            modifiers: Some(Modifiers::default()),
+        }],
+        data: Vec::new(),
+    }
+}
+
+fn merge(path: &Path, modifiers: Modifiers, loaded: &mut Loaded, depth: usize) -> io::Result<()> {
+    let contents = fs::read(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    loaded.data.extend_from_slice(&contents);
+    for token in tokens(&contents, modifiers) {
+        let line = match parse(token, modifiers) {
+            Ok(Some(line)) => line,
+            Ok(None) => continue,
+            Err(err) => return Err(invalid(path, token, &err)),
+        };
+        match line {
+            Line::Rule(rule) => loaded.items.push(Item::Rule(rule)),
+            Line::Clear => loaded.items.clear(),
+            Line::Merge {
+                path: file,
+                modifiers,
+                exclude_self,
+            } => {
+                if depth == MAX_DEPTH {
+                    return Err(invalid(path, token, "merge files nest too deep"));
+                }
+                let file = path.parent().unwrap_or(Path::new("")).join(file);
+                merge(&file, modifiers, loaded, depth + 1)?;
+                if exclude_self {
+                    loaded.items.extend(exclude(&file).map(Item::Rule));
+                }
+            }
+            Line::DirMerge {
+                path: file,
+                modifiers,
+                inherit,
+                exclude_self,
+            } => {
+                loaded.items.push(Item::PerDir(loaded.per_dir.len()));
+                if exclude_self {
+                    loaded.items.extend(exclude(&file).map(Item::Rule));
+                }
+                loaded.per_dir.push(PerDir {
+                    path: file,
+                    inherit,
+                    modifiers: Some(modifiers),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses a per-directory merge file read with `modifiers`.
+pub(super) fn parse_per_dir(data: &[u8], modifiers: Modifiers) -> Result<Rules, String> {
+    let mut rules = Rules::default();
+    for token in tokens(data, modifiers) {
+        let show = |err: &str| format!("{} in {:?}", err, String::from_utf8_lossy(token));
+        match parse(token, modifiers).map_err(|err| show(&err))? {
+            None => {}
+            Some(Line::Rule(rule)) => rules.rules.push(rule),
+            Some(Line::Clear) => {
+                rules.rules.clear();
+                rules.cleared = true;
+            }
+            Some(Line::Merge { .. } | Line::DirMerge { .. }) => {
+                return Err(show("merge rules are only supported in the filter file"))
+            }
+        }
+    }
+    Ok(rules)
+}
+
+fn invalid(path: &Path, token: &[u8], err: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{}: {} in {:?}",
+            path.display(),
+            err,
+            String::from_utf8_lossy(token)
+        ),
+    )
+}
+
+/// The rules in `data`, one per line or word, without comments.
+fn tokens(data: &[u8], modifiers: Modifiers) -> Vec<&[u8]> {
+    if modifiers.words {
+        return data
+            .split(u8::is_ascii_whitespace)
+            .filter(|word| !word.is_empty())
+            .collect();
+    }
+    data.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty() && line[0] != b'#' && line[0] != b';')
+        .collect()
+}
+
+/// Parses one rule, or returns `None` for one that does not decide what is sent.
+fn parse(token: &[u8], modifiers: Modifiers) -> Result<Option<Line>, String> {
+    if token == b"!" {
+        return Ok(Some(Line::Clear));
+    }
+    if let Some(include) = modifiers.only {
+        let (include, pattern) = match token {
+            [b'-', b' ', rest @ ..] => (false, rest),
+            [b'+', b' ', rest @ ..] => (true, rest),
+            _ => (include, token),
+        };
+        return rule(pattern, include, false).map(|rule| Some(Line::Rule(rule)));
+    }
+
+    let (head, arg) = match token.iter().position(|&b| b == b' ' || b == b'_') {
+        Some(i) => (&token[..i], &token[i + 1..]),
+        None => (token, &b""[..]),
+    };
+    const LONG: [(&[u8], u8); 9] = [
+        (b"exclude", b'-'),
+        (b"include", b'+'),
+        (b"merge", b'.'),
+        (b"dir-merge", b':'),
+        (b"hide", b'H'),
+        (b"show", b'S'),
+        (b"protect", b'P'),
+        (b"risk", b'R'),
+        (b"clear", b'!'),
+    ];
+    let long = LONG.iter().find_map(|&(name, short)| {
+        let rest = head.strip_prefix(name)?;
+        matches!(rest.first(), None | Some(b',')).then_some((short, rest))
+    });
+    let (kind, mut flags) = match (long, head) {
+        (Some(found), _) => found,
+        (None, [kind, flags @ ..]) => (*kind, flags),
+        (None, []) => return Err("unknown rule".to_owned()),
+    };
+    flags = flags.strip_prefix(b",").unwrap_or(flags);
+
+    match kind {
+        b'-' | b'+' | b'H' | b'S' => {
+            let (mut negate, mut sender, mut receiver) = (false, false, false);
+            for &flag in flags {
+                match flag {
+                    b'!' => negate = true,
+                    b's' => sender = true,
+                    b'r' => receiver = true,
+                    b'p' => {}
+                    // Rules for the names of extended attributes.
+                    b'x' => return Ok(None),
+                    _ => return Err(unsupported(flag)),
+                }
+            }
+            if receiver && !sender {
+                return Ok(None);
+            }
+            let include = matches!(kind, b'+' | b'S');
+            rule(arg, include, negate).map(|rule| Some(Line::Rule(rule)))
+        }
+        b'.' | b':' => {
+            let mut modifiers = Modifiers::default();
+            let (mut inherit, mut exclude_self) = (true, false);
+            let (mut sender, mut receiver) = (false, false);
+            for &flag in flags {
+                match flag {
+                    b'-' => modifiers.only = Some(false),
+                    b'+' => modifiers.only = Some(true),
+                    b'w' => modifiers.words = true,
+                    b'n' => inherit = false,
+                    b'e' => exclude_self = true,
+                    b's' => sender = true,
+                    b'r' => receiver = true,
+                    _ => return Err(unsupported(flag)),
+                }
+            }
+            if arg.is_empty() {
+                return Err("missing file name".to_owned());
+            }
+            if receiver && !sender {
+                return Ok(None);
+            }
+            Ok(Some(match kind {
+                b'.' => Line::Merge {
+                    path: PathBuf::from(OsStr::from_bytes(arg)),
+                    modifiers,
+                    exclude_self,
+                },
+                // A leading slash only says to start at the root of the transfer.
+                _ => Line::DirMerge {
+                    path: PathBuf::from(OsStr::from_bytes(arg.strip_prefix(b"/").unwrap_or(arg))),
+                    modifiers,
+                    inherit,
+                    exclude_self,
+                },
+            }))
+        }
+        b'P' | b'R' => Ok(None),
+        b'!' if arg.is_empty() && flags.is_empty() => Ok(Some(Line::Clear)),
+        _ => Err("unknown rule".to_owned()),
+    }
+}
+
+fn unsupported(flag: u8) -> String {
+    match flag {
+        b'/' => "rules on absolute paths are not supported".to_owned(),
+        b'C' => "--cvs-exclude rules are not supported".to_owned(),
+        _ => format!("unknown modifier {:?}", flag as char),
+    }
+}
+
+fn rule(pattern: &[u8], include: bool, negate: bool) -> Result<Rule, String> {
+    let (pattern, contents) = match pattern.strip_suffix(b"/***") {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let dir_only = !contents && pattern.last() == Some(&b'/');
+    let pattern = match dir_only {
+        true => &pattern[..pattern.len() - 1],
+        false => pattern,
+    };
+    let (pattern, anchored) = match pattern.strip_prefix(b"/") {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    if pattern.is_empty() {
+        return Err("missing pattern".to_owned());
+    }
+    let scope = if anchored {
+        Scope::Path
+    } else if contents || pattern.contains(&b'/') || pattern.windows(2).any(|w| w == b"**") {
+        Scope::Tail
+    } else {
+        Scope::Name
+    };
+    Ok(Rule {
+        contents: contents.then(|| [pattern, &b"/**"[..]].concat()),
+        pattern: pattern.to_vec(),
+        include,
+        negate,
+        dir_only,
+        scope,
+    })
+}
+
+/// A rule excluding the file at `path` by name.
+fn exclude(path: &Path) -> Option<Rule> {
+    let mut pattern = Vec::new();
+    for &b in path.file_name()?.as_bytes() {
+        if matches!(b, b'*' | b'?' | b'[' | b'\\') {
+            pattern.push(b'\\');
+        }
+        pattern.push(b);
+    }
+    Some(Rule {
+        pattern,
+        contents: None,
+        include: false,
+        negate: false,
+        dir_only: false,
+        scope: Scope::Name,
+    })
+}
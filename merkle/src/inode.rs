@@ -0,0 +1,289 @@
+//! Stable mapping between FUSE inode numbers and paths in the private store.
+//!
+//! FUSE inodes are allocated per backing `(st_dev, st_ino)` pair, so every hard
+//! link to a file shares one inode, and a name that is looked up twice always
+//! resolves to the same inode. Each inode remembers the `(parent, name)` links
+//! it is reachable through rather than a full path, which makes renaming a
+//! directory O(1) regardless of how many descendants have been looked up.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+pub const ROOT_INO: u64 = fuser::FUSE_ROOT_ID;
+
+/// Identity of a file in the private store: `(st_dev, st_ino)`.
+pub type Backing = (u64, u64);
+
+#[derive(Debug)]
+struct Inode {
+    backing: Backing,
+    /// `(parent, name)` pairs this inode is reachable through.
+    /// Directories have exactly one; the root has none.
+    links: Vec<(u64, OsString)>,
+    /// Kernel lookup count, as defined by the FUSE `forget` protocol.
+    lookups: u64,
+}
+
+#[derive(Debug)]
+pub struct InodeTable {
+    inodes: HashMap<u64, Inode>,
+    by_backing: HashMap<Backing, u64>,
+    next_ino: u64,
+}
+
+impl InodeTable {
+    pub fn new(root: Backing) -> Self {
+        let mut inodes = HashMap::new();
+        inodes.insert(
+            ROOT_INO,
+            Inode {
+                backing: root,
+                links: Vec::new(),
+                lookups: 1,
+            },
+        );
+        InodeTable {
+            inodes,
+            by_backing: HashMap::from([(root, ROOT_INO)]),
+            next_ino: ROOT_INO + 1,
+        }
+    }
+
+    /// Path of `ino` relative to the root of the private store.
+    pub fn path(&self, ino: u64) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut cur = ino;
+        while cur != ROOT_INO {
+            let (parent, name) = self.inodes.get(&cur)?.links.first()?;
+            names.push(name.as_os_str());
+            cur = *parent;
+        }
+        Some(names.iter().rev().collect())
+    }
+
+    /// Paths of every known link to `ino`, relative to the root of the private store.
+    pub fn paths(&self, ino: u64) -> Vec<PathBuf> {
+        let Some(inode) = self.inodes.get(&ino) else {
+            return Vec::new();
+        };
+        if ino == ROOT_INO {
+            return vec![PathBuf::new()];
+        }
+        inode
+            .links
+            .iter()
+            .filter_map(|(parent, name)| Some(self.path(*parent)?.join(name)))
+            .collect()
+    }
+
+    /// The `(parent, name)` of every known link to `ino`.
+    pub fn links(&self, ino: u64) -> Vec<(u64, OsString)> {
+        self.inodes
+            .get(&ino)
+            .map(|inode| inode.links.clone())
+            .unwrap_or_default()
+    }
+
+    /// The directory `ino` was first linked from, or `None` for the root.
+    pub fn parent(&self, ino: u64) -> Option<u64> {
+        Some(self.inodes.get(&ino)?.links.first()?.0)
+    }
+
+    /// Absolute path of `ino` inside the private store rooted at `root`.
+    pub fn store_path(&self, root: &Path, ino: u64) -> Option<PathBuf> {
+        self.path(ino).map(|rel| root.join(rel))
+    }
+
+    pub fn backing(&self, ino: u64) -> Option<Backing> {
+        self.inodes.get(&ino).map(|inode| inode.backing)
+    }
+
+    pub fn contains(&self, ino: u64) -> bool {
+        self.inodes.contains_key(&ino)
+    }
+
+    /// Records that `name` in `parent` resolves to `backing` and returns its inode,
+    /// allocating one on first sight. Does not touch the lookup count.
+    pub fn link(&mut self, parent: u64, name: &OsStr, backing: Backing, is_dir: bool) -> u64 {
+        if let Some(&ino) = self.by_backing.get(&backing) {
+            let inode = self.inodes.get_mut(&ino).expect("by_backing out of sync");
+            if is_dir {
+                // A directory has a single name, so any new sighting supersedes the old one.
+                inode.links.clear();
+            }
+            if !inode.links.iter().any(|(p, n)| *p == parent && n == name) {
+                inode.links.push((parent, name.to_owned()));
+            }
+            return ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(
+            ino,
+            Inode {
+                backing,
+                links: vec![(parent, name.to_owned())],
+                lookups: 0,
+            },
+        );
+        self.by_backing.insert(backing, ino);
+        ino
+    }
+
+    /// Like [`InodeTable::link`], but also counts a kernel lookup.
+    pub fn lookup(&mut self, parent: u64, name: &OsStr, backing: Backing, is_dir: bool) -> u64 {
+        let ino = self.link(parent, name, backing, is_dir);
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups += 1;
+        }
+        ino
+    }
+
+    /// Inode of the file identified by `backing`, if it has been seen before.
+    pub fn find(&self, backing: Backing) -> Option<u64> {
+        self.by_backing.get(&backing).copied()
+    }
+
+    /// Removes the `(parent, name)` link after the name was deleted from the store.
+    pub fn unlink(&mut self, ino: u64, parent: u64, name: &OsStr) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.links.retain(|(p, n)| !(*p == parent && n == name));
+            if inode.links.is_empty() {
+                // The store may hand the backing inode number to a new file
+                // while the kernel still holds a reference to this one.
+                let backing = inode.backing;
+                if inode.lookups == 0 {
+                    self.evict(ino);
+                } else if self.by_backing.get(&backing) == Some(&ino) {
+                    self.by_backing.remove(&backing);
+                }
+            }
+        }
+    }
+
+    /// Moves the `(parent, name)` link of `ino` to `(new_parent, new_name)`.
+    pub fn relink(
+        &mut self,
+        ino: u64,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            match inode
+                .links
+                .iter_mut()
+                .find(|(p, n)| *p == parent && n == name)
+            {
+                Some(link) => *link = (new_parent, new_name.to_owned()),
+                None => inode.links.push((new_parent, new_name.to_owned())),
+            }
+        }
+    }
+
+    /// Drops `nlookup` kernel references to `ino`.
+    ///
+    /// Inodes stay in the table while they are still linked into the tree,
+    /// so an inode number is never reused for a different file.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups = inode.lookups.saturating_sub(nlookup);
+            if inode.lookups == 0 && inode.links.is_empty() && ino != ROOT_INO {
+                self.evict(ino);
+            }
+        }
+    }
+
+    fn evict(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.remove(&ino) {
+            if self.by_backing.get(&inode.backing) == Some(&ino) {
+                self.by_backing.remove(&inode.backing);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> InodeTable {
+        InodeTable::new((1, 2))
+    }
+
+    fn name(name: &str) -> &OsStr {
+        OsStr::new(name)
+    }
+
+    #[test]
+    fn hard_links_share_an_inode() {
+        let mut table = table();
+        let dir = table.link(ROOT_INO, name("d"), (1, 3), true);
+        let a = table.link(ROOT_INO, name("a"), (1, 10), false);
+        assert_eq!(table.link(dir, name("b"), (1, 10), false), a);
+        assert_eq!(table.link(ROOT_INO, name("a"), (1, 10), false), a);
+        assert_eq!(table.find((1, 10)), Some(a));
+        assert_eq!(
+            table.links(a),
+            [(ROOT_INO, OsString::from("a")), (dir, OsString::from("b"))]
+        );
+        assert_eq!(table.paths(a), [PathBuf::from("a"), PathBuf::from("d/b")]);
+        assert_eq!(table.path(a), Some(PathBuf::from("a")));
+        assert_eq!(table.parent(a), Some(ROOT_INO));
+
+        // A directory has one name, so seeing it elsewhere moves it.
+        let e = table.link(ROOT_INO, name("e"), (1, 4), true);
+        assert_eq!(table.link(e, name("d"), (1, 3), true), dir);
+        assert_eq!(table.links(dir), [(e, OsString::from("d"))]);
+        assert_eq!(table.path(a), Some(PathBuf::from("a")));
+        assert_eq!(table.paths(a)[1], PathBuf::from("e/d/b"));
+
+        assert_eq!(table.path(ROOT_INO), Some(PathBuf::new()));
+        assert_eq!(table.paths(ROOT_INO), [PathBuf::new()]);
+        assert_eq!(table.parent(ROOT_INO), None);
+    }
+
+    #[test]
+    fn forgotten_inodes_go_once_unlinked() {
+        let mut table = table();
+        let a = table.lookup(ROOT_INO, name("a"), (1, 10), false);
+        assert_eq!(table.lookup(ROOT_INO, name("a"), (1, 10), false), a);
+
+        // Still linked, so forgetting every lookup keeps it.
+        table.forget(a, 2);
+        assert!(table.contains(a));
+        assert_eq!(table.find((1, 10)), Some(a));
+
+        table.lookup(ROOT_INO, name("a"), (1, 10), false);
+        table.unlink(a, ROOT_INO, name("a"));
+        assert!(table.contains(a));
+        // The backing inode number may be reused by a new file already.
+        assert_eq!(table.find((1, 10)), None);
+        let b = table.lookup(ROOT_INO, name("b"), (1, 10), false);
+        assert_ne!(b, a);
+
+        table.forget(a, 5);
+        assert!(!table.contains(a));
+        assert_eq!(table.find((1, 10)), Some(b));
+
+        // Never looked up, so unlinking is enough.
+        let c = table.link(ROOT_INO, name("c"), (1, 11), false);
+        table.unlink(c, ROOT_INO, name("c"));
+        assert!(!table.contains(c));
+        assert_eq!(table.find((1, 11)), None);
+        // Inode numbers are not reused.
+        assert!(table.link(ROOT_INO, name("c"), (1, 11), false) > c);
+    }
+
+    #[test]
+    fn the_root_is_never_evicted() {
+        let mut table = table();
+        table.forget(ROOT_INO, 1);
+        table.forget(ROOT_INO, u64::MAX);
+        assert!(table.contains(ROOT_INO));
+        assert_eq!(table.find((1, 2)), Some(ROOT_INO));
+        assert_eq!(table.path(ROOT_INO), Some(PathBuf::new()));
+    }
+}
@@ -0,0 +1,197 @@
+//! Write-ahead journal of paths about to change, for crashes between a change
+//! reaching the private store and its invalidation being persisted.
+//!
+//! Before a mutation is forwarded, the paths it touches are appended to
+//! `<store>/.mtfs/journal`. Once the hook has run the journaled entries are
+//! redundant, so the journal is emptied on a clean unmount, and whenever it
+//! grows too large, which is safe because FUSE requests are applied one at a
+//! time. On startup, whatever is left is replayed by invalidating every
+//! journaled node, or its deepest surviving ancestor, and everything above it.
+//! Replay does not stop short, as a crash may have interrupted an invalidation
+//! half way up the tree.
+//!
+//! Entries are store-relative paths terminated by a NUL byte, each written with
+//! a single `write`, so a killed process never leaves a torn entry behind.
+
+use crate::inode::ROOT_INO;
+use crate::store::Store;
+use crate::tree::Tree;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// Size past which the journal is emptied before the next append.
+const MAX_LEN: u64 = 1 << 20;
+
+pub struct Journal {
+    root: PathBuf,
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl Journal {
+    pub fn open(store: &Store) -> io::Result<Self> {
+        let path = store.meta_path("journal")?;
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(Journal {
+            root: store.root().to_owned(),
+            path,
+            len: file.metadata()?.len(),
+            file,
+        })
+    }
+
+    /// Paths journaled but possibly never invalidated, relative to the store.
+    pub fn pending(&self) -> io::Result<Vec<PathBuf>> {
+        let mut data = fs::read(&self.path)?;
+        // Only complete entries count; anything after the last NUL was never
+        // fully written, so its mutation was never forwarded either.
+        let complete = data.iter().rposition(|&b| b == 0).map_or(0, |i| i + 1);
+        data.truncate(complete);
+        Ok(data
+            .split(|&b| b == 0)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| PathBuf::from(OsString::from_vec(entry.to_vec())))
+            .collect())
+    }
+
+    /// Records that the store paths in `paths` are about to change.
+    pub fn append(&mut self, paths: &[&Path]) -> io::Result<()> {
+        if self.len > MAX_LEN {
+            self.clear()?;
+        }
+        let mut entry = Vec::new();
+        for path in paths {
+            let relative = path.strip_prefix(&self.root).unwrap_or(path);
+            entry.extend_from_slice(relative.as_os_str().as_bytes());
+            entry.push(0);
+        }
+        self.file.write_all(&entry)?;
+        self.len += entry.len() as u64;
+        Ok(())
+    }
+
+    /// Forgets every entry. Only call this once every journaled change has been
+    /// invalidated.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// Invalidates everything the journal says may have changed, loading the nodes
+/// on the way into `tree` so that their persisted valid bits are cleared too.
+/// Returns the number of valid bits cleared.
+pub fn replay(paths: &[PathBuf], store: &Store, tree: &Tree) -> io::Result<usize> {
+    let mut cleared = 0;
+    for path in paths {
+        let mut chain = vec![ROOT_INO];
+        let walked = store.walk(path, |parent, child| {
+            tree.ensure_attached(parent, child);
+            chain.push(child);
+        });
+        match walked {
+            Ok(_) => {}
+            Err(err)
+                if err.kind() == io::ErrorKind::NotFound
+                    || err.raw_os_error() == Some(libc::ENOTDIR) => {}
+            Err(err) => return Err(err),
+        }
+        for ino in chain.into_iter().rev() {
+            cleared += tree.invalidate(ino);
+        }
+    }
+    Ok(cleared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs::Attrs;
+    use crate::format::PLAIN;
+    use crate::hasher::SHA1;
+    use crate::metadata::Sidecar;
+    use crate::tree::{View, DEFAULT_VIEW};
+    use std::sync::Arc;
+
+    /// A tree over `store` whose hashes persist in a sidecar.
+    fn persisted_tree(store: &Arc<Store>) -> Tree {
+        let sidecar = Sidecar::open(
+            store.clone(),
+            DEFAULT_VIEW,
+            &SHA1,
+            &PLAIN,
+            None,
+            Attrs::NONE,
+        );
+        let view = View::new(
+            DEFAULT_VIEW.to_owned(),
+            &SHA1,
+            &PLAIN,
+            None,
+            Attrs::NONE,
+            Some(Box::new(sidecar.unwrap())),
+        );
+        Tree::new(vec![view])
+    }
+
+    #[test]
+    fn replay_invalidates_persisted_ancestors() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::write(dir.path().join("a/b/file"), "data").unwrap();
+        fs::write(dir.path().join("other"), "data").unwrap();
+        {
+            let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+            let tree = persisted_tree(&store);
+            tree.hash(&*store, 0, ROOT_INO).unwrap();
+            let mut journal = Journal::open(&store).unwrap();
+            journal.append(&[&dir.path().join("a/b/file")]).unwrap();
+            // Killed before the write reached the hook.
+        }
+
+        let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+        let tree = persisted_tree(&store);
+        assert!(tree.is_valid(0, ROOT_INO));
+        let pending = Journal::open(&store).unwrap().pending().unwrap();
+        assert_eq!(pending, [PathBuf::from("a/b/file")]);
+        assert_eq!(replay(&pending, &store, &tree).unwrap(), 4);
+        let other = store.walk(Path::new("other"), |_, _| {}).unwrap();
+        tree.ensure_attached(ROOT_INO, other);
+        assert!(tree.is_valid(0, other));
+        drop(tree);
+
+        // The invalidations reached the sidecar too.
+        let tree = persisted_tree(&store);
+        assert!(!tree.is_valid(0, ROOT_INO));
+    }
+
+    #[test]
+    fn pending_skips_torn_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().to_owned()).unwrap();
+        let mut journal = Journal::open(&store).unwrap();
+        journal.append(&[Path::new("a"), Path::new("b")]).unwrap();
+        journal.file.write_all(b"tor").unwrap();
+        assert_eq!(journal.pending().unwrap(), [Path::new("a"), Path::new("b")]);
+        journal.clear().unwrap();
+        assert!(journal.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replay_survives_removed_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+        let tree = persisted_tree(&store);
+        tree.hash(&*store, 0, ROOT_INO).unwrap();
+        // The crash happened after the removal: the deepest surviving ancestor is invalidated.
+        let cleared = replay(&[PathBuf::from("a/gone/file")], &store, &tree).unwrap();
+        assert_eq!(cleared, 2);
+        assert!(!tree.is_valid(0, ROOT_INO));
+    }
+}
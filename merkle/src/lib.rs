@@ -1,11 +1,191 @@
-use fuser::{Filesystem, MountOption};
+pub mod attrs;
+pub mod changes;
+pub mod control;
+pub mod diff;
+pub mod digest;
+pub mod format;
+pub mod fsck;
+pub mod fsmonitor;
+pub mod glob;
+pub mod hasher;
+pub mod ignore;
+pub mod inode;
+pub mod journal;
+pub mod metadata;
+pub mod options;
+pub mod passthrough;
+pub mod relay;
+pub mod server;
+pub mod store;
+pub mod sync;
+pub mod tree;
+pub mod watch;
+pub mod watchman;
+
+use changes::Changes;
+use control::Control;
+use ignore::Ignore;
+use journal::Journal;
+use options::Options;
+use passthrough::PassthroughFS;
+use server::Server;
 use std::env;
+use std::ffi::OsString;
+use std::process;
+use std::sync::Arc;
+use store::{view_meta, Store};
+use tree::Tree;
+use watchman::Watchman;
 
-struct NullFS;
+const USAGE: &str = "usage: mtfs <private-store> <mountpoint> [-o option[,option...]]
+       mtfs fsck [--repair] [-o metadata=backend[,view=name,...]] <private-store>
+       mtfs diff [-e rsh] [--view name] [[host]:]<path> [[host]:]<path>
+       mtfs serve <path>
+       mtfs sync [-n|--dry-run] [--delete] [-v] [--view name] <source> <destination>
+       mtfs fsmonitor-hook 2 <token>
+       mtfs watch [--debounce ms] [--view name] <path> -- <command> [args...]";
 
-impl Filesystem for NullFS {}
+pub(crate) fn usage_error(message: &str) -> ! {
+    eprintln!("mtfs: {}\n{}", message, USAGE);
+    process::exit(2);
+}
 
 pub fn main() {
-    let mountpoint = env::args_os().nth(1).unwrap();
-    fuser::mount2(NullFS, mountpoint, &[MountOption::AutoUnmount]).unwrap();
+    let mut options = Options::default();
+    let mut paths: Vec<OsString> = Vec::new();
+    let mut args = env::args_os().skip(1).peekable();
+    if args.next_if(|arg| arg == "fsck").is_some() {
+        fsck::main(args);
+    }
+    if args.next_if(|arg| arg == "diff").is_some() {
+        diff::main(args);
+    }
+    if args.next_if(|arg| arg == "serve").is_some() {
+        relay::main(args);
+    }
+    if args.next_if(|arg| arg == "sync").is_some() {
+        sync::main(args);
+    }
+    if args.next_if(|arg| arg == "fsmonitor-hook").is_some() {
+        fsmonitor::main(args);
+    }
+    if args.next_if(|arg| arg == "watch").is_some() {
+        watch::main(args);
+    }
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            let list = args
+                .next()
+                .unwrap_or_else(|| usage_error("-o requires an argument"));
+            let list = list
+                .to_str()
+                .unwrap_or_else(|| usage_error("mount options must be UTF-8"));
+            options.parse(list).unwrap_or_else(|err| usage_error(&err));
+        } else {
+            paths.push(arg);
+        }
+    }
+    let [store, mountpoint] = <[OsString; 2]>::try_from(paths)
+        .unwrap_or_else(|_| usage_error("expected a private store and a mountpoint"));
+
+    let store = Arc::new(Store::open(store.into()).unwrap());
+    let mut views = Vec::with_capacity(options.views.len());
+    // Without persisted hashes, there is nothing a crash could leave stale.
+    let mut journaled = false;
+    for view in &options.views {
+        let ignore = ignore::open(view.ignore, &store, view).unwrap_or_else(|err| {
+            eprintln!(
+                "mtfs: cannot read ignore rules of view {}: {}",
+                view.name, err
+            );
+            process::exit(1)
+        });
+        let ignore_setting = ignore.as_ref().map(Ignore::setting);
+        let attrs_setting = view.attrs.setting();
+        for (setting, value) in [
+            ("algorithm", view.hasher.name()),
+            ("format", view.format.name()),
+            ("ignore", ignore_setting.as_deref().unwrap_or("none")),
+            ("attrs", attrs_setting.as_deref().unwrap_or("none")),
+        ] {
+            let setting = view_meta(&view.name, setting);
+            if let Some(previous) = store.record(&setting, value).unwrap() {
+                eprintln!(
+                    "mtfs: {} changed from {} to {}, rehashing everything",
+                    setting, previous, value
+                );
+            }
+        }
+        let persisted = metadata::open(
+            view.metadata,
+            &view.name,
+            &store,
+            view.hasher,
+            view.format,
+            ignore_setting.as_deref(),
+            view.attrs,
+            true,
+        )
+        .unwrap_or_else(|err| {
+            eprintln!(
+                "mtfs: cannot open {} metadata of view {} ({}), its hashes will not survive a remount",
+                view.metadata, view.name, err
+            );
+            None
+        });
+        journaled |= persisted.is_some();
+        views.push(tree::View::new(
+            view.name.clone(),
+            view.hasher,
+            view.format,
+            ignore,
+            view.attrs,
+            persisted,
+        ));
+    }
+    let tree = Arc::new(Tree::new(views));
+    let journal = journaled.then(|| {
+        let mut journal = Journal::open(&store).unwrap();
+        let pending = journal.pending().unwrap();
+        if !pending.is_empty() {
+            let cleared = journal::replay(&pending, &store, &tree).unwrap();
+            eprintln!(
+                "mtfs: replayed {} journal entries after an unclean shutdown, {} hashes invalidated",
+                pending.len(),
+                cleared
+            );
+        }
+        journal.clear().unwrap();
+        journal
+    });
+    let changes = Arc::new(Changes::open(&store).unwrap());
+    let socket = match options.socket {
+        Some(socket) => socket,
+        None => store.meta_path("socket").unwrap(),
+    };
+    // The control directory links to it from wherever the mount is.
+    let socket = std::path::absolute(socket).unwrap();
+    Server::new(store.clone(), tree.clone(), Some(changes.clone()))
+        .spawn(&socket)
+        .unwrap();
+    let watchman = options.watchman.map(|watchman| {
+        let watchman = std::path::absolute(watchman).unwrap();
+        // Clients name files by their paths in the mount.
+        let mountpoint = std::fs::canonicalize(&mountpoint).unwrap();
+        Watchman::new(store.clone(), changes.clone(), mountpoint)
+            .spawn(&watchman)
+            .unwrap();
+        watchman
+    });
+    let control = Control::new(
+        store.clone(),
+        tree.clone(),
+        socket.clone(),
+        options.hash_files,
+    );
+    let fs = PassthroughFS::new(store, tree, journal, Some(changes), Some(control));
+    fuser::mount2(fs, mountpoint, &options.mount).unwrap();
+    for socket in [Some(socket), watchman].into_iter().flatten() {
+        let _ = std::fs::remove_file(socket);
+    }
 }
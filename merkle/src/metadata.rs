@@ -0,0 +1,154 @@
+//! Persistent copies of cached hashes and valid bits, so that the cache survives
+//! a remount instead of forcing the whole tree to be rehashed.
+//!
+//! The tree writes through to a [`MetadataStore`] whenever it validates or
+//! invalidates a node, in the order it did, and loads from it when it first
+//! sees a node. Backends must never let a crash leave a valid bit on a stale
+//! hash: an interrupted update may lose a validation, but must not lose an
+//! invalidation or expose a valid bit before its hash. They also only trust
+//! records made with the current hash algorithm, format, ignore setting and
+//! hashed attributes.
+//!
+//! Every view keeps records of its own, under names made by
+//! [`view_meta`](crate::store::view_meta).
+
+mod sidecar;
+mod xattr;
+
+pub use sidecar::Sidecar;
+pub use xattr::Xattrs;
+
+use crate::attrs::Attrs;
+use crate::digest::Digest;
+use crate::format::Format;
+use crate::hasher::MerkleHasher;
+use crate::store::Store;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// What a backend remembers about a node.
+pub struct Record {
+    pub hash: Digest,
+    pub valid: bool,
+    pub generation: u64,
+}
+
+/// A change the tree made to the cached hash of a node, to be persisted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Update {
+    /// `hash` became the valid hash of `ino` at `generation`.
+    Validated {
+        ino: u64,
+        hash: Digest,
+        generation: u64,
+    },
+    /// `ino` became invalid, and its generation is now `generation`.
+    Invalidated { ino: u64, generation: u64 },
+}
+
+impl Update {
+    pub fn ino(&self) -> u64 {
+        match *self {
+            Update::Validated { ino, .. } | Update::Invalidated { ino, .. } => ino,
+        }
+    }
+}
+
+pub trait MetadataStore: Send + Sync {
+    /// Name accepted by the `metadata=` mount option.
+    fn name(&self) -> &'static str;
+
+    /// The persisted record of `ino`, if there is one for this configuration.
+    fn load(&self, ino: u64) -> io::Result<Option<Record>>;
+
+    /// Persists `hash` as the valid hash of `ino` at `generation`.
+    fn validated(&self, ino: u64, hash: Digest, generation: u64) -> io::Result<()>;
+
+    /// Clears the persisted valid bit of `ino`, whose generation is now `generation`.
+    fn invalidated(&self, ino: u64, generation: u64) -> io::Result<()>;
+
+    /// Persists `updates` in order: the validations of one hash, or the
+    /// invalidations of one change. Backends that can write them together
+    /// should. Every update is tried, and the first error returned.
+    fn persist(&self, updates: &[Update]) -> io::Result<()> {
+        let mut result = Ok(());
+        for update in updates {
+            let written = match *update {
+                Update::Validated {
+                    ino,
+                    hash,
+                    generation,
+                } => self.validated(ino, hash, generation),
+                Update::Invalidated { ino, generation } => self.invalidated(ino, generation),
+            };
+            result = result.and(written);
+        }
+        result
+    }
+}
+
+impl fmt::Debug for dyn MetadataStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub static BACKENDS: [&str; 3] = ["xattr", "sidecar", "none"];
+
+/// Opens the backend called `name` for the view `view` of `store`, or returns
+/// `None` for `none`. `ignore` is the
+/// [`Ignore::setting`](crate::ignore::Ignore::setting) in use, if any, and
+/// `attrs` the attributes hashed along with contents. Unless `writable`, the
+/// store is only read while opening.
+#[allow(clippy::too_many_arguments)]
+pub fn open(
+    name: &str,
+    view: &str,
+    store: &Arc<Store>,
+    hasher: &'static dyn MerkleHasher,
+    format: &'static dyn Format,
+    ignore: Option<&str>,
+    attrs: Attrs,
+    writable: bool,
+) -> io::Result<Option<Box<dyn MetadataStore>>> {
+    Ok(match name {
+        "xattr" => Some(Box::new(Xattrs::open(
+            store.clone(),
+            view,
+            hasher,
+            format,
+            ignore,
+            attrs,
+            writable,
+        )?)),
+        "sidecar" => Some(Box::new(Sidecar::open(
+            store.clone(),
+            view,
+            hasher,
+            format,
+            ignore,
+            attrs,
+        )?)),
+        _ => None,
+    })
+}
+
+/// The hash algorithm, format, ignore setting and hashed attributes a record
+/// is valid for. Settings left at their defaults are left out, so records made
+/// before they existed stay valid.
+fn stamp(
+    hasher: &dyn MerkleHasher,
+    format: &dyn Format,
+    ignore: Option<&str>,
+    attrs: Attrs,
+) -> String {
+    let mut stamp = format!("{}/{}", hasher.name(), format.name());
+    if let Some(ignore) = ignore {
+        stamp = stamp + "/" + ignore;
+    }
+    if let Some(attrs) = attrs.setting() {
+        stamp = stamp + "/attrs=" + &attrs;
+    }
+    stamp
+}
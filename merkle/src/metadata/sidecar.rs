@@ -0,0 +1,254 @@
+//! Records kept in a redb database in the private store's metadata directory,
+//! for backing filesystems without usable extended attributes.
+//!
+//! Records are keyed by the backing file's device and inode number. A record
+//! left behind by a deleted file is invalidated once a new file created through
+//! the mount reuses its inode number. The updates the tree persists together,
+//! the validations of a hash or the invalidations of a change, are written in
+//! one transaction. One with invalidations is committed durably before
+//! returning; one with only validations is not, as losing them to a crash only
+//! costs a rehash, but they become durable with any later commit.
+
+use super::{stamp, MetadataStore, Record, Update};
+use crate::attrs::Attrs;
+use crate::digest::Digest;
+use crate::format::Format;
+use crate::hasher::MerkleHasher;
+use crate::inode::Backing;
+use crate::store::{view_meta, Store};
+use redb::{Database, Durability, ReadableTable, TableDefinition, WriteTransaction};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Backing `(st_dev, st_ino)` to `(hash, valid, generation)`.
+const NODES: TableDefinition<(u64, u64), (&[u8], bool, u64)> = TableDefinition::new("nodes");
+const CONFIG: TableDefinition<&str, &str> = TableDefinition::new("config");
+
+/// Every this many validations, a commit is made durably to let redb reclaim pages.
+const DURABLE_EVERY: u64 = 1024;
+
+pub struct Sidecar {
+    store: Arc<Store>,
+    db: Database,
+    validations: AtomicU64,
+}
+
+impl Sidecar {
+    /// Opens or creates `metadata.redb`, or `<view>.metadata.redb` for a view
+    /// other than the default, dropping every record if it was made with a
+    /// different hash algorithm, format, ignore setting or set of
+    /// hashed attributes.
+    pub fn open(
+        store: Arc<Store>,
+        view: &str,
+        hasher: &'static dyn MerkleHasher,
+        format: &'static dyn Format,
+        ignore: Option<&str>,
+        attrs: Attrs,
+    ) -> io::Result<Self> {
+        let db = Database::create(store.meta_path(&view_meta(view, "metadata.redb"))?)
+            .map_err(db_error)?;
+        let stamp = stamp(hasher, format, ignore, attrs);
+        let txn = db.begin_write().map_err(db_error)?;
+        {
+            let mut config = txn.open_table(CONFIG).map_err(db_error)?;
+            let current = config
+                .get("stamp")
+                .map_err(db_error)?
+                .map(|v| v.value() == stamp);
+            if current != Some(true) {
+                txn.delete_table(NODES).map_err(db_error)?;
+                config.insert("stamp", stamp.as_str()).map_err(db_error)?;
+            }
+        }
+        txn.open_table(NODES).map_err(db_error)?;
+        txn.commit().map_err(db_error)?;
+        Ok(Sidecar {
+            store,
+            db,
+            validations: AtomicU64::new(0),
+        })
+    }
+
+    fn key(&self, ino: u64) -> io::Result<Backing> {
+        self.store
+            .inodes()
+            .backing(ino)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn write(&self, durability: Durability) -> io::Result<WriteTransaction> {
+        let mut txn = self.db.begin_write().map_err(db_error)?;
+        txn.set_durability(durability);
+        Ok(txn)
+    }
+}
+
+impl MetadataStore for Sidecar {
+    fn name(&self) -> &'static str {
+        "sidecar"
+    }
+
+    fn load(&self, ino: u64) -> io::Result<Option<Record>> {
+        let key = self.key(ino)?;
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let nodes = txn.open_table(NODES).map_err(db_error)?;
+        Ok(nodes.get(key).map_err(db_error)?.map(|record| {
+            let (hash, valid, generation) = record.value();
+            Record {
+                hash: Digest::new(hash),
+                valid,
+                generation,
+            }
+        }))
+    }
+
+    fn validated(&self, ino: u64, hash: Digest, generation: u64) -> io::Result<()> {
+        self.persist(&[Update::Validated {
+            ino,
+            hash,
+            generation,
+        }])
+    }
+
+    fn invalidated(&self, ino: u64, generation: u64) -> io::Result<()> {
+        self.persist(&[Update::Invalidated { ino, generation }])
+    }
+
+    fn persist(&self, updates: &[Update]) -> io::Result<()> {
+        let validations = updates
+            .iter()
+            .filter(|update| matches!(update, Update::Validated { .. }))
+            .count() as u64;
+        let before = self.validations.fetch_add(validations, Ordering::Relaxed);
+        let durable = validations < updates.len() as u64
+            || (before + validations) / DURABLE_EVERY != before / DURABLE_EVERY;
+        let txn = self.write(match durable {
+            true => Durability::Immediate,
+            false => Durability::None,
+        })?;
+        // A node that is gone has no record to keep, but the others do.
+        let mut result = Ok(());
+        {
+            let mut nodes = txn.open_table(NODES).map_err(db_error)?;
+            for update in updates {
+                let key = match self.key(update.ino()) {
+                    Ok(key) => key,
+                    Err(err) => {
+                        result = result.and(Err(err));
+                        continue;
+                    }
+                };
+                match *update {
+                    Update::Validated {
+                        hash, generation, ..
+                    } => {
+                        nodes
+                            .insert(key, (hash.as_bytes(), true, generation))
+                            .map_err(db_error)?;
+                    }
+                    Update::Invalidated { generation, .. } => {
+                        let hash = nodes
+                            .get(key)
+                            .map_err(db_error)?
+                            .map(|record| record.value().0.to_vec());
+                        if let Some(hash) = hash {
+                            nodes
+                                .insert(key, (hash.as_slice(), false, generation))
+                                .map_err(db_error)?;
+                        }
+                    }
+                }
+            }
+        }
+        txn.commit().map_err(db_error)?;
+        result
+    }
+}
+
+impl Drop for Sidecar {
+    /// Makes any outstanding validations durable.
+    fn drop(&mut self) {
+        if let Err(err) = self
+            .write(Durability::Immediate)
+            .and_then(|txn| txn.commit().map_err(db_error))
+        {
+            eprintln!("mtfs: cannot flush metadata database: {}", err);
+        }
+    }
+}
+
+fn db_error(err: impl Into<redb::Error>) -> io::Error {
+    io::Error::other(err.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::PLAIN;
+    use crate::hasher::{SHA1, SHA256};
+    use crate::tree::DEFAULT_VIEW;
+    use std::fs;
+    use std::path::Path;
+
+    fn open(store: &Arc<Store>, hasher: &'static dyn MerkleHasher) -> Sidecar {
+        Sidecar::open(
+            store.clone(),
+            DEFAULT_VIEW,
+            hasher,
+            &PLAIN,
+            None,
+            Attrs::NONE,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn batches_survive_reopening_with_the_same_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "a").unwrap();
+        fs::write(dir.path().join("b"), "b").unwrap();
+        let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+        let a = store.walk(Path::new("a"), |_, _| {}).unwrap();
+        let b = store.walk(Path::new("b"), |_, _| {}).unwrap();
+        let hash = SHA1.digest(b"a");
+
+        let sidecar = open(&store, &SHA1);
+        sidecar
+            .persist(&[
+                Update::Validated {
+                    ino: a,
+                    hash,
+                    generation: 1,
+                },
+                Update::Validated {
+                    ino: b,
+                    hash,
+                    generation: 1,
+                },
+                Update::Invalidated {
+                    ino: a,
+                    generation: 2,
+                },
+            ])
+            .unwrap();
+        drop(sidecar);
+
+        let sidecar = open(&store, &SHA1);
+        let record = sidecar.load(a).unwrap().unwrap();
+        assert_eq!(
+            (record.hash, record.valid, record.generation),
+            (hash, false, 2)
+        );
+        let record = sidecar.load(b).unwrap().unwrap();
+        assert_eq!(
+            (record.hash, record.valid, record.generation),
+            (hash, true, 1)
+        );
+        drop(sidecar);
+
+        // Records made with another algorithm are dropped.
+        assert!(open(&store, &SHA256).load(b).unwrap().is_none());
+    }
+}
@@ -0,0 +1,278 @@
+//! Records kept in extended attributes of the backing files themselves.
+//!
+//! `mtfs.hash` holds the last hash computed for a node and `mtfs.valid` marks it
+//! valid, or `mtfs.<view>.hash` and `mtfs.<view>.valid` for a view other than
+//! the default. Attributes go in the `trusted` namespace when running as root,
+//! `user` otherwise. Validating writes the hash before setting the valid bit, and
+//! invalidating only removes the valid bit, so an interrupted update leaves the
+//! node invalid at worst. The valid bit holds the settings it was computed
+//! with, as made by [`stamp`]. Generations are not kept.
+
+use super::{stamp, MetadataStore, Record};
+use crate::attrs::Attrs;
+use crate::digest::Digest;
+use crate::format::Format;
+use crate::hasher::MerkleHasher;
+use crate::passthrough::{check, cstr};
+use crate::store::{view_meta, Store};
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+pub struct Xattrs {
+    store: Arc<Store>,
+    hash: CString,
+    valid: CString,
+    /// Value of the valid attribute: the configuration the hash is valid for.
+    stamp: Vec<u8>,
+    len: usize,
+}
+
+impl Xattrs {
+    /// Fails if the private store does not accept our attributes, or, unless
+    /// `writable`, does not support extended attributes at all.
+    pub fn open(
+        store: Arc<Store>,
+        view: &str,
+        hasher: &'static dyn MerkleHasher,
+        format: &'static dyn Format,
+        ignore: Option<&str>,
+        attrs: Attrs,
+        writable: bool,
+    ) -> io::Result<Self> {
+        let namespace = if unsafe { libc::geteuid() } == 0 {
+            "trusted"
+        } else {
+            "user"
+        };
+        let name = |attr: &str| {
+            CString::new(format!("{}.mtfs.{}", namespace, view_meta(view, attr))).unwrap()
+        };
+        let xattrs = Xattrs {
+            store,
+            hash: name("hash"),
+            valid: name("valid"),
+            stamp: stamp(hasher, format, ignore, attrs).into_bytes(),
+            len: hasher.digest(b"").as_bytes().len(),
+        };
+        xattrs.probe(writable)?;
+        Ok(xattrs)
+    }
+
+    /// Sets and removes an attribute on the metadata directory, or only reads
+    /// it unless `writable`.
+    fn probe(&self, writable: bool) -> io::Result<()> {
+        let path = cstr(&self.store.meta_path("")?)?;
+        if !writable {
+            return get(&path, &self.valid).map(drop);
+        }
+        check(unsafe {
+            libc::lsetxattr(
+                path.as_ptr(),
+                self.valid.as_ptr(),
+                self.stamp.as_ptr().cast(),
+                self.stamp.len(),
+                0,
+            )
+        })?;
+        check(unsafe { libc::lremovexattr(path.as_ptr(), self.valid.as_ptr()) })
+    }
+}
+
+impl MetadataStore for Xattrs {
+    fn name(&self) -> &'static str {
+        "xattr"
+    }
+
+    /// Only valid records are returned, as stale hashes are of no use without a generation.
+    fn load(&self, ino: u64) -> io::Result<Option<Record>> {
+        let path = cstr(&self.store.path(ino)?)?;
+        let Some(stamp) = get(&path, &self.valid)? else {
+            return Ok(None);
+        };
+        if stamp != self.stamp {
+            return Ok(None);
+        }
+        Ok(get(&path, &self.hash)?
+            .filter(|hash| hash.len() == self.len)
+            .map(|hash| Record {
+                hash: Digest::new(&hash),
+                valid: true,
+                generation: 0,
+            }))
+    }
+
+    fn validated(&self, ino: u64, hash: Digest, _generation: u64) -> io::Result<()> {
+        let path = cstr(&self.store.path(ino)?)?;
+        match set(&path, &self.hash, hash.as_bytes()) {
+            Err(err) if ignorable(&err) => return Ok(()),
+            result => result?,
+        }
+        set(&path, &self.valid, &self.stamp)
+    }
+
+    fn invalidated(&self, ino: u64, _generation: u64) -> io::Result<()> {
+        let path = cstr(&self.store.path(ino)?)?;
+        match check(unsafe { libc::lremovexattr(path.as_ptr(), self.valid.as_ptr()) }) {
+            Err(err) if ignorable(&err) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl fmt::Debug for Xattrs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Xattrs")
+            .field("root", &self.store.root())
+            .field("hash", &self.hash)
+            .field("valid", &self.valid)
+            .finish()
+    }
+}
+
+/// Errors meaning the attribute is not there and cannot be: `ENODATA` for a
+/// missing attribute, `EPERM` for `user` attributes on symlinks and special files.
+fn ignorable(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENODATA | libc::EPERM))
+}
+
+fn get(path: &CString, name: &CString) -> io::Result<Option<Vec<u8>>> {
+    let mut value = vec![0; 128];
+    let len = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        return if ignorable(&err) || err.raw_os_error() == Some(libc::ERANGE) {
+            // Anything longer than our own values was not written by us.
+            Ok(None)
+        } else {
+            Err(err)
+        };
+    }
+    value.truncate(len as usize);
+    Ok(Some(value))
+}
+
+fn set(path: &CString, name: &CString, value: &[u8]) -> io::Result<()> {
+    check(unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs::FIELDS;
+    use crate::format::{GIT, PLAIN};
+    use crate::hasher::{BLAKE3, SHA1, SHA256};
+    use crate::inode::ROOT_INO;
+    use crate::store::backing;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::Path;
+
+    /// A store holding a file `f`, and its inode.
+    fn store(dir: &Path) -> (Arc<Store>, u64) {
+        fs::write(dir.join("f"), "f").unwrap();
+        let store = Arc::new(Store::open(dir.to_owned()).unwrap());
+        let meta = fs::symlink_metadata(dir.join("f")).unwrap();
+        let f = store
+            .inodes()
+            .lookup(ROOT_INO, OsStr::new("f"), backing(&meta), false);
+        (store, f)
+    }
+
+    /// Records for the default view, or `None` if the filesystem holding the
+    /// temporary directory has no extended attributes.
+    fn open(
+        store: &Arc<Store>,
+        hasher: &'static dyn MerkleHasher,
+        format: &'static dyn Format,
+        ignore: Option<&str>,
+        attrs: Attrs,
+    ) -> Option<Xattrs> {
+        let view = crate::tree::DEFAULT_VIEW;
+        match Xattrs::open(store.clone(), view, hasher, format, ignore, attrs, true) {
+            Err(err) if err.raw_os_error() == Some(libc::ENOTSUP) => None,
+            result => Some(result.unwrap()),
+        }
+    }
+
+    #[test]
+    fn records_are_saved_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, f) = store(dir.path());
+        let Some(xattrs) = open(&store, &SHA1, &PLAIN, None, Attrs::NONE) else {
+            return;
+        };
+        assert!(xattrs.load(f).unwrap().is_none());
+        let hash = SHA1.digest(b"f");
+        xattrs.validated(f, hash, 3).unwrap();
+        let record = xattrs.load(f).unwrap().unwrap();
+        assert_eq!((record.hash, record.valid), (hash, true));
+        xattrs.invalidated(f, 4).unwrap();
+        assert!(xattrs.load(f).unwrap().is_none());
+        // Invalidating twice finds nothing to remove.
+        xattrs.invalidated(f, 5).unwrap();
+    }
+
+    #[test]
+    fn records_made_with_other_settings_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, f) = store(dir.path());
+        let Some(xattrs) = open(&store, &SHA1, &PLAIN, None, Attrs::NONE) else {
+            return;
+        };
+        xattrs.validated(f, SHA1.digest(b"f"), 1).unwrap();
+
+        let others = [
+            open(&store, &SHA256, &PLAIN, None, Attrs::NONE),
+            open(&store, &BLAKE3, &PLAIN, None, Attrs::NONE),
+            open(&store, &SHA1, &GIT, None, Attrs::NONE),
+            open(&store, &SHA1, &PLAIN, Some("git"), Attrs::NONE),
+            open(&store, &SHA1, &PLAIN, None, crate::attrs::PERM),
+        ];
+        for other in others {
+            assert!(other.unwrap().load(f).unwrap().is_none());
+        }
+        let same = open(&store, &SHA1, &PLAIN, None, Attrs::NONE).unwrap();
+        assert!(same.load(f).unwrap().is_some());
+    }
+
+    #[test]
+    fn the_longest_stamp_fits() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, f) = store(dir.path());
+        let all = FIELDS
+            .iter()
+            .fold(Attrs::NONE, |all, (_, field)| all | *field);
+        let ignore = Some("rsync+0123456789ab");
+        let Some(xattrs) = open(&store, &BLAKE3, &PLAIN, ignore, all) else {
+            return;
+        };
+        assert_eq!(
+            String::from_utf8_lossy(&xattrs.stamp),
+            "blake3/plain/rsync+0123456789ab/attrs=type+perm+owner+mtime+xattrs"
+        );
+        xattrs.validated(f, BLAKE3.digest(b"f"), 1).unwrap();
+        assert!(xattrs.load(f).unwrap().is_some());
+
+        // Anything longer than the buffer reads as missing.
+        let path = cstr(&store.path(f).unwrap()).unwrap();
+        set(&path, &xattrs.valid, &[b'x'; 129]).unwrap();
+        assert!(xattrs.load(f).unwrap().is_none());
+    }
+}
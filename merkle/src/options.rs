@@ -0,0 +1,190 @@
+//! Parsing of `-o` mount options.
+
+use crate::attrs::Attrs;
+use crate::format::{self, Format};
+use crate::hasher::{self, MerkleHasher};
+use crate::ignore;
+use crate::metadata;
+use crate::tree::DEFAULT_VIEW;
+use fuser::MountOption;
+use std::path::PathBuf;
+
+pub struct Options {
+    /// The views hashes are kept for, the default one first.
+    pub views: Vec<ViewOptions>,
+    /// Where to listen for queries, instead of `socket` in the metadata directory.
+    pub socket: Option<PathBuf>,
+    /// Where to speak Watchman's protocol, if anywhere.
+    pub watchman: Option<PathBuf>,
+    /// Whether `name@@mtfs-hash` files can be looked up in the mount.
+    pub hash_files: bool,
+    /// Options handed to the kernel, including any this module does not recognize.
+    pub mount: Vec<MountOption>,
+}
+
+/// How one view of the tree is hashed.
+pub struct ViewOptions {
+    pub name: String,
+    pub hasher: &'static dyn MerkleHasher,
+    pub format: &'static dyn Format,
+    /// Name of the [`metadata`] backend that persists hashes.
+    pub metadata: &'static str,
+    /// Which entries are left out of hashes, one of [`ignore::MODES`].
+    pub ignore: &'static str,
+    /// Global excludes file for `ignore=git`, instead of git's default.
+    pub excludes_file: Option<PathBuf>,
+    /// File of filter rules for `ignore=rsync`, instead of `.rsync-filter` files.
+    pub filter: Option<PathBuf>,
+    /// Attributes hashed along with contents, as chosen by `attrs=`.
+    pub attrs: Attrs,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            views: vec![ViewOptions::new(DEFAULT_VIEW.to_owned())],
+            socket: None,
+            watchman: None,
+            hash_files: false,
+            mount: vec![
+                MountOption::AutoUnmount,
+                MountOption::FSName("mtfs".to_owned()),
+            ],
+        }
+    }
+}
+
+impl ViewOptions {
+    /// A view called `name` with the default settings.
+    pub fn new(name: String) -> Self {
+        ViewOptions {
+            name,
+            hasher: &hasher::SHA1,
+            format: &format::PLAIN,
+            metadata: "xattr",
+            ignore: "none",
+            excludes_file: None,
+            filter: None,
+            attrs: Attrs::NONE,
+        }
+    }
+}
+
+impl Options {
+    /// The default view.
+    pub fn view(&self) -> &ViewOptions {
+        &self.views[0]
+    }
+
+    /// Applies a comma-separated list such as `hash=blake3,allow_other`.
+    /// `view=name` starts another view, which the hashing options after it
+    /// apply to, starting from the defaults.
+    pub fn parse(&mut self, list: &str) -> Result<(), String> {
+        for option in list.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            };
+            let view = self
+                .views
+                .last_mut()
+                .expect("there is always a default view");
+            match (key, value) {
+                ("view", Some(name)) => {
+                    let valid = name
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+                    if name.is_empty() || !valid {
+                        return Err(format!(
+                            "invalid view name {:?} (expected letters, digits, - and _)",
+                            name
+                        ));
+                    }
+                    if self.views.iter().any(|view| view.name == name) {
+                        return Err(format!("view {} is defined twice", name));
+                    }
+                    self.views.push(ViewOptions::new(name.to_owned()));
+                }
+                ("hash", Some(name)) => {
+                    view.hasher = hasher::by_name(name).ok_or_else(|| {
+                        let known: Vec<_> = hasher::ALGORITHMS.iter().map(|h| h.name()).collect();
+                        format!(
+                            "unknown hash algorithm {:?} (expected one of {})",
+                            name,
+                            known.join(", ")
+                        )
+                    })?;
+                }
+                ("format", Some(name)) => {
+                    view.format = format::by_name(name).ok_or_else(|| {
+                        let known: Vec<_> = format::FORMATS.iter().map(|f| f.name()).collect();
+                        format!(
+                            "unknown format {:?} (expected one of {})",
+                            name,
+                            known.join(", ")
+                        )
+                    })?;
+                }
+                ("metadata", Some(name)) => {
+                    view.metadata = metadata::BACKENDS
+                        .iter()
+                        .copied()
+                        .find(|backend| *backend == name)
+                        .ok_or_else(|| {
+                            format!(
+                                "unknown metadata backend {:?} (expected one of {})",
+                                name,
+                                metadata::BACKENDS.join(", ")
+                            )
+                        })?;
+                }
+                ("ignore", Some(name)) => {
+                    view.ignore = ignore::MODES
+                        .iter()
+                        .copied()
+                        .find(|mode| *mode == name)
+                        .ok_or_else(|| {
+                            format!(
+                                "unknown ignore mode {:?} (expected one of {})",
+                                name,
+                                ignore::MODES.join(", ")
+                            )
+                        })?;
+                }
+                ("attrs", Some(list)) => view.attrs = Attrs::parse(list)?,
+                ("excludesfile", Some(path)) => view.excludes_file = Some(PathBuf::from(path)),
+                ("filter", Some(path)) => view.filter = Some(PathBuf::from(path)),
+                ("socket", Some(path)) => self.socket = Some(PathBuf::from(path)),
+                ("watchman", Some(path)) => self.watchman = Some(PathBuf::from(path)),
+                (
+                    "view" | "hash" | "format" | "metadata" | "ignore" | "attrs" | "excludesfile"
+                    | "filter" | "socket" | "watchman",
+                    None,
+                ) => return Err(format!("option {} requires a value", key)),
+                ("hash_files", None) => self.hash_files = true,
+                ("allow_other", None) => self.mount.push(MountOption::AllowOther),
+                ("allow_root", None) => self.mount.push(MountOption::AllowRoot),
+                ("default_permissions", None) => self.mount.push(MountOption::DefaultPermissions),
+                ("ro", None) => self.mount.push(MountOption::RO),
+                _ => self.mount.push(MountOption::CUSTOM(option.to_owned())),
+            }
+        }
+        for view in &self.views {
+            if !view.format.supports(view.hasher) {
+                return Err(format!(
+                    "format {} cannot be used with hash algorithm {} in view {}",
+                    view.format.name(),
+                    view.hasher.name(),
+                    view.name
+                ));
+            }
+            if !view.attrs.is_empty() && view.format.name() == "git" {
+                return Err(format!(
+                    "format git cannot hash attributes in view {}, as git object ids would not match",
+                    view.name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
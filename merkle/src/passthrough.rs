@@ -0,0 +1,1306 @@
+//! A FUSE filesystem that forwards every request to a backing "private store" directory.
+
+use crate::attrs::{self, Attrs};
+use crate::changes::Changes;
+use crate::control::{self, Control};
+use crate::inode::ROOT_INO;
+use crate::journal::Journal;
+use crate::store::{backing, is_reserved, Store, META_DIR};
+use fuser::{
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
+};
+use libc::c_int;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the kernel may cache attributes and entries.
+/// All changes to the store are expected to go through the mount.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Receives every change that reaches the private store through the mount,
+/// after it has been applied, so that cached hashes covering it can be invalidated.
+pub trait Hook {
+    /// The contents of `ino` changed.
+    fn modified(&self, _ino: u64) {}
+
+    /// Only the attributes `fields` of `ino` changed.
+    fn attributes_changed(&self, _ino: u64, _fields: Attrs) {}
+
+    /// `ino` became reachable as a child of `parent`.
+    fn linked(&self, _parent: u64, _ino: u64) {}
+
+    /// `ino` is no longer reachable as a child of `parent`.
+    fn unlinked(&self, _parent: u64, _ino: u64) {}
+
+    /// `ino` was found as a child of `parent` by a lookup. Nothing changed, but
+    /// the kernel can now modify `ino`, so it is worth knowing where it lives.
+    fn looked_up(&self, _parent: u64, _ino: u64) {}
+}
+
+impl Hook for () {}
+
+impl<H: Hook> Hook for Arc<H> {
+    fn modified(&self, ino: u64) {
+        (**self).modified(ino)
+    }
+
+    fn attributes_changed(&self, ino: u64, fields: Attrs) {
+        (**self).attributes_changed(ino, fields)
+    }
+
+    fn linked(&self, parent: u64, ino: u64) {
+        (**self).linked(parent, ino)
+    }
+
+    fn unlinked(&self, parent: u64, ino: u64) {
+        (**self).unlinked(parent, ino)
+    }
+
+    fn looked_up(&self, parent: u64, ino: u64) {
+        (**self).looked_up(parent, ino)
+    }
+}
+
+pub struct PassthroughFS<H: Hook = ()> {
+    store: Arc<Store>,
+    files: HashMap<u64, File>,
+    next_fh: u64,
+    /// Contents of open files in the control directory, by file handle.
+    contents: HashMap<u64, Vec<u8>>,
+    hook: H,
+    journal: Option<Journal>,
+    changes: Option<Arc<Changes>>,
+    control: Option<Control>,
+}
+
+impl<H: Hook> PassthroughFS<H> {
+    /// With a `journal`, every mutation is journaled before it is forwarded.
+    /// With `changes`, every mutation is recorded in the change log once applied.
+    /// With a `control`, the root has a `.mtfs` control directory.
+    pub fn new(
+        store: Arc<Store>,
+        hook: H,
+        journal: Option<Journal>,
+        changes: Option<Arc<Changes>>,
+        control: Option<Control>,
+    ) -> Self {
+        PassthroughFS {
+            store,
+            files: HashMap::new(),
+            next_fh: 1,
+            contents: HashMap::new(),
+            hook,
+            journal,
+            changes,
+            control,
+        }
+    }
+
+    /// The control directory, if `ino` is in it.
+    fn control(&self, ino: u64) -> Option<&Control> {
+        self.control.as_ref().filter(|_| control::is_virtual(ino))
+    }
+
+    /// Refuses to change anything in the control directory.
+    fn writable(&self, ino: u64) -> io::Result<()> {
+        if control::is_virtual(ino) {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    /// Refuses to change `name` in `parent` if it is the control directory or in it.
+    fn writable_child(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        self.writable(parent)?;
+        if self.control.is_some() && is_reserved(parent, name) {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    /// Journals the store paths a mutation is about to change, if there is a journal.
+    fn journal(&mut self, paths: &[&Path]) -> io::Result<()> {
+        match &mut self.journal {
+            Some(journal) => journal.append(paths),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the store paths a mutation changed, if there is a change log.
+    fn changed(&self, paths: &[&Path], subtree: bool) {
+        if let Some(changes) = &self.changes {
+            changes.record(paths, subtree, None);
+        }
+    }
+
+    /// Records that `ino` changed, or only its attributes `attrs` if given,
+    /// under every name it is known by.
+    fn changed_links(&self, ino: u64, attrs: Option<Attrs>) {
+        if let Some(changes) = &self.changes {
+            let paths = self.store.inodes().paths(ino);
+            let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+            changes.record(&paths, false, attrs);
+        }
+    }
+
+    fn path(&self, ino: u64) -> io::Result<PathBuf> {
+        self.store.path(ino)
+    }
+
+    fn child_path(&self, parent: u64, name: &OsStr) -> io::Result<PathBuf> {
+        self.store.child_path(parent, name)
+    }
+
+    fn file(&self, fh: u64) -> io::Result<&File> {
+        self.files
+            .get(&fh)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    fn attr(&self, ino: u64) -> io::Result<FileAttr> {
+        if let Some(control) = self.control(ino) {
+            return control.attr(ino);
+        }
+        let meta = fs::symlink_metadata(self.path(ino)?)?;
+        Ok(to_attr(ino, &meta))
+    }
+
+    fn do_lookup(&mut self, parent: u64, name: &OsStr) -> io::Result<FileAttr> {
+        if let Some(found) = self.control.as_ref().and_then(|c| c.lookup(parent, name)) {
+            return found;
+        }
+        let meta = fs::symlink_metadata(self.child_path(parent, name)?)?;
+        let ino = self
+            .store
+            .inodes()
+            .lookup(parent, name, backing(&meta), meta.is_dir());
+        self.hook.looked_up(parent, ino);
+        Ok(to_attr(ino, &meta))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn do_setattr(
+        &mut self,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        fh: Option<u64>,
+    ) -> io::Result<FileAttr> {
+        self.writable(ino)?;
+        let path = self.path(ino)?;
+        self.journal(&[&path])?;
+        let before = self.attr(ino)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+        if uid.is_some() || gid.is_some() {
+            let c_path = cstr(&path)?;
+            let uid = uid.unwrap_or(u32::MAX);
+            let gid = gid.unwrap_or(u32::MAX);
+            check(unsafe { libc::lchown(c_path.as_ptr(), uid, gid) })?;
+        }
+        if let Some(size) = size {
+            match fh.and_then(|fh| self.files.get(&fh)) {
+                Some(file) => file.set_len(size)?,
+                None => OpenOptions::new().write(true).open(&path)?.set_len(size)?,
+            }
+        }
+        if atime.is_some() || mtime.is_some() {
+            let c_path = cstr(&path)?;
+            let times = [to_timespec(atime), to_timespec(mtime)];
+            check(unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    c_path.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+        let after = self.attr(ino)?;
+        if size.is_some() {
+            self.hook.modified(ino);
+            self.changed_links(ino, None);
+        } else {
+            let fields = Attrs::changed(&before, &after);
+            if !fields.is_empty() {
+                self.hook.attributes_changed(ino, fields);
+            }
+            self.changed_links(ino, Some(fields));
+        }
+        Ok(after)
+    }
+
+    fn do_readdir(&mut self, ino: u64, offset: i64, reply: &mut ReplyDirectory) -> io::Result<()> {
+        let entries = match self.control(ino) {
+            Some(control) => control.entries(ino)?,
+            None => self.entries(ino)?,
+        };
+        for (i, (child, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // The offset handed back is that of the *next* entry.
+            if reply.add(child, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The entries of the directory `ino` in the store, as `(ino, kind, name)`.
+    fn entries(&mut self, ino: u64) -> io::Result<Vec<(u64, FileType, OsString)>> {
+        let path = self.path(ino)?;
+        let mut entries = vec![
+            (ino, FileType::Directory, OsStr::new(".").to_owned()),
+            (ino, FileType::Directory, OsStr::new("..").to_owned()),
+        ];
+        if ino == ROOT_INO && self.control.is_some() {
+            entries.push((control::DIR_INO, FileType::Directory, META_DIR.into()));
+        }
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                // Raced with a removal in the store.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let name = entry.file_name();
+            if is_reserved(ino, &name) {
+                continue;
+            }
+            let child = self
+                .store
+                .inodes()
+                .link(ino, &name, backing(&meta), meta.is_dir());
+            entries.push((child, file_type(&meta), name));
+        }
+        Ok(entries)
+    }
+
+    fn do_getxattr(&self, ino: u64, name: &OsStr) -> io::Result<Vec<u8>> {
+        if let Some(value) = self.control.as_ref().and_then(|c| c.xattr(ino, name)) {
+            return value;
+        }
+        if control::is_virtual(ino) || control::is_internal_xattr(name.as_bytes()) {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA));
+        }
+        let path = cstr(&self.path(ino)?)?;
+        let name = CString::new(name.as_bytes())
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        read_sized(|buf, len| unsafe {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf.cast(), len)
+        })
+    }
+
+    fn do_setxattr(&mut self, ino: u64, name: &OsStr, value: &[u8], flags: i32) -> io::Result<()> {
+        self.writable(ino)?;
+        if control::is_internal_xattr(name.as_bytes()) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        let path = self.path(ino)?;
+        let c_path = cstr(&path)?;
+        let name = CString::new(name.as_bytes())
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let old = read_sized(|buf, len| unsafe {
+            libc::lgetxattr(c_path.as_ptr(), name.as_ptr(), buf.cast(), len)
+        })
+        .ok();
+        self.journal(&[&path])?;
+        check(unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                flags,
+            )
+        })?;
+        if old.as_deref() != Some(value) {
+            self.hook.attributes_changed(ino, attrs::XATTRS);
+            self.changed_links(ino, Some(attrs::XATTRS));
+        } else {
+            self.changed_links(ino, Some(Attrs::NONE));
+        }
+        Ok(())
+    }
+
+    fn do_removexattr(&mut self, ino: u64, name: &OsStr) -> io::Result<()> {
+        self.writable(ino)?;
+        if control::is_internal_xattr(name.as_bytes()) {
+            return Err(io::Error::from_raw_os_error(libc::ENODATA));
+        }
+        let path = self.path(ino)?;
+        let c_path = cstr(&path)?;
+        let name = CString::new(name.as_bytes())
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        self.journal(&[&path])?;
+        check(unsafe { libc::lremovexattr(c_path.as_ptr(), name.as_ptr()) })?;
+        self.hook.attributes_changed(ino, attrs::XATTRS);
+        self.changed_links(ino, Some(attrs::XATTRS));
+        Ok(())
+    }
+
+    fn do_listxattr(&self, ino: u64) -> io::Result<Vec<u8>> {
+        if control::is_virtual(ino) {
+            return Ok(Vec::new());
+        }
+        let path = cstr(&self.path(ino)?)?;
+        let names =
+            read_sized(|buf, len| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), len) })?;
+        let mut listed = Vec::with_capacity(names.len());
+        for name in names.split_inclusive(|&b| b == 0) {
+            if !control::is_internal_xattr(name) {
+                listed.extend_from_slice(name);
+            }
+        }
+        Ok(listed)
+    }
+
+    fn do_open(&mut self, ino: u64, flags: i32) -> io::Result<u64> {
+        if let Some(control) = self.control(ino) {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+            // Read once, so every read of this handle sees the same contents.
+            let contents = control.contents(ino)?;
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.contents.insert(fh, contents);
+            return Ok(fh);
+        }
+        let path = self.path(ino)?;
+        let file = OpenOptions::new()
+            .read(flags & libc::O_ACCMODE != libc::O_WRONLY)
+            .write(flags & libc::O_ACCMODE != libc::O_RDONLY)
+            .custom_flags(flags & !(libc::O_ACCMODE | libc::O_CREAT | libc::O_EXCL))
+            .open(path)?;
+        Ok(self.insert_file(file))
+    }
+
+    fn do_write(&mut self, ino: u64, fh: u64, offset: i64, data: &[u8]) -> io::Result<()> {
+        let path = self.path(ino)?;
+        self.journal(&[&path])?;
+        self.file(fh)?.write_all_at(data, offset as u64)?;
+        self.hook.modified(ino);
+        self.changed_links(ino, None);
+        Ok(())
+    }
+
+    fn insert_file(&mut self, file: File) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.files.insert(fh, file);
+        fh
+    }
+
+    /// Finishes creating `name` in `parent`: hands ownership to the caller when
+    /// running as root, registers the new inode and notifies the hook.
+    fn created(&mut self, req: &Request<'_>, parent: u64, name: &OsStr) -> io::Result<FileAttr> {
+        let path = self.child_path(parent, name)?;
+        if unsafe { libc::geteuid() } == 0 {
+            let c_path = cstr(&path)?;
+            check(unsafe { libc::lchown(c_path.as_ptr(), req.uid(), req.gid()) })?;
+        }
+        let meta = fs::symlink_metadata(&path)?;
+        let ino = self
+            .store
+            .inodes()
+            .lookup(parent, name, backing(&meta), meta.is_dir());
+        self.hook.linked(parent, ino);
+        // Anything cached about a file that used to have this backing inode is stale.
+        self.hook.modified(ino);
+        self.changed(&[&path], false);
+        Ok(to_attr(ino, &meta))
+    }
+
+    fn do_mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+    ) -> io::Result<FileAttr> {
+        self.writable_child(parent, name)?;
+        let path = self.child_path(parent, name)?;
+        self.journal(&[&path])?;
+        let c_path = cstr(&path)?;
+        check(unsafe { libc::mknod(c_path.as_ptr(), mode & !umask, rdev as libc::dev_t) })?;
+        self.created(req, parent, name)
+    }
+
+    fn do_mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<FileAttr> {
+        self.writable_child(parent, name)?;
+        let path = self.child_path(parent, name)?;
+        self.journal(&[&path])?;
+        fs::DirBuilder::new().mode(mode & !umask).create(path)?;
+        self.created(req, parent, name)
+    }
+
+    fn do_symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+    ) -> io::Result<FileAttr> {
+        self.writable_child(parent, name)?;
+        let path = self.child_path(parent, name)?;
+        self.journal(&[&path])?;
+        std::os::unix::fs::symlink(link, path)?;
+        self.created(req, parent, name)
+    }
+
+    fn do_create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+    ) -> io::Result<(FileAttr, u64)> {
+        self.writable_child(parent, name)?;
+        let path = self.child_path(parent, name)?;
+        self.journal(&[&path])?;
+        let file = OpenOptions::new()
+            .read(flags & libc::O_ACCMODE != libc::O_WRONLY)
+            .write(flags & libc::O_ACCMODE != libc::O_RDONLY)
+            .create(true)
+            .custom_flags(flags & !libc::O_ACCMODE)
+            .mode(mode & !umask)
+            .open(path)?;
+        let attr = self.created(req, parent, name)?;
+        Ok((attr, self.insert_file(file)))
+    }
+
+    fn do_link(&mut self, ino: u64, new_parent: u64, new_name: &OsStr) -> io::Result<FileAttr> {
+        self.writable(ino)?;
+        self.writable_child(new_parent, new_name)?;
+        let path = self.path(ino)?;
+        let new_path = self.child_path(new_parent, new_name)?;
+        self.journal(&[&path, &new_path])?;
+        fs::hard_link(&path, &new_path)?;
+        let meta = fs::symlink_metadata(&new_path)?;
+        let ino = self
+            .store
+            .inodes()
+            .lookup(new_parent, new_name, backing(&meta), false);
+        self.hook.linked(new_parent, ino);
+        // Directories already holding the file may have been persisted as
+        // valid on the grounds that it had a single link.
+        self.hook.modified(ino);
+        self.changed_links(ino, None);
+        Ok(to_attr(ino, &meta))
+    }
+
+    /// Removes `name` from `parent` with `remove` and drops the corresponding link.
+    fn do_remove(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        remove: fn(&Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.writable_child(parent, name)?;
+        let path = self.child_path(parent, name)?;
+        let meta = fs::symlink_metadata(&path)?;
+        self.journal(&[&path])?;
+        remove(&path)?;
+        let found = self.store.inodes().find(backing(&meta));
+        if let Some(ino) = found {
+            self.store.inodes().unlink(ino, parent, name);
+            self.hook.unlinked(parent, ino);
+        }
+        self.changed(&[&path], false);
+        Ok(())
+    }
+
+    fn do_rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.writable_child(parent, name)?;
+        self.writable_child(new_parent, new_name)?;
+        let path = self.child_path(parent, name)?;
+        let new_path = self.child_path(new_parent, new_name)?;
+        let meta = fs::symlink_metadata(&path)?;
+        let replaced = fs::symlink_metadata(&new_path).ok();
+        self.journal(&[&path, &new_path])?;
+        let (c_path, c_new_path) = (cstr(&path)?, cstr(&new_path)?);
+        check(unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                c_path.as_ptr(),
+                libc::AT_FDCWD,
+                c_new_path.as_ptr(),
+                flags,
+            )
+        })?;
+        // Whatever was below a renamed directory has moved with it.
+        let subtree = meta.is_dir() || replaced.as_ref().is_some_and(Metadata::is_dir);
+        self.changed(&[&path, &new_path], subtree);
+        if replaced.as_ref().map(backing) == Some(backing(&meta)) {
+            // Renaming onto another link of the same file leaves both in place.
+            return Ok(());
+        }
+
+        let ino = self
+            .store
+            .inodes()
+            .link(parent, name, backing(&meta), meta.is_dir());
+        if let Some(replaced) = replaced {
+            let other = self.store.inodes().link(
+                new_parent,
+                new_name,
+                backing(&replaced),
+                replaced.is_dir(),
+            );
+            if flags & libc::RENAME_EXCHANGE != 0 {
+                self.store
+                    .inodes()
+                    .relink(other, new_parent, new_name, parent, name);
+                self.hook.linked(parent, other);
+                self.hook.unlinked(new_parent, other);
+            } else {
+                self.store.inodes().unlink(other, new_parent, new_name);
+                self.hook.unlinked(new_parent, other);
+            }
+        }
+        self.store
+            .inodes()
+            .relink(ino, parent, name, new_parent, new_name);
+        // Link before unlinking, so that the node is never briefly parentless.
+        self.hook.linked(new_parent, ino);
+        self.hook.unlinked(parent, ino);
+        Ok(())
+    }
+}
+
+impl<H: Hook> Filesystem for PassthroughFS<H> {
+    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
+        // Requests carry their own umask; ours must not mask it a second time.
+        unsafe { libc::umask(0) };
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        // Every journaled change has reached the hook by now.
+        if let Some(Err(err)) = self.journal.as_mut().map(Journal::clear) {
+            eprintln!("mtfs: cannot clear journal: {}", err);
+        }
+        if let Some(Err(err)) = self.changes.as_ref().map(|changes| changes.close()) {
+            eprintln!("mtfs: cannot close change log: {}", err);
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.do_lookup(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.store.inodes().forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        match self.do_setattr(ino, mode, uid, gid, size, atime, mtime, fh) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        if let Some(control) = self.control(ino) {
+            return match control.contents(ino) {
+                Ok(target) => reply.data(&target),
+                Err(err) => reply.error(errno(err)),
+            };
+        }
+        match self.path(ino).and_then(fs::read_link) {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.do_mknod(req, parent, name, mode, umask, rdev) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.do_mkdir(req, parent, name, mode, umask) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.do_remove(parent, name, |path| fs::remove_file(path)) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.do_remove(parent, name, |path| fs::remove_dir(path)) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        match self.do_symlink(req, parent, name, link) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.do_rename(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.do_link(ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.do_open(ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if let Some(contents) = self.contents.get(&fh) {
+            let start = (offset as usize).min(contents.len());
+            let end = (start + size as usize).min(contents.len());
+            return reply.data(&contents[start..end]);
+        }
+        let mut buf = vec![0; size as usize];
+        let result = self.file(fh).and_then(|file| {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match file.read_at(&mut buf[filled..], offset as u64 + filled as u64) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(filled)
+        });
+        match result {
+            Ok(n) => reply.data(&buf[..n]),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.do_write(ino, fh, offset, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        reply.ok();
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.files.remove(&fh);
+        self.contents.remove(&fh);
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let result = self.file(fh).and_then(|file| {
+            if datasync {
+                file.sync_data()
+            } else {
+                file.sync_all()
+            }
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        match self.do_readdir(ino, offset, &mut reply) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.do_create(req, parent, name, mode, umask, flags) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        match self.do_getxattr(ino, name) {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.do_setxattr(ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.do_removexattr(ino, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.do_listxattr(ino) {
+            Ok(names) => reply_xattr(reply, size, &names),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let result = cstr(self.store.root()).and_then(|c_path| {
+            let mut st = unsafe { std::mem::zeroed::<libc::statvfs>() };
+            check(unsafe { libc::statvfs(c_path.as_ptr(), &mut st) })?;
+            Ok(st)
+        });
+        match result {
+            Ok(st) => reply.statfs(
+                st.f_blocks,
+                st.f_bfree,
+                st.f_bavail,
+                st.f_files,
+                st.f_ffree,
+                st.f_bsize as u32,
+                st.f_namemax as u32,
+                st.f_frsize as u32,
+            ),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+}
+
+/// Answers an xattr request with `value`, or only its size if that is all
+/// the kernel asked for.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+/// Calls `read`, a system call like `getxattr` that fills a buffer of the
+/// given length, with a buffer large enough for the result.
+pub(crate) fn read_sized(read: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let len = read(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0; len as usize];
+        let len = read(buf.as_mut_ptr(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        // The value grew between the two calls.
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+fn errno(err: io::Error) -> i32 {
+    err.raw_os_error().unwrap_or(libc::EIO)
+}
+
+pub(crate) fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+pub(crate) fn cstr(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+fn to_timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    match time {
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        Some(TimeOrNow::Now) => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+        Some(TimeOrNow::SpecificTime(time)) => {
+            let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            libc::timespec {
+                tv_sec: since.as_secs() as libc::time_t,
+                tv_nsec: since.subsec_nanos() as libc::c_long,
+            }
+        }
+    }
+}
+
+fn system_time(secs: i64, nsecs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsecs as u32)
+    } else {
+        UNIX_EPOCH - Duration::new(secs.unsigned_abs(), 0) + Duration::from_nanos(nsecs as u64)
+    }
+}
+
+pub(crate) fn file_type(meta: &Metadata) -> FileType {
+    match meta.mode() & libc::S_IFMT {
+        libc::S_IFDIR => FileType::Directory,
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFIFO => FileType::NamedPipe,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFBLK => FileType::BlockDevice,
+        libc::S_IFSOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+pub(crate) fn to_attr(ino: u64, meta: &Metadata) -> FileAttr {
+    FileAttr {
+        ino,
+        size: meta.size(),
+        blocks: meta.blocks(),
+        atime: system_time(meta.atime(), meta.atime_nsec()),
+        mtime: system_time(meta.mtime(), meta.mtime_nsec()),
+        ctime: system_time(meta.ctime(), meta.ctime_nsec()),
+        crtime: UNIX_EPOCH,
+        kind: file_type(meta),
+        perm: (meta.mode() & 0o7777) as u16,
+        nlink: meta.nlink() as u32,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: meta.rdev() as u32,
+        blksize: meta.blksize() as u32,
+        flags: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::PLAIN;
+    use crate::hasher::SHA1;
+    use crate::tree::{Tree, View, DEFAULT_VIEW};
+    use std::sync::Mutex;
+
+    /// Records the links and unlinks a filesystem reports.
+    #[derive(Default)]
+    struct Links(Mutex<Vec<(&'static str, u64, u64)>>);
+
+    impl Links {
+        fn take(&self) -> Vec<(&'static str, u64, u64)> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    impl Hook for Links {
+        fn linked(&self, parent: u64, ino: u64) {
+            self.0.lock().unwrap().push(("linked", parent, ino));
+        }
+
+        fn unlinked(&self, parent: u64, ino: u64) {
+            self.0.lock().unwrap().push(("unlinked", parent, ino));
+        }
+    }
+
+    fn passthrough(dir: &Path) -> (PassthroughFS<Arc<Links>>, Arc<Links>) {
+        let store = Arc::new(Store::open(dir.to_owned()).unwrap());
+        let links = Arc::new(Links::default());
+        let mount = PassthroughFS::new(store, links.clone(), None, None, None);
+        (mount, links)
+    }
+
+    impl PassthroughFS<Arc<Links>> {
+        fn ino(&mut self, path: &str) -> u64 {
+            let mut ino = ROOT_INO;
+            for name in Path::new(path) {
+                ino = self.do_lookup(ino, name).unwrap().ino;
+            }
+            ino
+        }
+
+        fn paths(&self, ino: u64) -> Vec<PathBuf> {
+            self.store.inodes().paths(ino)
+        }
+
+        fn rename(&mut self, from: &str, to: &str, flags: u32) -> io::Result<()> {
+            let (from, to) = (Path::new(from), Path::new(to));
+            let parent = self.ino(from.parent().unwrap().to_str().unwrap());
+            let new_parent = self.ino(to.parent().unwrap().to_str().unwrap());
+            let (name, new_name) = (from.file_name().unwrap(), to.file_name().unwrap());
+            self.do_rename(parent, name, new_parent, new_name, flags)
+        }
+    }
+
+    #[test]
+    fn renames_move_links() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), "a").unwrap();
+        fs::write(dir.path().join("b"), "b").unwrap();
+        fs::create_dir_all(dir.path().join("d/sub")).unwrap();
+        fs::write(dir.path().join("d/sub/x"), "x").unwrap();
+        fs::create_dir(dir.path().join("e")).unwrap();
+        let (mut mount, links) = passthrough(dir.path());
+        let [a, b, d, sub, x, e] =
+            ["a", "b", "d", "d/sub", "d/sub/x", "e"].map(|path| mount.ino(path));
+
+        // Over an existing entry, which is gone from the table but not
+        // forgotten by the kernel yet.
+        mount.rename("a", "b", 0).unwrap();
+        assert_eq!(fs::read(dir.path().join("b")).unwrap(), b"a");
+        assert_eq!(mount.paths(a), [PathBuf::from("b")]);
+        assert_eq!(mount.paths(b), Vec::<PathBuf>::new());
+        assert!(mount.store.inodes().contains(b));
+        assert_eq!(mount.ino("b"), a);
+        assert_eq!(
+            links.take(),
+            [
+                ("unlinked", ROOT_INO, b),
+                ("linked", ROOT_INO, a),
+                ("unlinked", ROOT_INO, a)
+            ]
+        );
+
+        assert_eq!(
+            mount
+                .rename("e", "b", libc::RENAME_NOREPLACE)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(mount.paths(a), [PathBuf::from("b")]);
+        assert_eq!(mount.paths(e), [PathBuf::from("e")]);
+        assert_eq!(links.take(), []);
+        mount.rename("b", "c", libc::RENAME_NOREPLACE).unwrap();
+        assert_eq!(mount.paths(a), [PathBuf::from("c")]);
+        links.take();
+
+        // Everything below a moved directory moves with it.
+        mount.rename("d", "e", libc::RENAME_EXCHANGE).unwrap();
+        assert_eq!(mount.paths(d), [PathBuf::from("e")]);
+        assert_eq!(mount.paths(e), [PathBuf::from("d")]);
+        assert_eq!(mount.paths(x), [PathBuf::from("e/sub/x")]);
+        assert_eq!(
+            links.take(),
+            [
+                ("linked", ROOT_INO, e),
+                ("unlinked", ROOT_INO, e),
+                ("linked", ROOT_INO, d),
+                ("unlinked", ROOT_INO, d)
+            ]
+        );
+
+        mount.rename("e/sub", "d/sub", 0).unwrap();
+        assert_eq!(mount.store.inodes().parent(sub), Some(e));
+        assert_eq!(mount.paths(x), [PathBuf::from("d/sub/x")]);
+        assert_eq!(fs::read(dir.path().join("d/sub/x")).unwrap(), b"x");
+        assert_eq!(links.take(), [("linked", e, sub), ("unlinked", d, sub)]);
+    }
+
+    #[test]
+    fn unlinking_keeps_other_links() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("f"), "f").unwrap();
+        fs::create_dir(dir.path().join("d")).unwrap();
+        let (mut mount, links) = passthrough(dir.path());
+        let [f, d] = ["f", "d"].map(|path| mount.ino(path));
+
+        let attr = mount.do_link(f, d, OsStr::new("g")).unwrap();
+        assert_eq!((attr.ino, attr.nlink), (f, 2));
+        assert_eq!(mount.paths(f), [PathBuf::from("f"), PathBuf::from("d/g")]);
+
+        mount
+            .do_remove(ROOT_INO, OsStr::new("f"), |path| fs::remove_file(path))
+            .unwrap();
+        assert_eq!(mount.paths(f), [PathBuf::from("d/g")]);
+        assert_eq!(mount.store.inodes().parent(f), Some(d));
+        assert_eq!(mount.attr(f).unwrap().nlink, 1);
+        assert_eq!(links.take(), [("linked", d, f), ("unlinked", ROOT_INO, f)]);
+
+        mount
+            .do_remove(d, OsStr::new("g"), |path| fs::remove_file(path))
+            .unwrap();
+        assert_eq!(mount.paths(f), Vec::<PathBuf>::new());
+        mount
+            .do_remove(ROOT_INO, OsStr::new("d"), |path| fs::remove_dir(path))
+            .unwrap();
+        assert_eq!(
+            links.take(),
+            [("unlinked", d, f), ("unlinked", ROOT_INO, d)]
+        );
+    }
+
+    #[test]
+    fn attributes_of_backing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("f");
+        fs::write(&file, "hello").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::hard_link(&file, dir.path().join("g")).unwrap();
+        let before_epoch = UNIX_EPOCH - Duration::from_millis(1_500);
+        File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(before_epoch)
+            .unwrap();
+        let meta = fs::symlink_metadata(&file).unwrap();
+        let attr = to_attr(7, &meta);
+        assert_eq!(attr.ino, 7);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.perm, 0o640);
+        assert_eq!((attr.size, attr.nlink), (5, 2));
+        assert_eq!((attr.uid, attr.gid), (meta.uid(), meta.gid()));
+        assert_eq!(attr.mtime, before_epoch);
+        assert_eq!(attr.atime, meta.accessed().unwrap());
+        assert_eq!(attr.crtime, UNIX_EPOCH);
+
+        let sticky = dir.path().join("d");
+        fs::create_dir(&sticky).unwrap();
+        fs::set_permissions(&sticky, fs::Permissions::from_mode(0o1777)).unwrap();
+        let attr = to_attr(8, &fs::symlink_metadata(&sticky).unwrap());
+        assert_eq!((attr.kind, attr.perm), (FileType::Directory, 0o1777));
+
+        let link = dir.path().join("l");
+        std::os::unix::fs::symlink("f", &link).unwrap();
+        let attr = to_attr(9, &fs::symlink_metadata(&link).unwrap());
+        assert_eq!((attr.kind, attr.size), (FileType::Symlink, 1));
+
+        let fifo = cstr(&dir.path().join("p")).unwrap();
+        check(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }).unwrap();
+        let attr = to_attr(10, &fs::symlink_metadata(dir.path().join("p")).unwrap());
+        assert_eq!(attr.kind, FileType::NamedPipe);
+    }
+
+    #[test]
+    fn setattr_invalidates_only_hashed_attributes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("f"), "f").unwrap();
+        let store = Arc::new(Store::open(dir.path().to_owned()).unwrap());
+        let view = View::new(
+            DEFAULT_VIEW.to_owned(),
+            &SHA1,
+            &PLAIN,
+            None,
+            attrs::PERM,
+            None,
+        );
+        let tree = Arc::new(Tree::new(vec![view]));
+        let mut mount = PassthroughFS::new(store.clone(), tree.clone(), None, None, None);
+        let f = mount.do_lookup(ROOT_INO, OsStr::new("f")).unwrap().ino;
+        let setattr = |mount: &mut PassthroughFS<_>, mode, uid, gid, mtime| {
+            mount
+                .do_setattr(f, mode, uid, gid, None, None, mtime, None)
+                .unwrap()
+        };
+        let root = tree.hash(&*store, 0, ROOT_INO).unwrap();
+
+        let mtime = Some(TimeOrNow::SpecificTime(UNIX_EPOCH));
+        setattr(&mut mount, None, None, None, mtime);
+        // Only root can give a file away.
+        if unsafe { libc::geteuid() } == 0 {
+            let attr = setattr(&mut mount, None, Some(1), Some(1), None);
+            assert_eq!((attr.uid, attr.gid), (1, 1));
+        }
+        assert!(tree.is_valid(0, f));
+        assert!(tree.is_valid(0, ROOT_INO));
+
+        setattr(&mut mount, Some(0o600), None, None, None);
+        assert!(!tree.is_valid(0, f));
+        assert!(!tree.is_valid(0, ROOT_INO));
+        assert_ne!(tree.hash(&*store, 0, ROOT_INO).unwrap(), root);
+    }
+}
@@ -0,0 +1,118 @@
+//! `mtfs serve`: speaks the query protocol on standard input and output for
+//! the tree at a path, relaying every request to the socket of the mount that
+//! path is in. This is what `mtfs diff` runs at the other end of a remote
+//! shell, much as rsync runs `rsync --server`.
+//!
+//! Paths in requests are taken relative to the served path, so the other end
+//! sees it as the root and cannot reach anything outside it.
+
+use crate::server::send;
+use crate::usage_error;
+use mtfs_client::protocol::{Request, Response};
+use mtfs_client::{Client, Subscription};
+use std::ffi::OsString;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub fn main(args: impl Iterator<Item = OsString>) -> ! {
+    let paths: Vec<OsString> = args.collect();
+    let [path] = <[OsString; 1]>::try_from(paths)
+        .unwrap_or_else(|_| usage_error("expected a path to serve"));
+    if let Err(err) = serve(Path::new(&path)) {
+        eprintln!("mtfs serve: {}", err);
+        process::exit(1);
+    }
+    process::exit(0)
+}
+
+fn serve(path: &Path) -> io::Result<()> {
+    let (socket, root) = mtfs_client::locate(path)?;
+    let mut client = Client::connect(&socket)?;
+    let mut out = io::stdout().lock();
+    send(&mut out, client.hello())?;
+    for line in io::stdin().lock().lines() {
+        match serde_json::from_str(&line?) {
+            Ok(Request::Subscribe { path }) => {
+                let view = client.hello().view.clone();
+                return subscribe(&socket, view.as_deref(), &under(&root, path), &mut out);
+            }
+            Ok(request) => relay(&mut client, &root, request, &mut out)?,
+            Err(err) => send(
+                &mut out,
+                &Response::Error {
+                    errno: 0,
+                    message: format!("malformed request: {}", err),
+                },
+            )?,
+        }
+    }
+    Ok(())
+}
+
+fn relay(
+    client: &mut Client,
+    root: &Path,
+    request: Request,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let under = |path| under(root, path);
+    let request = match request {
+        Request::Hash { path } => Request::Hash { path: under(path) },
+        Request::IsValid { path } => Request::IsValid { path: under(path) },
+        Request::StatTree { path } => Request::StatTree { path: under(path) },
+        Request::Entry { path } => Request::Entry { path: under(path) },
+        Request::Children { paths } => Request::Children {
+            paths: paths.into_iter().map(under).collect(),
+        },
+        Request::Changes { path, since } => Request::Changes {
+            path: under(path),
+            since,
+        },
+        Request::View { name } => Request::View { name },
+        Request::Subscribe { .. } => unreachable!("subscriptions take over the connection"),
+        Request::Diff { old, new } => {
+            // Changes are relative to the compared paths, so they need no translating back.
+            for change in client.diff(under(old), under(new))? {
+                match change {
+                    Ok(change) => send(out, &Response::Change(change))?,
+                    Err(err) => return send(out, &Response::error(&err)),
+                }
+            }
+            return send(out, &Response::Done);
+        }
+    };
+    match client.call(&request) {
+        Ok(response) => send(out, &response),
+        Err(err) => send(out, &Response::error(&err)),
+    }
+}
+
+/// Relays the changes below `path` that can change hashes in `view` until
+/// either end hangs up.
+fn subscribe(
+    socket: &Path,
+    view: Option<&str>,
+    path: &Path,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut subscription = match Subscription::connect(socket, view, path) {
+        Ok(subscription) => subscription,
+        Err(err) => return send(out, &Response::error(&err)),
+    };
+    send(out, &Response::Subscribed)?;
+    loop {
+        match subscription.recv() {
+            Ok(path) => send(out, &Response::Invalidated { path })?,
+            Err(err) => return send(out, &Response::error(&err)),
+        }
+    }
+}
+
+/// `path` from a request, which is relative to the served path `root`.
+fn under(root: &Path, path: PathBuf) -> PathBuf {
+    match path.strip_prefix("/") {
+        Ok(path) => root.join(path),
+        Err(_) => root.join(path),
+    }
+}
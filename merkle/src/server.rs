@@ -0,0 +1,463 @@
+//! The query socket: a Unix-domain socket where applications ask for hashes.
+//!
+//! The wire format lives in the `mtfs-client` crate, next to the client that
+//! speaks it. Each connection is served by its own thread, so a slow hash does
+//! not hold up other clients, and a subscription can block waiting for changes.
+//! Each connection also has a view of its own, the default one until it asks
+//! for another. A subscription is only told of changes that can change hashes
+//! in its view: those to entries the view ignores, or to attributes it does
+//! not hash, are passed over.
+
+use crate::attrs::Attrs;
+use crate::changes::Changes;
+use crate::ignore::Ignore;
+use crate::inode::ROOT_INO;
+use crate::store::Store;
+use crate::tree::{Child, Source, Tree};
+use mtfs_client::diff::{self, Side};
+use mtfs_client::protocol::{Entry, Hello, Request, Response, TreeStat, VERSION};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{iter, thread};
+
+/// How often an idle subscription checks whether its client is still there.
+const HANG_UP_CHECK: Duration = Duration::from_secs(1);
+
+/// How long a subscription waits after a change for more to merge it with,
+/// so that a burst of changes is sent as one.
+const COALESCE: Duration = Duration::from_millis(10);
+
+pub struct Server {
+    store: Arc<Store>,
+    tree: Arc<Tree>,
+    changes: Option<Arc<Changes>>,
+}
+
+impl Server {
+    /// Without `changes`, asking what changed is an error.
+    pub fn new(store: Arc<Store>, tree: Arc<Tree>, changes: Option<Arc<Changes>>) -> Self {
+        Server {
+            store,
+            tree,
+            changes,
+        }
+    }
+
+    /// Listens on `socket`, replacing any stale socket left there, and serves
+    /// connections on a background thread.
+    pub fn spawn(self, socket: &Path) -> io::Result<thread::JoinHandle<()>> {
+        let server = Arc::new(self);
+        listen(socket, "query", move |stream| server.serve(stream))
+    }
+
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut view = 0;
+        send(&mut writer, &self.hello(view))?;
+        for line in BufReader::new(stream).lines() {
+            let response = match serde_json::from_str(&line?) {
+                Ok(Request::Subscribe { path }) => {
+                    return match self.changes() {
+                        Ok(changes) => self.subscribe(changes, view, &path, &mut writer),
+                        Err(err) => send(&mut writer, &Response::error(&err)),
+                    }
+                }
+                Ok(request) => self
+                    .handle(request, &mut view, &mut writer)
+                    .unwrap_or_else(|err| Response::error(&err)),
+                Err(err) => Response::Error {
+                    errno: 0,
+                    message: format!("malformed request: {}", err),
+                },
+            };
+            send(&mut writer, &response)?;
+        }
+        Ok(())
+    }
+
+    /// What a connection is told about hashes in `view`.
+    fn hello(&self, view: usize) -> Hello {
+        let views = self.tree.views();
+        Hello {
+            server: format!("mtfs {}", env!("CARGO_PKG_VERSION")),
+            version: VERSION,
+            algorithm: views[view].hasher().name().to_owned(),
+            format: views[view].format().name().to_owned(),
+            ignore: views[view].ignore().map(Ignore::setting),
+            ignore_file: views[view]
+                .ignore()
+                .and_then(Ignore::file)
+                .map(Path::to_owned),
+            attrs: views[view].attrs().setting(),
+            view: (view != 0).then(|| views[view].name().to_owned()),
+            views: views[1..]
+                .iter()
+                .map(|view| view.name().to_owned())
+                .collect(),
+        }
+    }
+
+    /// Answers `request` in `view`, which it may switch. Only a diff writes
+    /// anything itself: its changes, as they are found, before the returned
+    /// [`Response::Done`].
+    fn handle(
+        &self,
+        request: Request,
+        view: &mut usize,
+        writer: &mut UnixStream,
+    ) -> io::Result<Response> {
+        let view = match request {
+            Request::View { name } => {
+                *view = self.tree.view(&name)?;
+                return Ok(Response::Hello(self.hello(*view)));
+            }
+            _ => *view,
+        };
+        Ok(match request {
+            Request::Hash { path } => {
+                let ino = self.resolve(&path)?;
+                Response::Hash {
+                    hash: self.tree.hash(&*self.store, view, ino)?.to_string(),
+                }
+            }
+            Request::IsValid { path } => Response::IsValid {
+                valid: self.tree.is_valid(view, self.resolve(&path)?),
+            },
+            Request::StatTree { path } => {
+                let stat = self
+                    .tree
+                    .stat(view, self.resolve(&path)?)
+                    .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+                Response::StatTree(TreeStat {
+                    hash: stat
+                        .cache
+                        .hash
+                        .filter(|_| stat.cache.valid)
+                        .map(|h| h.to_string()),
+                    valid: stat.cache.valid,
+                    generation: stat.cache.generation,
+                    nodes: stat.nodes as u64,
+                    invalid: stat.invalid as u64,
+                })
+            }
+            Request::Entry { path } => Response::Entry(self.entry(view, &path)?),
+            Request::Children { paths } => Response::Children {
+                entries: Local(self, view).children(&paths)?,
+            },
+            Request::Diff { old, new } => {
+                diff::diff(
+                    &mut Local(self, view),
+                    &old,
+                    &mut Local(self, view),
+                    &new,
+                    &mut |change| send(writer, &Response::Change(change)),
+                )?;
+                Response::Done
+            }
+            Request::Changes { path, since } => {
+                // Only relative paths can be matched against the log.
+                let path = path.strip_prefix("/").unwrap_or(&path);
+                Response::Changed(self.changes()?.since(path, since.as_deref()))
+            }
+            Request::Subscribe { .. } => unreachable!("subscriptions take over the connection"),
+            Request::View { .. } => unreachable!("answered above"),
+        })
+    }
+
+    fn changes(&self) -> io::Result<&Changes> {
+        self.changes
+            .as_deref()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    /// Sends the changes below `path` as they happen, until the client hangs up.
+    fn subscribe(
+        &self,
+        changes: &Changes,
+        view: usize,
+        path: &Path,
+        writer: &mut UnixStream,
+    ) -> io::Result<()> {
+        let path = path.strip_prefix("/").unwrap_or(path);
+        let receiver = changes.subscribe(path);
+        send(writer, &Response::Subscribed)?;
+        loop {
+            let first = match receiver.recv_timeout(HANG_UP_CHECK) {
+                Ok(first) => first,
+                Err(RecvTimeoutError::Timeout) if !hung_up(writer) => continue,
+                Err(_) => return Ok(()),
+            };
+            thread::sleep(COALESCE);
+            // Whatever piled up meanwhile is merged: sorted, a path's
+            // descendants follow it, and are dropped.
+            let mut paths: Vec<PathBuf> = iter::once(first)
+                .chain(receiver.try_iter())
+                .filter(|(changed, attrs)| {
+                    // When in doubt, tell.
+                    self.affects(view, &path.join(changed), *attrs)
+                        .unwrap_or(true)
+                })
+                .map(|(changed, _)| changed)
+                .collect();
+            paths.sort_unstable();
+            let mut highest: Vec<PathBuf> = Vec::new();
+            for path in paths {
+                if !highest.last().is_some_and(|top| path.starts_with(top)) {
+                    highest.push(path);
+                }
+            }
+            for path in highest {
+                match send(writer, &Response::Invalidated { path }) {
+                    Err(err) if err.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                    result => result?,
+                }
+            }
+        }
+    }
+
+    fn entry(&self, view: usize, path: &Path) -> io::Result<Entry> {
+        let ino = self.resolve(path)?;
+        let hash = self.tree.hash(&*self.store, view, ino)?;
+        let mode = fs::symlink_metadata(self.store.path(ino)?)?.mode();
+        Ok(Entry {
+            name: path.file_name().map(PathBuf::from).unwrap_or_default(),
+            mode,
+            hash: hash.to_string(),
+        })
+    }
+
+    fn children(&self, view: usize, path: &Path) -> io::Result<Option<Vec<Entry>>> {
+        let ino = self.resolve(path)?;
+        // Hash the directory first, so its children are hashed in parallel.
+        self.tree.hash(&*self.store, view, ino)?;
+        let Some(children) = self.store.children(ino)? else {
+            return Ok(None);
+        };
+        let mut entries = Vec::with_capacity(children.len());
+        for child in children {
+            if !self.tree.includes(view, ino, &child)? {
+                continue;
+            }
+            self.tree.ensure_attached(ino, child.ino);
+            entries.push(Entry {
+                hash: self.tree.hash(&*self.store, view, child.ino)?.to_string(),
+                name: child.name.into(),
+                mode: child.mode,
+            });
+        }
+        Ok(Some(entries))
+    }
+
+    /// Whether a change to the store-relative `path`, or to its attributes
+    /// `attrs` alone, can change hashes in `view`. A path that is gone is
+    /// only passed over if it would be ignored as a file and as a directory.
+    fn affects(&self, view: usize, path: &Path, attrs: Option<Attrs>) -> io::Result<bool> {
+        if attrs.is_some_and(|fields| !self.tree.views()[view].hashes(fields)) {
+            return Ok(false);
+        }
+        let mut inodes = Vec::new();
+        // Only as far as the path still exists.
+        let _ = self.store.walk(path, |_, child| inodes.push(child));
+        let mut dir = ROOT_INO;
+        let mut current = self.store.root().to_owned();
+        for (i, name) in path.iter().enumerate() {
+            current.push(name);
+            let modes = match fs::symlink_metadata(&current) {
+                Ok(meta) => vec![meta.mode()],
+                Err(_) => vec![libc::S_IFREG, libc::S_IFDIR],
+            };
+            let ino = inodes.get(i).copied();
+            let mut included = false;
+            for mode in modes {
+                let child = Child {
+                    name: name.to_owned(),
+                    ino: ino.unwrap_or(0),
+                    mode,
+                    links: 1,
+                };
+                included |= self.tree.includes(view, dir, &child)?;
+            }
+            if !included {
+                return Ok(false);
+            }
+            match ino {
+                Some(ino) => dir = ino,
+                None => break,
+            }
+        }
+        Ok(true)
+    }
+
+    /// The inode of the mount-relative `path`, tracked by the tree from now on.
+    fn resolve(&self, path: &Path) -> io::Result<u64> {
+        self.store.walk(path, |parent, child| {
+            self.tree.ensure_attached(parent, child)
+        })
+    }
+}
+
+/// One side of a diff, answered in this process rather than over a socket,
+/// from the view with the given index.
+pub struct Local<'a>(pub &'a Server, pub usize);
+
+impl Side for Local<'_> {
+    fn entry(&mut self, path: &Path) -> io::Result<Entry> {
+        self.0.entry(self.1, path)
+    }
+
+    fn children(&mut self, paths: &[PathBuf]) -> io::Result<Vec<Option<Vec<Entry>>>> {
+        paths
+            .iter()
+            .map(|path| self.0.children(self.1, path))
+            .collect()
+    }
+}
+
+/// Listens on `socket`, replacing any stale socket left there, and calls
+/// `serve` on a thread of its own for every connection. `what` names the
+/// connections in error messages.
+pub(crate) fn listen(
+    socket: &Path,
+    what: &'static str,
+    serve: impl Fn(UnixStream) -> io::Result<()> + Send + Sync + 'static,
+) -> io::Result<thread::JoinHandle<()>> {
+    match fs::remove_file(socket) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let listener = UnixListener::bind(socket)?;
+    let serve = Arc::new(serve);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let serve = serve.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = serve(stream) {
+                            eprintln!("mtfs: {} connection failed: {}", what, err);
+                        }
+                    });
+                }
+                Err(err) => eprintln!("mtfs: cannot accept {} connection: {}", what, err),
+            }
+        }
+    }))
+}
+
+/// Whether the other end of `stream` has hung up, without waiting.
+fn hung_up(stream: &UnixStream) -> bool {
+    let mut fd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut fd, 1, 0) };
+    ready > 0 && fd.revents & (libc::POLLRDHUP | libc::POLLHUP | libc::POLLERR) != 0
+}
+
+pub(crate) fn send(writer: &mut impl Write, message: &impl serde::Serialize) -> io::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs;
+    use crate::format::PLAIN;
+    use crate::hasher::{SHA1, SHA256};
+    use crate::tree::{View, DEFAULT_VIEW};
+    use mtfs_client::{Client, Subscription};
+
+    #[test]
+    fn subscriptions_pass_over_what_the_view_leaves_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("store");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("a"), "a").unwrap();
+        fs::write(root.join("dir/b.o"), "b").unwrap();
+        let filter = dir.path().join("filter");
+        fs::write(&filter, "- *.o\n").unwrap();
+
+        let store = Arc::new(Store::open(root.clone()).unwrap());
+        let ignore = Ignore::rsync(store.clone(), Some(&filter)).unwrap();
+        let view = View::new(
+            DEFAULT_VIEW.to_owned(),
+            &SHA1,
+            &PLAIN,
+            Some(ignore),
+            Attrs::NONE,
+            None,
+        );
+        let changes = Arc::new(Changes::open(&store).unwrap());
+        let tree = Arc::new(Tree::new(vec![view]));
+        let socket = root.join(".mtfs/socket");
+        Server::new(store, tree, Some(changes.clone()))
+            .spawn(&socket)
+            .unwrap();
+        let mut subscription = Subscription::connect(&socket, None, "").unwrap();
+
+        let record = |path: &str, attrs| changes.record(&[&root.join(path)], false, attrs);
+        record("dir/b.o", None);
+        record("dir/gone.o", None);
+        record("a", Some(attrs::MTIME));
+        record("a", Some(attrs::PERM));
+        assert_eq!(subscription.recv().unwrap(), Path::new("a"));
+        record("dir", None);
+        assert_eq!(subscription.recv().unwrap(), Path::new("dir"));
+    }
+
+    #[test]
+    fn connections_switch_views() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("store");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a"), "a").unwrap();
+        let store = Arc::new(Store::open(root).unwrap());
+        let views = vec![
+            View::new(
+                DEFAULT_VIEW.to_owned(),
+                &SHA1,
+                &PLAIN,
+                None,
+                Attrs::NONE,
+                None,
+            ),
+            View::new("alt".to_owned(), &SHA256, &PLAIN, None, Attrs::NONE, None),
+        ];
+        let tree = Arc::new(Tree::new(views));
+        let socket = dir.path().join("socket");
+        Server::new(store.clone(), tree.clone(), None)
+            .spawn(&socket)
+            .unwrap();
+        let hash = |view, path| {
+            let ino = store.walk(Path::new(path), |_, _| {}).unwrap();
+            tree.hash(&*store, view, ino).unwrap().to_string()
+        };
+
+        let mut client = Client::connect(&socket).unwrap();
+        assert_eq!(client.hello().views, ["alt"]);
+        assert_eq!(client.hash("a").unwrap(), hash(0, "a"));
+        client.view("alt").unwrap();
+        assert_eq!(client.hello().view.as_deref(), Some("alt"));
+        assert_eq!(client.hello().algorithm, "sha256");
+        assert_eq!(client.hash("a").unwrap(), hash(1, "a"));
+        assert_eq!(client.hash("").unwrap(), hash(1, ""));
+        assert_ne!(hash(0, ""), hash(1, ""));
+
+        assert!(client.view("other").is_err());
+        assert_eq!(client.hash("").unwrap(), hash(1, ""));
+        client.view(DEFAULT_VIEW).unwrap();
+        assert_eq!(client.hello().view, None);
+        assert_eq!(client.hash("").unwrap(), hash(0, ""));
+    }
+}
@@ -0,0 +1,257 @@
+//! The private store: the backing directory and the inode table that maps into it.
+//!
+//! Shared between the FUSE session and anything that hashes the tree.
+
+use crate::attrs::Attrs;
+use crate::format::Data;
+use crate::inode::{Backing, InodeTable, ROOT_INO};
+use crate::passthrough::to_attr;
+use crate::tree::{Child, Source, DEFAULT_VIEW};
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Directory at the root of the private store holding MTFS's own metadata.
+/// It is hidden from the mount and never hashed.
+pub const META_DIR: &str = ".mtfs";
+
+pub struct Store {
+    root: PathBuf,
+    inodes: Mutex<InodeTable>,
+}
+
+impl Store {
+    pub fn open(root: PathBuf) -> io::Result<Self> {
+        let meta = fs::metadata(&root)?;
+        if !meta.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        Ok(Store {
+            inodes: Mutex::new(InodeTable::new(backing(&meta))),
+            root,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn inodes(&self) -> MutexGuard<'_, InodeTable> {
+        self.inodes.lock().unwrap()
+    }
+
+    /// Absolute path of `ino` in the store.
+    pub fn path(&self, ino: u64) -> io::Result<PathBuf> {
+        self.inodes()
+            .store_path(&self.root, ino)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    /// Absolute path of `name` in `parent`, which must not be the metadata directory.
+    pub fn child_path(&self, parent: u64, name: &OsStr) -> io::Result<PathBuf> {
+        if is_reserved(parent, name) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        Ok(self.path(parent)?.join(name))
+    }
+
+    /// Path of `name` inside the metadata directory, creating the directory if needed.
+    pub fn meta_path(&self, name: &str) -> io::Result<PathBuf> {
+        let dir = self.root.join(META_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join(name))
+    }
+
+    /// Looks up the store-relative `path` component by component, starting at
+    /// the root, and returns its inode. `each` is called with every
+    /// `(parent, child)` pair on the way, so a caller can follow the walk even
+    /// when it ends in an error. Only the last component may be a symlink,
+    /// which is not followed: anything else than a directory before it fails
+    /// with `ENOTDIR`, so a path never leads out of the store.
+    pub fn walk(&self, path: &Path, mut each: impl FnMut(u64, u64)) -> io::Result<u64> {
+        let mut ino = ROOT_INO;
+        let mut current = self.root.clone();
+        let mut is_dir = true;
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                Component::RootDir | Component::CurDir => continue,
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL))
+                }
+            };
+            if !is_dir {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+            }
+            if is_reserved(ino, name) {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            current.push(name);
+            let meta = fs::symlink_metadata(&current)?;
+            is_dir = meta.is_dir();
+            let child = self.inodes().link(ino, name, backing(&meta), is_dir);
+            each(ino, child);
+            ino = child;
+        }
+        Ok(ino)
+    }
+
+    /// Records a setting the store's hashes depend on, such as the hash algorithm,
+    /// in the metadata file `name`. Returns the previously recorded value if it differs.
+    pub fn record(&self, name: &str, value: &str) -> io::Result<Option<String>> {
+        let previous = self.recorded(name)?;
+        if previous.as_deref() == Some(value) {
+            return Ok(None);
+        }
+        let path = self.meta_path(name)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, format!("{}\n", value))?;
+        fs::rename(&tmp, &path)?;
+        Ok(previous)
+    }
+
+    /// The value recorded in the metadata file `name`, if any.
+    pub fn recorded(&self, name: &str) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.root.join(META_DIR).join(name)) {
+            Ok(value) => Ok(Some(value.trim().to_owned())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The name under which the view `view` keeps the metadata called `name`:
+/// `name` itself for the default view, `view.name` for any other.
+pub fn view_meta(view: &str, name: &str) -> String {
+    if view == DEFAULT_VIEW {
+        name.to_owned()
+    } else {
+        format!("{}.{}", view, name)
+    }
+}
+
+/// Whether `name` in `parent` is the metadata directory.
+pub fn is_reserved(parent: u64, name: &OsStr) -> bool {
+    parent == ROOT_INO && name == META_DIR
+}
+
+impl Source for Store {
+    fn children(&self, ino: u64) -> io::Result<Option<Vec<Child>>> {
+        let path = self.path(ino)?;
+        if !fs::symlink_metadata(&path)?.is_dir() {
+            return Ok(None);
+        }
+        let mut children = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let name = entry.file_name();
+            if is_reserved(ino, &name) {
+                continue;
+            }
+            let child = self
+                .inodes()
+                .link(ino, &name, backing(&meta), meta.is_dir());
+            children.push(Child {
+                name,
+                ino: child,
+                mode: meta.mode(),
+                links: meta.nlink(),
+            });
+        }
+        Ok(Some(children))
+    }
+
+    fn data(&self, ino: u64) -> io::Result<Data> {
+        let path = self.path(ino)?;
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.is_file() {
+            Ok(Data {
+                len: meta.len(),
+                reader: Box::new(File::open(&path)?),
+            })
+        } else if meta.file_type().is_symlink() {
+            let target = fs::read_link(&path)?.into_os_string().into_vec();
+            Ok(Data {
+                len: target.len() as u64,
+                reader: Box::new(io::Cursor::new(target)),
+            })
+        } else {
+            Ok(Data {
+                len: 0,
+                reader: Box::new(io::empty()),
+            })
+        }
+    }
+
+    fn attributes(&self, ino: u64, attrs: Attrs) -> io::Result<Vec<u8>> {
+        let path = self.path(ino)?;
+        let meta = fs::symlink_metadata(&path)?;
+        attrs.encode(&to_attr(ino, &meta), &path)
+    }
+}
+
+pub fn backing(meta: &Metadata) -> Backing {
+    (meta.dev(), meta.ino())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn changed_settings_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().to_owned()).unwrap();
+        assert_eq!(store.recorded("algorithm").unwrap(), None);
+        assert_eq!(store.record("algorithm", "sha1").unwrap(), None);
+        assert_eq!(store.record("algorithm", "sha1").unwrap(), None);
+        drop(store);
+
+        // Remounting with another algorithm says what the hashes were made with.
+        let store = Store::open(dir.path().to_owned()).unwrap();
+        assert_eq!(
+            store.recorded("algorithm").unwrap().as_deref(),
+            Some("sha1")
+        );
+        assert_eq!(
+            store.record("algorithm", "blake3").unwrap().as_deref(),
+            Some("sha1")
+        );
+        assert_eq!(
+            store.recorded("algorithm").unwrap().as_deref(),
+            Some("blake3")
+        );
+    }
+
+    #[test]
+    fn walk_does_not_follow_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), "secret").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("dir")).unwrap();
+        fs::write(dir.path().join("dir/file"), "").unwrap();
+        symlink(outside.path(), dir.path().join("link")).unwrap();
+        let store = Store::open(dir.path().to_owned()).unwrap();
+
+        let file = store.walk(Path::new("dir/file"), |_, _| {}).unwrap();
+        assert_eq!(store.path(file).unwrap(), dir.path().join("dir/file"));
+        // The link itself can be looked up, but not gone through.
+        store.walk(Path::new("link"), |_, _| {}).unwrap();
+        let err = store.walk(Path::new("link/secret"), |_, _| {}).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+        let err = store.walk(Path::new("dir/file/x"), |_, _| {}).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+        let err = store.walk(Path::new("../etc"), |_, _| {}).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+}
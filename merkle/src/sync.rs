@@ -0,0 +1,381 @@
+//! `mtfs sync`: copies what differs from a tree in an MTFS mount to a
+//! destination, like `rsync -a` but deciding what differs by the Merkle tree.
+//!
+//! The destination is either in another MTFS mount, whose hashes are asked
+//! for over its socket, or a plain directory, which is hashed here with the
+//! source's algorithm, format, ignore rules and hashed attributes. Changes are
+//! applied as the diff finds them: added entries are copied with everything
+//! below them that the source's hashes include, modified files are copied
+//! aside and renamed into place, and removed entries are deleted only with
+//! `--delete`. Copies keep their permissions and modification times, and so
+//! do the directories they were made in.
+//!
+//! Entries are compared by name and mode as well as by hash, so a rename or a
+//! chmod is copied even where the format leaves it out of hashes. Only what
+//! the hashes of directories cover leads the diff below them, though: with
+//! the git format, that is the executable bit and no other permission.
+//!
+//! With `--view`, hashes come from that view of the source's mount, and of
+//! the destination's if it is in one.
+
+use crate::attrs::Attrs;
+use crate::diff::print;
+use crate::ignore;
+use crate::options::ViewOptions;
+use crate::passthrough::{check, cstr};
+use crate::server::{Local, Server};
+use crate::store::Store;
+use crate::tree::{Tree, View, DEFAULT_VIEW};
+use crate::{format, hasher, usage_error};
+use mtfs_client::diff::{self, Change, ChangeKind};
+use mtfs_client::protocol::Hello;
+use mtfs_client::{locate, Client};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, Metadata};
+use std::io;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+pub fn main(mut args: impl Iterator<Item = OsString>) -> ! {
+    let mut sync = Sync::default();
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "-n" || arg == "--dry-run" {
+            sync.dry_run = true;
+        } else if arg == "--delete" {
+            sync.delete = true;
+        } else if arg == "-v" || arg == "--verbose" {
+            sync.verbose = true;
+        } else if arg == "--view" {
+            let view = args
+                .next()
+                .and_then(|view| view.into_string().ok())
+                .unwrap_or_else(|| usage_error("--view requires a view name"));
+            sync.view = Some(view);
+        } else {
+            paths.push(PathBuf::from(arg));
+        }
+    }
+    let [source, destination] = <[PathBuf; 2]>::try_from(paths)
+        .unwrap_or_else(|_| usage_error("expected a source and a destination"));
+    sync.source = source;
+    sync.destination = destination;
+
+    if let Err(err) = sync.run() {
+        eprintln!("mtfs sync: {}", err);
+        process::exit(1);
+    }
+    process::exit(0)
+}
+
+#[derive(Default)]
+struct Sync {
+    source: PathBuf,
+    destination: PathBuf,
+    dry_run: bool,
+    delete: bool,
+    verbose: bool,
+    /// The view to compare hashes in, if not the default.
+    view: Option<String>,
+    /// The source's path relative to its mount.
+    source_path: PathBuf,
+    /// A connection to the source's mount for listing what to copy, as the
+    /// other is busy with the diff.
+    lister: Option<Client>,
+    /// Destination directories whose entries were changed, relative to the
+    /// destination, so their times can be restored at the end.
+    touched: BTreeSet<PathBuf>,
+}
+
+impl Sync {
+    fn run(&mut self) -> io::Result<()> {
+        let (socket, source_path) = locate(&self.source)?;
+        let mut source = Client::connect(&socket)?;
+        let mut lister = Client::connect(&socket)?;
+        if let Some(view) = &self.view {
+            source.view(view)?;
+            lister.view(view)?;
+        }
+        self.source_path = source_path.clone();
+        self.lister = Some(lister);
+
+        if fs::symlink_metadata(&self.destination).is_err() {
+            let mode = fs::symlink_metadata(&self.source)?.mode();
+            return self.apply(Change {
+                kind: ChangeKind::Added,
+                path: PathBuf::new(),
+                mode,
+            });
+        }
+
+        // The changes that turn the destination into the source.
+        match locate(&self.destination) {
+            Ok((socket, path)) => {
+                let mut destination = Client::connect(socket)?;
+                if let Some(view) = &self.view {
+                    destination.view(view)?;
+                }
+                diff::between(
+                    &mut destination,
+                    &path,
+                    &mut source,
+                    &source_path,
+                    &mut |change| self.apply(change),
+                )?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // Hash a plain directory the way the source does.
+                let store = Arc::new(Store::open(self.destination.clone())?);
+                let view = plain_view(source.hello(), &store)?;
+                let tree = Arc::new(Tree::new(vec![view]));
+                let server = Server::new(store, tree, None);
+                diff::diff(
+                    &mut Local(&server, 0),
+                    Path::new(""),
+                    &mut source,
+                    &source_path,
+                    &mut |change| self.apply(change),
+                )?;
+            }
+            Err(err) => return Err(err),
+        }
+
+        if !self.dry_run {
+            for dir in &self.touched {
+                let meta = fs::symlink_metadata(self.source.join(dir))?;
+                preserve(&meta, &self.destination.join(dir))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, change: Change) -> io::Result<()> {
+        if change.kind == ChangeKind::Removed && !self.delete {
+            return Ok(());
+        }
+        if self.verbose || self.dry_run {
+            print(&mut io::stdout().lock(), &change)?;
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let to = self.destination.join(&change.path);
+        match change.kind {
+            ChangeKind::Removed => remove(&to)?,
+            ChangeKind::Added => {
+                // Something of another type may be in the way if it was not deleted.
+                if fs::symlink_metadata(&to).is_ok() {
+                    remove(&to)?;
+                }
+                self.copy(&change.path, &to)?;
+            }
+            ChangeKind::Modified => {
+                let meta = fs::symlink_metadata(self.source.join(&change.path))?;
+                if meta.is_dir() {
+                    // Only the directory's own metadata differs.
+                    return preserve(&meta, &to);
+                }
+                let mut name = OsString::from(".");
+                name.push(to.file_name().unwrap_or_default());
+                name.push(".mtfs-sync");
+                let tmp = to.with_file_name(name);
+                if fs::symlink_metadata(&tmp).is_ok() {
+                    remove(&tmp)?;
+                }
+                self.copy(&change.path, &tmp)?;
+                fs::rename(&tmp, &to)?;
+            }
+        }
+        if let Some(parent) = change.path.parent() {
+            self.touched.insert(parent.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Copies `path` in the source, and everything below it that its hash
+    /// includes, to `to`, which must not exist.
+    fn copy(&mut self, path: &Path, to: &Path) -> io::Result<()> {
+        let from = self.source.join(path);
+        let meta = fs::symlink_metadata(&from)?;
+        let kind = meta.file_type();
+        if kind.is_dir() {
+            fs::create_dir(to)?;
+            let lister = self.lister.as_mut().expect("connected before copying");
+            let listed = lister.children(&[self.source_path.join(path)])?;
+            for entry in listed.into_iter().flatten().flatten() {
+                self.copy(&path.join(&entry.name), &to.join(&entry.name))?;
+            }
+        } else if kind.is_symlink() {
+            symlink(fs::read_link(&from)?, to)?;
+        } else if kind.is_file() {
+            fs::copy(&from, to)?;
+        } else {
+            eprintln!("mtfs sync: skipping special file {}", from.display());
+            return Ok(());
+        }
+        preserve(&meta, to)
+    }
+}
+
+/// A view hashing the plain directory `store` the way the source's, as
+/// described by `hello`, hashes its tree.
+fn plain_view(hello: &Hello, store: &Arc<Store>) -> io::Result<View> {
+    let unknown = |what: &str, name: &str| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("source uses unknown {} {:?}", what, name),
+        )
+    };
+    let hasher =
+        hasher::by_name(&hello.algorithm).ok_or_else(|| unknown("algorithm", &hello.algorithm))?;
+    let format = format::by_name(&hello.format).ok_or_else(|| unknown("format", &hello.format))?;
+    let ignore = match hello.ignore.as_deref() {
+        Some(setting) => {
+            let mode = setting.split('+').next().unwrap_or(setting);
+            // The rules from outside the tree are those the source read.
+            let mut options = ViewOptions::new(DEFAULT_VIEW.to_owned());
+            match mode {
+                "git" => options.excludes_file = hello.ignore_file.clone(),
+                "rsync" => options.filter = hello.ignore_file.clone(),
+                _ => {}
+            }
+            let ignore =
+                ignore::open(mode, store, &options)?.ok_or_else(|| unknown("ignore mode", mode))?;
+            if ignore.setting() != setting {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the source's ignore setting is {}, but its rules give {} here",
+                        setting,
+                        ignore.setting()
+                    ),
+                ));
+            }
+            Some(ignore)
+        }
+        None => None,
+    };
+    let attrs = match hello.attrs.as_deref() {
+        Some(list) => Attrs::parse(list).map_err(|_| unknown("attributes", list))?,
+        None => Attrs::NONE,
+    };
+    Ok(View::new(
+        DEFAULT_VIEW.to_owned(),
+        hasher,
+        format,
+        ignore,
+        attrs,
+        None,
+    ))
+}
+
+/// Gives `to` the permissions and modification time in `meta`.
+fn preserve(meta: &Metadata, to: &Path) -> io::Result<()> {
+    if !meta.file_type().is_symlink() {
+        fs::set_permissions(to, meta.permissions())?;
+    }
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: meta.mtime(),
+            tv_nsec: meta.mtime_nsec(),
+        },
+    ];
+    let path = cstr(to)?;
+    check(unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::PLAIN;
+    use crate::hasher::SHA1;
+    use crate::ignore::Ignore;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Serves the hashes of `root`, leaving out what `filter` excludes.
+    fn serve(root: &Path, filter: &Path) {
+        let store = Arc::new(Store::open(root.to_owned()).unwrap());
+        let ignore = Ignore::rsync(store.clone(), Some(filter)).unwrap();
+        let view = View::new(
+            DEFAULT_VIEW.to_owned(),
+            &SHA1,
+            &PLAIN,
+            Some(ignore),
+            Attrs::NONE,
+            None,
+        );
+        let server = Server::new(store, Arc::new(Tree::new(vec![view])), None);
+        server.spawn(&root.join(".mtfs/socket")).unwrap();
+    }
+
+    fn sync(source: &Path, destination: &Path) {
+        let mut sync = Sync {
+            source: source.to_owned(),
+            destination: destination.to_owned(),
+            delete: true,
+            ..Sync::default()
+        };
+        sync.run().unwrap();
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().mode() & 0o7777
+    }
+
+    #[test]
+    fn renames_chmods_and_ignored_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, destination) = (dir.path().join("src"), dir.path().join("dst"));
+        let filter = dir.path().join("filter");
+        fs::write(&filter, "- *.o\n").unwrap();
+        fs::create_dir_all(source.join(".mtfs")).unwrap();
+        fs::create_dir(source.join("dir")).unwrap();
+        fs::write(source.join("dir/a"), "a").unwrap();
+        fs::write(source.join("dir/run"), "#!/bin/sh\n").unwrap();
+        fs::write(source.join("dir/a.o"), "object").unwrap();
+
+        serve(&source, &filter);
+        sync(&source, &destination);
+        assert!(destination.join("dir/a").exists());
+        assert!(!destination.join("dir/a.o").exists());
+        assert!(!destination.join(".mtfs").exists());
+
+        fs::rename(source.join("dir/a"), source.join("dir/b")).unwrap();
+        fs::set_permissions(source.join("dir/run"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir(source.join("new")).unwrap();
+        fs::write(source.join("new/c"), "c").unwrap();
+        fs::write(source.join("new/c.o"), "object").unwrap();
+
+        // Nothing invalidates the hashes without a mount, so start afresh.
+        serve(&source, &filter);
+        sync(&source, &destination);
+        assert!(!destination.join("dir/a").exists());
+        assert_eq!(fs::read(destination.join("dir/b")).unwrap(), b"a");
+        assert_eq!(mode(&destination.join("dir/run")), 0o755);
+        assert!(destination.join("new/c").exists());
+        assert!(!destination.join("new/c.o").exists());
+    }
+}
@@ -0,0 +1,894 @@
+//! In-memory cache of Merkle hashes, keyed by inode.
+//!
+//! This implements `modify` and `hash` from the design in the README. Every node
+//! holds its last computed hash and a valid bit. Modifying a node clears its
+//! valid bit and walks towards the root, but stops at the first ancestor that is
+//! already invalid: by the recursive validity invariant, everything above it is
+//! invalid too. Hashing recomputes only invalid nodes, fanning out over
+//! independent subtrees on the rayon thread pool.
+//!
+//! Each node also carries a generation counter that every invalidation bumps.
+//! A hash computed concurrently with a write is only marked valid if the
+//! generation it was computed under is still current, and if every child it
+//! was computed from is still valid at the generation that was read.
+//!
+//! With a [`MetadataStore`], the hash, valid bit and generation of every node
+//! are written through: the invalidations of one walk together, and the
+//! validations of one [`Tree::hash`] once it is done. The writes happen after
+//! the node table is unlocked, so hashing does not wait on them, but in the
+//! order the changes were made in memory, so the persisted copy ends up
+//! matching memory. Nodes are loaded from it when they are first seen,
+//! which is always before anything below them can be modified. The exception
+//! is a file with several hard links, which can change through a link the tree
+//! has never seen, so directories containing one are never persisted as valid.
+//!
+//! With an [`Ignore`], the children it ignores are left out of their parent's
+//! hash, and it is told about every change so that it can invalidate the
+//! directories a change of its rules affects.
+//!
+//! A tree can be hashed in several [`View`]s at once, each with its own
+//! algorithm, format, ignore rules, hashed attributes and metadata backend,
+//! and each node keeps a [`Cache`] per view. A change invalidates every view
+//! in the same walk, which goes on past a node as long as any view still had
+//! it valid. A change to attributes alone only invalidates the views that
+//! hash them.
+
+use crate::attrs::Attrs;
+use crate::digest::Digest;
+use crate::format::{Data, Entry, Format};
+use crate::hasher::MerkleHasher;
+use crate::ignore::Ignore;
+use crate::inode::ROOT_INO;
+use crate::metadata::{MetadataStore, Update};
+use crate::passthrough::Hook;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+/// A child of a directory, as listed by a [`Source`].
+pub struct Child {
+    pub name: OsString,
+    pub ino: u64,
+    /// `st_mode` of the child.
+    pub mode: u32,
+    /// `st_nlink` of the child.
+    pub links: u64,
+}
+
+/// Where the tree gets the children and data of a node when it needs to rehash it.
+pub trait Source: Sync {
+    /// Children of `ino`, or `None` if it is not a directory.
+    fn children(&self, ino: u64) -> io::Result<Option<Vec<Child>>>;
+
+    /// The node's own data: file contents, a symlink's target, nothing for a directory.
+    fn data(&self, ino: u64) -> io::Result<Data>;
+
+    /// The node's `attrs`, written out as described in [`crate::attrs`].
+    fn attributes(&self, ino: u64, attrs: Attrs) -> io::Result<Vec<u8>>;
+}
+
+/// Name of the view made of the hashing options before any `view=`.
+pub const DEFAULT_VIEW: &str = "default";
+
+#[derive(Clone, Debug, Default)]
+pub struct Node {
+    /// One entry per link, so a file hard-linked twice into the same directory
+    /// appears twice. Empty for the root.
+    pub parents: Vec<u64>,
+    /// What is cached for each view, in the order of [`Tree::views`].
+    pub caches: Vec<Cache>,
+}
+
+/// The cached hash of a node in one view.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cache {
+    pub hash: Option<Digest>,
+    pub valid: bool,
+    pub generation: u64,
+    /// Whether the valid bit may be persisted: no hard-linked file lies below it.
+    pub durable: bool,
+}
+
+/// What the tree tracks at and below a node, in one view.
+#[derive(Clone, Debug)]
+pub struct Stat {
+    pub cache: Cache,
+    /// Tracked nodes in the subtree, including the node itself.
+    pub nodes: usize,
+    /// How many of those are invalid.
+    pub invalid: usize,
+}
+
+/// One way of hashing the tree, with a cache of its own.
+#[derive(Debug)]
+pub struct View {
+    name: String,
+    hasher: &'static dyn MerkleHasher,
+    format: &'static dyn Format,
+    ignore: Option<Ignore>,
+    attrs: Attrs,
+    persisted: Option<Box<dyn MetadataStore>>,
+}
+
+impl View {
+    pub fn new(
+        name: String,
+        hasher: &'static dyn MerkleHasher,
+        format: &'static dyn Format,
+        ignore: Option<Ignore>,
+        attrs: Attrs,
+        persisted: Option<Box<dyn MetadataStore>>,
+    ) -> Self {
+        View {
+            name,
+            hasher,
+            format,
+            ignore,
+            attrs,
+            persisted,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn hasher(&self) -> &'static dyn MerkleHasher {
+        self.hasher
+    }
+
+    pub fn format(&self) -> &'static dyn Format {
+        self.format
+    }
+
+    pub fn ignore(&self) -> Option<&Ignore> {
+        self.ignore.as_ref()
+    }
+
+    /// The attributes hashed along with every node's contents.
+    pub fn attrs(&self) -> Attrs {
+        self.attrs
+    }
+
+    /// Whether a change to the attributes `fields` of a node changes hashes,
+    /// because the view hashes one of them or the format puts it in the
+    /// parent's hash.
+    pub fn hashes(&self, fields: Attrs) -> bool {
+        (self.attrs | self.format.attrs()).intersects(fields)
+    }
+}
+
+#[derive(Debug)]
+pub struct Tree {
+    nodes: Mutex<HashMap<u64, Node>>,
+    /// The default view first.
+    views: Vec<View>,
+    turns: Turns,
+}
+
+/// Turns to write to the metadata backends in, taken while the node table is
+/// locked, so that updates are persisted in the order they were made even
+/// though they are written once it is unlocked.
+#[derive(Debug, Default)]
+struct Turns {
+    next: AtomicU64,
+    current: Mutex<u64>,
+    done: Condvar,
+}
+
+impl Turns {
+    /// Only take a turn while the node table is locked, and always run it.
+    fn take(&self) -> u64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Runs `write` once every earlier turn has run.
+    fn run(&self, turn: u64, write: impl FnOnce()) {
+        let mut current = self.current.lock().unwrap();
+        while *current != turn {
+            current = self.done.wait(current).unwrap();
+        }
+        write();
+        *current += 1;
+        self.done.notify_all();
+    }
+}
+
+impl Tree {
+    pub fn new(views: Vec<View>) -> Self {
+        assert!(!views.is_empty(), "a tree needs a view");
+        let tree = Tree {
+            nodes: Mutex::new(HashMap::new()),
+            views,
+            turns: Turns::default(),
+        };
+        let root = tree.load(ROOT_INO);
+        tree.nodes().insert(ROOT_INO, root);
+        tree
+    }
+
+    pub fn views(&self) -> &[View] {
+        &self.views
+    }
+
+    /// The index of the view called `name`.
+    pub fn view(&self, name: &str) -> io::Result<usize> {
+        self.views
+            .iter()
+            .position(|view| view.name == name)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no view called {}", name))
+            })
+    }
+
+    /// Whether `child` of the directory `dir` is part of its hash in `view`.
+    pub fn includes(&self, view: usize, dir: u64, child: &Child) -> io::Result<bool> {
+        let view = &self.views[view];
+        if !view.format.includes(&child.name) {
+            return Ok(false);
+        }
+        match &view.ignore {
+            Some(ignore) => Ok(!ignore.ignores(dir, &child.name, child.mode)?),
+            None => Ok(true),
+        }
+    }
+
+    fn nodes(&self) -> MutexGuard<'_, HashMap<u64, Node>> {
+        self.nodes.lock().unwrap()
+    }
+
+    /// A node seen for the first time, as each view persisted it if it did.
+    fn load(&self, ino: u64) -> Node {
+        let caches = self.views.iter().map(|view| {
+            let Some(persisted) = &view.persisted else {
+                return Cache::default();
+            };
+            match persisted.load(ino) {
+                Ok(Some(record)) => Cache {
+                    hash: Some(record.hash),
+                    valid: record.valid,
+                    generation: record.generation,
+                    durable: true,
+                },
+                Ok(None) => Cache::default(),
+                Err(err) => {
+                    eprintln!(
+                        "mtfs: cannot load cached hash of inode {} in view {}: {}",
+                        ino, view.name, err
+                    );
+                    Cache::default()
+                }
+            }
+        });
+        Node {
+            parents: Vec::new(),
+            caches: caches.collect(),
+        }
+    }
+
+    /// The node for `ino`, loading it if it is not tracked yet.
+    fn entry<'a>(&self, nodes: &'a mut HashMap<u64, Node>, ino: u64) -> &'a mut Node {
+        nodes.entry(ino).or_insert_with(|| self.load(ino))
+    }
+
+    /// Number of nodes currently tracked.
+    pub fn len(&self) -> usize {
+        self.nodes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn node(&self, ino: u64) -> Option<Node> {
+        self.nodes().get(&ino).cloned()
+    }
+
+    pub fn is_valid(&self, view: usize, ino: u64) -> bool {
+        self.nodes()
+            .get(&ino)
+            .is_some_and(|node| node.caches[view].valid)
+    }
+
+    /// Counts the tracked nodes below `ino`. This looks at every tracked node.
+    pub fn stat(&self, view: usize, ino: u64) -> Option<Stat> {
+        let nodes = self.nodes();
+        let mut stat = Stat {
+            cache: nodes.get(&ino)?.caches[view],
+            nodes: 0,
+            invalid: 0,
+        };
+        for node in below(&nodes, ino) {
+            stat.nodes += 1;
+            stat.invalid += usize::from(!nodes[&node].caches[view].valid);
+        }
+        Some(stat)
+    }
+
+    /// The nodes at and below `ino` that are valid in `view`. This looks at
+    /// every tracked node.
+    pub fn valid_below(&self, view: usize, ino: u64) -> Vec<u64> {
+        let nodes = self.nodes();
+        below(&nodes, ino)
+            .filter(|node| nodes[node].caches[view].valid)
+            .collect()
+    }
+
+    /// The cached hash of `ino` in `view`, if it is valid.
+    pub fn cached(&self, view: usize, ino: u64) -> Option<Digest> {
+        self.nodes()
+            .get(&ino)
+            .map(|node| node.caches[view])
+            .filter(|cache| cache.valid)
+            .and_then(|cache| cache.hash)
+    }
+
+    /// Records a link from `parent` to `child` without invalidating anything.
+    /// Used both when a change adds a link and when hashing discovers one.
+    pub fn attach(&self, parent: u64, child: u64) {
+        let mut nodes = self.nodes();
+        self.entry(&mut nodes, parent);
+        self.entry(&mut nodes, child).parents.push(parent);
+    }
+
+    /// Records a link from `parent` to `child` if it is not already known.
+    pub fn ensure_attached(&self, parent: u64, child: u64) {
+        let mut nodes = self.nodes();
+        self.entry(&mut nodes, parent);
+        let node = self.entry(&mut nodes, child);
+        if !node.parents.contains(&parent) {
+            node.parents.push(parent);
+        }
+    }
+
+    /// Drops one link from `parent` to `child`, forgetting `child` once it has none.
+    pub fn detach(&self, parent: u64, child: u64) {
+        let mut nodes = self.nodes();
+        if let Some(node) = nodes.get_mut(&child) {
+            if let Some(i) = node.parents.iter().position(|&p| p == parent) {
+                node.parents.swap_remove(i);
+            }
+            if node.parents.is_empty() && child != ROOT_INO {
+                nodes.remove(&child);
+            }
+        }
+    }
+
+    /// Marks `ino` and its ancestors invalid in every view, stopping short at
+    /// ancestors that already are in all of them. Returns the number of valid
+    /// bits that were cleared.
+    pub fn invalidate(&self, ino: u64) -> usize {
+        self.clear(ino, |_| true)
+    }
+
+    /// Marks `ino` and its ancestors invalid in `view` alone, as when only
+    /// what the view leaves out of hashes has changed.
+    pub fn invalidate_view(&self, view: usize, ino: u64) -> usize {
+        self.clear(ino, |i| i == view)
+    }
+
+    /// Clears the valid bits of `ino` and its ancestors in the views `views`
+    /// picks, in one walk up the tree that goes on as long as any of them was
+    /// still valid.
+    fn clear(&self, ino: u64, views: impl Fn(usize) -> bool) -> usize {
+        let mut nodes = self.nodes();
+        let mut cleared = 0;
+        let mut updates = vec![Vec::new(); self.views.len()];
+        let mut pending = vec![ino];
+        while let Some(ino) = pending.pop() {
+            let Some(node) = nodes.get_mut(&ino) else {
+                continue;
+            };
+            let mut climb = false;
+            for (i, (view, cache)) in self.views.iter().zip(&mut node.caches).enumerate() {
+                if !views(i) {
+                    continue;
+                }
+                cache.generation += 1;
+                if !cache.valid {
+                    continue;
+                }
+                cache.valid = false;
+                cleared += 1;
+                climb = true;
+                if view.persisted.is_some() {
+                    updates[i].push(Update::Invalidated {
+                        ino,
+                        generation: cache.generation,
+                    });
+                }
+            }
+            if climb {
+                pending.extend(node.parents.iter().copied());
+            }
+        }
+        self.persist(nodes, updates);
+        cleared
+    }
+
+    /// Writes `updates`, listed by view, to the views' backends once `nodes`,
+    /// under which they were made, is unlocked.
+    fn persist(&self, nodes: MutexGuard<'_, HashMap<u64, Node>>, updates: Vec<Vec<Update>>) {
+        if updates.iter().all(Vec::is_empty) {
+            return;
+        }
+        let turn = self.turns.take();
+        drop(nodes);
+        self.turns.run(turn, || {
+            for (view, updates) in self.views.iter().zip(updates) {
+                let Some(persisted) = &view.persisted else {
+                    continue;
+                };
+                if updates.is_empty() {
+                    continue;
+                }
+                if let Err(err) = persisted.persist(&updates) {
+                    eprintln!("mtfs: cannot persist hashes of view {}: {}", view.name, err);
+                }
+            }
+        });
+    }
+
+    /// Persists the validations of `view` in `batch` that still stand.
+    fn flush(&self, view: usize, batch: Mutex<Vec<Update>>) {
+        let nodes = self.nodes();
+        let mut updates = vec![Vec::new(); self.views.len()];
+        updates[view] = batch.into_inner().unwrap();
+        updates[view].retain(|update| {
+            let Update::Validated {
+                ino,
+                hash,
+                generation,
+            } = *update
+            else {
+                return true;
+            };
+            nodes.get(&ino).is_some_and(|node| {
+                let cache = node.caches[view];
+                cache.valid
+                    && cache.durable
+                    && cache.generation == generation
+                    && cache.hash == Some(hash)
+            })
+        });
+        self.persist(nodes, updates);
+    }
+
+    /// Stores `hash` for `ino` in `view` and marks it valid, provided no
+    /// invalidation has reached `ino` since `generation` was read and every
+    /// `(child, generation)` it was computed from is still valid at that
+    /// generation. The hash is only persisted if `durable` and every child is
+    /// durable. Returns whether it was stored.
+    pub fn validate(
+        &self,
+        view: usize,
+        ino: u64,
+        generation: u64,
+        hash: Digest,
+        children: &[(u64, u64)],
+        durable: bool,
+    ) -> bool {
+        let batch = Mutex::new(Vec::new());
+        let stored = self.validate_into(view, ino, generation, hash, children, durable, &batch);
+        self.flush(view, batch);
+        stored
+    }
+
+    /// Like [`Tree::validate`], but leaves what is to be persisted in `batch`.
+    #[allow(clippy::too_many_arguments)]
+    fn validate_into(
+        &self,
+        view: usize,
+        ino: u64,
+        generation: u64,
+        hash: Digest,
+        children: &[(u64, u64)],
+        durable: bool,
+        batch: &Mutex<Vec<Update>>,
+    ) -> bool {
+        let mut nodes = self.nodes();
+        let mut children_durable = true;
+        let children_current = children.iter().all(|(child, generation)| {
+            nodes.get(child).is_some_and(|node| {
+                let cache = &node.caches[view];
+                children_durable &= cache.durable;
+                cache.valid && cache.generation == *generation
+            })
+        });
+        match nodes.get_mut(&ino).map(|node| &mut node.caches[view]) {
+            Some(cache) if cache.generation == generation && children_current => {
+                cache.hash = Some(hash);
+                cache.valid = true;
+                cache.durable = durable && children_durable;
+                if cache.durable && self.views[view].persisted.is_some() {
+                    batch.lock().unwrap().push(Update::Validated {
+                        ino,
+                        hash,
+                        generation,
+                    });
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the hash of `ino` in `view`, recomputing it and any invalid descendants.
+    pub fn hash<S: Source>(&self, source: &S, view: usize, ino: u64) -> io::Result<Digest> {
+        let batch = Mutex::new(Vec::new());
+        let result = self.hash_node(source, view, ino, &batch);
+        self.flush(view, batch);
+        result.map(|(hash, _)| hash)
+    }
+
+    /// Returns the hash of `ino` together with the generation it is valid for,
+    /// leaving what is to be persisted in `batch`.
+    fn hash_node<S: Source>(
+        &self,
+        source: &S,
+        view: usize,
+        ino: u64,
+        batch: &Mutex<Vec<Update>>,
+    ) -> io::Result<(Digest, u64)> {
+        let generation = {
+            let mut nodes = self.nodes();
+            let cache = self.entry(&mut nodes, ino).caches[view];
+            match cache.hash {
+                Some(hash) if cache.valid => return Ok((hash, cache.generation)),
+                _ => cache.generation,
+            }
+        };
+
+        let mut used = Vec::new();
+        let mut durable = true;
+        let entries = match source.children(ino)? {
+            Some(listed) => {
+                let mut children = Vec::with_capacity(listed.len());
+                for child in listed {
+                    if self.includes(view, ino, &child)? {
+                        children.push(child);
+                    }
+                }
+                durable = children
+                    .iter()
+                    .all(|child| child.links == 1 || child.mode & libc::S_IFMT == libc::S_IFDIR);
+                for child in &children {
+                    self.ensure_attached(ino, child.ino);
+                }
+                let hashes = children
+                    .par_iter()
+                    .map(|child| self.hash_node(source, view, child.ino, batch))
+                    .collect::<io::Result<Vec<_>>>()?;
+                let mut entries = Vec::with_capacity(children.len());
+                for (child, (hash, child_generation)) in children.into_iter().zip(hashes) {
+                    used.push((child.ino, child_generation));
+                    entries.push(Entry {
+                        name: child.name,
+                        mode: child.mode,
+                        hash,
+                    });
+                }
+                Some(entries)
+            }
+            None => None,
+        };
+        let View {
+            hasher,
+            format,
+            attrs,
+            ..
+        } = self.views[view];
+        let mut hash = format.hash(hasher, entries, &|| source.data(ino))?;
+        if !attrs.is_empty() {
+            let encoded = source.attributes(ino, attrs)?;
+            hash = hasher.digest(&[hash.as_bytes(), &encoded].concat());
+        }
+        self.validate_into(view, ino, generation, hash, &used, durable, batch);
+        Ok((hash, generation))
+    }
+}
+
+impl Hook for Tree {
+    fn looked_up(&self, parent: u64, ino: u64) {
+        self.ensure_attached(parent, ino);
+    }
+
+    fn modified(&self, ino: u64) {
+        self.invalidate(ino);
+        for (i, view) in self.views.iter().enumerate() {
+            if let Some(ignore) = &view.ignore {
+                ignore.modified(self, i, ino);
+            }
+        }
+    }
+
+    /// Only views that hash one of `fields`, or whose format puts it in the
+    /// parent's hash, are affected.
+    fn attributes_changed(&self, ino: u64, fields: Attrs) {
+        self.clear(ino, |i| self.views[i].hashes(fields));
+    }
+
+    fn linked(&self, parent: u64, ino: u64) {
+        self.attach(parent, ino);
+        self.invalidate(parent);
+        for (i, view) in self.views.iter().enumerate() {
+            if let Some(ignore) = &view.ignore {
+                ignore.linked(self, i, parent, ino);
+            }
+        }
+    }
+
+    fn unlinked(&self, parent: u64, ino: u64) {
+        self.detach(parent, ino);
+        self.invalidate(parent);
+        for (i, view) in self.views.iter().enumerate() {
+            if let Some(ignore) = &view.ignore {
+                ignore.unlinked(self, i, parent, ino);
+            }
+        }
+    }
+}
+
+/// The tracked nodes at and below `ino`.
+fn below(nodes: &HashMap<u64, Node>, ino: u64) -> impl Iterator<Item = u64> + '_ {
+    fn within(nodes: &HashMap<u64, Node>, memo: &mut HashMap<u64, bool>, node: u64) -> bool {
+        if let Some(&within) = memo.get(&node) {
+            return within;
+        }
+        let parents = nodes.get(&node).map_or(&[][..], |node| &node.parents[..]);
+        let result = parents.iter().any(|&parent| within(nodes, memo, parent));
+        memo.insert(node, result);
+        result
+    }
+
+    let mut memo = HashMap::from([(ino, true)]);
+    nodes
+        .keys()
+        .copied()
+        .filter(move |&node| within(nodes, &mut memo, node))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::PLAIN;
+    use crate::hasher::{SHA1, SHA256};
+    use crate::metadata::Record;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    /// A directory tree held in memory, counting how often data is read.
+    #[derive(Default)]
+    struct Memory {
+        dirs: HashMap<u64, Vec<(&'static str, u64)>>,
+        files: HashMap<u64, &'static [u8]>,
+        reads: AtomicUsize,
+    }
+
+    impl Memory {
+        /// `1/{a, d/{b, c}}`, with `d` as inode 3.
+        fn sample() -> Self {
+            Memory {
+                dirs: HashMap::from([(1, vec![("a", 2), ("d", 3)]), (3, vec![("b", 4), ("c", 5)])]),
+                files: HashMap::from([(2, &b"a"[..]), (4, b"b"), (5, b"c")]),
+                reads: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Source for Memory {
+        fn children(&self, ino: u64) -> io::Result<Option<Vec<Child>>> {
+            Ok(self.dirs.get(&ino).map(|children| {
+                children
+                    .iter()
+                    .map(|&(name, ino)| Child {
+                        name: name.into(),
+                        ino,
+                        mode: match self.dirs.contains_key(&ino) {
+                            true => libc::S_IFDIR | 0o755,
+                            false => libc::S_IFREG | 0o644,
+                        },
+                        links: 1,
+                    })
+                    .collect()
+            }))
+        }
+
+        fn data(&self, ino: u64) -> io::Result<Data> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            let data = self.files.get(&ino).copied().unwrap_or_default();
+            Ok(Data {
+                len: data.len() as u64,
+                reader: Box::new(data),
+            })
+        }
+
+        fn attributes(&self, _ino: u64, _attrs: Attrs) -> io::Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn tree() -> Tree {
+        let view = View::new(
+            DEFAULT_VIEW.to_owned(),
+            &SHA1,
+            &PLAIN,
+            None,
+            Attrs::NONE,
+            None,
+        );
+        Tree::new(vec![view])
+    }
+
+    /// A backend that only remembers what it was asked to persist.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Update>>>);
+
+    impl MetadataStore for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn load(&self, _ino: u64) -> io::Result<Option<Record>> {
+            Ok(None)
+        }
+
+        fn validated(&self, ino: u64, hash: Digest, generation: u64) -> io::Result<()> {
+            self.0.lock().unwrap().push(Update::Validated {
+                ino,
+                hash,
+                generation,
+            });
+            Ok(())
+        }
+
+        fn invalidated(&self, ino: u64, generation: u64) -> io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(Update::Invalidated { ino, generation });
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn invalidation_stops_at_invalid_ancestors() {
+        let (tree, source) = (tree(), Memory::sample());
+        tree.hash(&source, 0, ROOT_INO).unwrap();
+        assert!((1..=5).all(|ino| tree.is_valid(0, ino)));
+
+        assert_eq!(tree.invalidate(4), 3);
+        assert!(!tree.is_valid(0, 3) && !tree.is_valid(0, ROOT_INO));
+        assert!(tree.is_valid(0, 2) && tree.is_valid(0, 5));
+        // Everything above 5 is already invalid.
+        assert_eq!(tree.invalidate(5), 1);
+        assert_eq!(tree.invalidate(5), 0);
+    }
+
+    #[test]
+    fn rehashing_only_reads_invalid_nodes() {
+        let (tree, mut source) = (tree(), Memory::sample());
+        let before = tree.hash(&source, 0, ROOT_INO).unwrap();
+        assert_eq!(source.reads.swap(0, Ordering::Relaxed), 5);
+        assert_eq!(tree.hash(&source, 0, ROOT_INO).unwrap(), before);
+        assert_eq!(source.reads.load(Ordering::Relaxed), 0);
+
+        source.files.insert(4, b"changed");
+        tree.invalidate(4);
+        assert_ne!(tree.hash(&source, 0, ROOT_INO).unwrap(), before);
+        // The file itself and the directories above it.
+        assert_eq!(source.reads.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn validation_loses_to_a_concurrent_invalidation() {
+        let tree = tree();
+        tree.attach(ROOT_INO, 2);
+        let hash = SHA1.digest(b"");
+        let generation = tree.node(2).unwrap().caches[0].generation;
+        // A write lands while the hash is being computed.
+        tree.invalidate(2);
+        assert!(!tree.validate(0, 2, generation, hash, &[], true));
+        assert!(!tree.is_valid(0, 2));
+
+        let generation = tree.node(2).unwrap().caches[0].generation;
+        assert!(tree.validate(0, 2, generation, hash, &[], true));
+    }
+
+    #[test]
+    fn validation_needs_children_at_the_generation_read() {
+        let tree = tree();
+        tree.attach(ROOT_INO, 2);
+        let hash = SHA1.digest(b"");
+        assert!(tree.validate(0, 2, 0, hash, &[], true));
+        let child = tree.node(2).unwrap().caches[0].generation;
+        let parent = tree.node(ROOT_INO).unwrap().caches[0].generation;
+
+        // The child changes and is rehashed before the parent is validated.
+        tree.invalidate(2);
+        let regenerated = tree.node(2).unwrap().caches[0].generation;
+        assert!(tree.validate(0, 2, regenerated, hash, &[], true));
+        let parent_now = tree.node(ROOT_INO).unwrap().caches[0].generation;
+        assert!(!tree.validate(0, ROOT_INO, parent_now, hash, &[(2, child)], true));
+        assert!(tree.validate(0, ROOT_INO, parent_now, hash, &[(2, regenerated)], true));
+        assert_ne!(parent, parent_now);
+    }
+
+    #[test]
+    fn persisted_in_the_order_made() {
+        let recorder = Recorder::default();
+        let view = View::new(
+            DEFAULT_VIEW.to_owned(),
+            &SHA1,
+            &PLAIN,
+            None,
+            Attrs::NONE,
+            Some(Box::new(recorder.clone())),
+        );
+        let (tree, source) = (Tree::new(vec![view]), Memory::sample());
+        tree.hash(&source, 0, ROOT_INO).unwrap();
+        let recorded = |from: usize| recorder.0.lock().unwrap()[from..].to_vec();
+        assert_eq!(recorded(0).len(), 5);
+
+        tree.invalidate(4);
+        let invalidated: Vec<u64> = recorded(5)
+            .iter()
+            .map(|update| match *update {
+                Update::Invalidated { ino, .. } => ino,
+                Update::Validated { .. } => panic!("{:?} is not an invalidation", update),
+            })
+            .collect();
+        assert_eq!(invalidated, [4, 3, ROOT_INO]);
+
+        // A validation overtaken by an invalidation before it is written.
+        let generation = tree.node(4).unwrap().caches[0].generation;
+        let batch = Mutex::new(Vec::new());
+        let hash = SHA1.digest(b"b");
+        assert!(tree.validate_into(0, 4, generation, hash, &[], true, &batch));
+        tree.invalidate(4);
+        tree.flush(0, batch);
+        assert_eq!(
+            recorded(8),
+            [Update::Invalidated {
+                ino: 4,
+                generation: generation + 1
+            }]
+        );
+    }
+
+    #[test]
+    fn views_keep_separate_caches() {
+        let source = Memory::sample();
+        let views =
+            [(DEFAULT_VIEW, &SHA1 as &dyn MerkleHasher), ("alt", &SHA256)].map(|(name, hasher)| {
+                View::new(name.to_owned(), hasher, &PLAIN, None, Attrs::NONE, None)
+            });
+        let tree = Tree::new(views.into());
+        assert_eq!(tree.view("alt").unwrap(), 1);
+        assert!(tree.view("other").is_err());
+
+        let default = tree.hash(&source, 0, ROOT_INO).unwrap();
+        assert!((1..=5).all(|ino| tree.is_valid(0, ino) && !tree.is_valid(1, ino)));
+        let alt = tree.hash(&source, 1, ROOT_INO).unwrap();
+        assert_ne!(default, alt);
+        assert_eq!(source.reads.swap(0, Ordering::Relaxed), 10);
+        let caches = tree.node(ROOT_INO).unwrap().caches;
+        assert_eq!((caches[0].hash, caches[1].hash), (Some(default), Some(alt)));
+
+        assert_eq!(tree.invalidate_view(1, 4), 3);
+        assert!((1..=5).all(|ino| tree.is_valid(0, ino)));
+        assert!(!tree.is_valid(1, 4) && !tree.is_valid(1, 3) && !tree.is_valid(1, ROOT_INO));
+        assert!(tree.is_valid(1, 2) && tree.is_valid(1, 5));
+        assert_eq!(tree.hash(&source, 0, ROOT_INO).unwrap(), default);
+        assert_eq!(source.reads.load(Ordering::Relaxed), 0);
+        assert_eq!(tree.hash(&source, 1, ROOT_INO).unwrap(), alt);
+        assert_eq!(source.reads.load(Ordering::Relaxed), 3);
+
+        // A change to what both views hash invalidates both.
+        tree.invalidate(2);
+        assert!(!tree.is_valid(0, ROOT_INO) && !tree.is_valid(1, ROOT_INO));
+    }
+}
@@ -0,0 +1,205 @@
+//! `mtfs watch`: runs a command, and runs it again whenever the hash of a tree
+//! in a mount changes.
+//!
+//! Changes are followed through a subscription on the query socket. A burst
+//! of them is waited out until the tree has been quiet for the debounce
+//! interval, and the command is only run again if the hash of the tree then
+//! differs from the hash it was last run on, so saving a file unchanged or
+//! undoing an edit runs nothing. Output the command writes inside the tree
+//! runs it once more, unless it is the same as last time. With `--view`, the
+//! hash compared is the one in that view, and changes it leaves out, such as
+//! to ignored build outputs, are not even waited out.
+
+use crate::usage_error;
+use mtfs_client::{locate, Client, Subscription};
+use std::ffi::OsString;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+/// How long the tree must be quiet before the command runs, by default.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+pub fn main(mut args: impl Iterator<Item = OsString>) -> ! {
+    let mut debounce = DEBOUNCE;
+    let mut view = None;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--debounce" {
+            let ms = args
+                .next()
+                .and_then(|ms| ms.to_str()?.parse().ok())
+                .unwrap_or_else(|| usage_error("--debounce requires a number of milliseconds"));
+            debounce = Duration::from_millis(ms);
+        } else if arg == "--view" {
+            view = Some(
+                args.next()
+                    .and_then(|view| view.into_string().ok())
+                    .unwrap_or_else(|| usage_error("--view requires a view name")),
+            );
+        } else if path.is_none() {
+            path = Some(PathBuf::from(arg));
+        } else {
+            usage_error("expected -- before the command");
+        }
+    }
+    let path = path.unwrap_or_else(|| usage_error("expected a path to watch"));
+    let command: Vec<OsString> = args.collect();
+    if command.is_empty() {
+        usage_error("expected a command to run");
+    }
+
+    if let Err(err) = watch(&path, view.as_deref(), &command, debounce) {
+        eprintln!("mtfs watch: {}", err);
+        process::exit(1);
+    }
+    process::exit(0)
+}
+
+fn watch(
+    path: &Path,
+    view: Option<&str>,
+    command: &[OsString],
+    debounce: Duration,
+) -> io::Result<()> {
+    let (socket, root) = locate(path)?;
+    // Subscribe first, so that nothing changing while the command first runs is missed.
+    let mut subscription = Subscription::connect(&socket, view, &root)?;
+    subscription.set_nonblocking(true)?;
+    let mut client = Client::connect(&socket)?;
+    if let Some(view) = view {
+        client.view(view)?;
+    }
+
+    let mut last = client.hash(&root)?;
+    run(command)?;
+    loop {
+        wait(&mut subscription, None)?;
+        // Wait out the rest of the burst.
+        while wait(&mut subscription, Some(debounce))? {}
+        let hash = client.hash(&root)?;
+        if hash != last {
+            last = hash;
+            run(command)?;
+        }
+    }
+}
+
+/// Waits up to `timeout`, or for as long as it takes, for changes, and reads
+/// every change that has arrived. Returns whether there were any.
+fn wait(subscription: &mut Subscription, timeout: Option<Duration>) -> io::Result<bool> {
+    // A deadline too far off to represent is never reached.
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    loop {
+        let ms = match deadline {
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .as_millis()
+                .min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let mut fd = libc::pollfd {
+            fd: subscription.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fd, 1, ms) } {
+            -1 => match io::Error::last_os_error() {
+                err if err.kind() == io::ErrorKind::Interrupted => continue,
+                err => return Err(err),
+            },
+            0 => return Ok(false),
+            _ => {}
+        }
+        let mut changed = false;
+        loop {
+            match subscription.recv() {
+                Ok(_) => changed = true,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        if changed {
+            return Ok(true);
+        }
+    }
+}
+
+/// Runs `command` to completion, reporting it if it fails.
+fn run(command: &[OsString]) -> io::Result<()> {
+    let status = Command::new(&command[0]).args(&command[1..]).status()?;
+    if !status.success() {
+        eprintln!(
+            "mtfs watch: {} exited with {}",
+            command[0].to_string_lossy(),
+            status
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::send;
+    use mtfs_client::protocol::{Hello, Response, VERSION};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    /// A subscription, and the end of it the server would write to.
+    fn subscription() -> (Subscription, UnixStream) {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let hello = Hello {
+            server: "test".to_owned(),
+            version: VERSION,
+            algorithm: "sha1".to_owned(),
+            format: "plain".to_owned(),
+            ignore: None,
+            ignore_file: None,
+            attrs: None,
+            view: None,
+            views: Vec::new(),
+        };
+        send(&mut theirs, &hello).unwrap();
+        send(&mut theirs, &Response::Subscribed).unwrap();
+        let subscription = Subscription::over(ours, None, "").unwrap();
+        subscription.set_nonblocking(true).unwrap();
+        (subscription, theirs)
+    }
+
+    fn invalidate(server: &mut UnixStream, path: &str) {
+        let path = PathBuf::from(path);
+        send(server, &Response::Invalidated { path }).unwrap();
+    }
+
+    #[test]
+    fn waits_for_changes_and_reads_them_all() {
+        let (mut subscription, mut server) = subscription();
+        let quiet = Some(Duration::from_millis(10));
+        assert!(!wait(&mut subscription, quiet).unwrap());
+
+        invalidate(&mut server, "a");
+        invalidate(&mut server, "b");
+        assert!(wait(&mut subscription, None).unwrap());
+        assert!(!wait(&mut subscription, quiet).unwrap());
+        assert!(!wait(&mut subscription, Some(Duration::ZERO)).unwrap());
+
+        // Longer than poll can be asked to wait for, or than can be added to now.
+        for timeout in [Duration::from_secs(1 << 32), Duration::MAX] {
+            let later = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                invalidate(&mut server, "c");
+                server
+            });
+            assert!(wait(&mut subscription, Some(timeout)).unwrap());
+            server = later.join().unwrap();
+        }
+
+        drop(server);
+        assert!(wait(&mut subscription, None).is_err());
+    }
+}
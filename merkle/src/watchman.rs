@@ -0,0 +1,854 @@
+//! A socket speaking Watchman's JSON protocol, so that tools written against
+//! Watchman can ask the mount what changed instead of crawling it.
+//!
+//! It is opened by the `watchman=<socket>` mount option, and clients find it
+//! through `WATCHMAN_SOCK`. Every request is a JSON array on a line of its own,
+//! answered by a JSON object on a line. The commands understood are `version`,
+//! `list-capabilities`, `watch`, `watch-project`, `clock` and `query`. Clocks
+//! are tokens of the change log, so a `since` query lists the paths changed
+//! through the mount since, and survives remounts the way the log does; a
+//! query without one, or with a clock the log cannot answer, lists everything
+//! and says it is a fresh instance.
+//!
+//! Queries support `relative_root`, `suffix`, `fields`,
+//! `empty_on_fresh_instance` and expressions built from `allof`, `anyof`,
+//! `not`, `true`, `false`, `exists`, `type`, `suffix`, `name`, `iname`,
+//! `match`, `imatch`, `dirname` and `idirname`. The log does not remember what
+//! type a deleted file had, so `type` matches every deleted file. Like
+//! Watchman, nothing inside `.git`, `.hg` or `.svn` is reported.
+
+use crate::changes::Changes;
+use crate::glob;
+use crate::server::{listen, send};
+use crate::store::{Store, META_DIR};
+use serde_json::{json, Map, Value};
+use std::fs::{self, Metadata};
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+const VERSION: &str = concat!("mtfs-", env!("CARGO_PKG_VERSION"));
+
+/// Files that make a directory the root of a project for `watch-project`.
+const ROOT_FILES: [&str; 3] = [".watchmanconfig", ".hg", ".git"];
+
+/// Directories whose contents are never reported.
+const IGNORE_VCS: [&str; 3] = [".git", ".hg", ".svn"];
+
+const FIELDS: [&str; 24] = [
+    "name",
+    "exists",
+    "new",
+    "type",
+    "size",
+    "mode",
+    "uid",
+    "gid",
+    "ino",
+    "dev",
+    "nlink",
+    "symlink_target",
+    "mtime",
+    "mtime_ms",
+    "mtime_us",
+    "mtime_ns",
+    "mtime_f",
+    "ctime",
+    "ctime_ms",
+    "ctime_us",
+    "ctime_ns",
+    "ctime_f",
+    "cclock",
+    "oclock",
+];
+
+const DEFAULT_FIELDS: [&str; 5] = ["name", "exists", "new", "size", "mode"];
+
+const CAPABILITIES: [&str; 20] = [
+    "relative_root",
+    "suffix-set",
+    "wildmatch",
+    "term-allof",
+    "term-anyof",
+    "term-not",
+    "term-true",
+    "term-false",
+    "term-exists",
+    "term-type",
+    "term-suffix",
+    "term-name",
+    "term-iname",
+    "term-match",
+    "term-imatch",
+    "term-dirname",
+    "term-idirname",
+    "cmd-watch-project",
+    "cmd-clock",
+    "cmd-query",
+];
+
+pub struct Watchman {
+    store: Arc<Store>,
+    changes: Arc<Changes>,
+    /// Where the store is mounted, as clients name it.
+    mountpoint: PathBuf,
+}
+
+impl Watchman {
+    pub fn new(store: Arc<Store>, changes: Arc<Changes>, mountpoint: PathBuf) -> Self {
+        Watchman {
+            store,
+            changes,
+            mountpoint,
+        }
+    }
+
+    /// Listens on `socket`, replacing any stale socket left there, and serves
+    /// connections on a background thread.
+    pub fn spawn(self, socket: &Path) -> io::Result<thread::JoinHandle<()>> {
+        let watchman = Arc::new(self);
+        listen(socket, "watchman", move |stream| watchman.serve(stream))
+    }
+
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str(&line) {
+                Ok(Value::Array(command)) => self.handle(&command),
+                Ok(_) => Err("commands must be arrays".to_owned()),
+                Err(err) => Err(format!("invalid JSON: {}", err)),
+            };
+            let mut response = response.unwrap_or_else(|message| json!({ "error": message }));
+            response["version"] = json!(VERSION);
+            send(&mut writer, &response)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&self, command: &[Value]) -> Result<Value, String> {
+        let (name, args) = match command.split_first() {
+            Some((Value::String(name), args)) => (name.as_str(), args),
+            _ => return Err("expected a command name".to_owned()),
+        };
+        match name {
+            "version" => version(args.first()),
+            "list-capabilities" => Ok(json!({ "capabilities": CAPABILITIES })),
+            "watch" => {
+                let root = self.root(args)?;
+                Ok(json!({ "watch": self.absolute(&root), "watcher": "mtfs" }))
+            }
+            "watch-project" => {
+                let path = self.root(args)?;
+                let root = path
+                    .ancestors()
+                    .find(|dir| {
+                        let dir = self.store.root().join(dir);
+                        ROOT_FILES.iter().any(|file| dir.join(file).exists())
+                    })
+                    .unwrap_or(&path);
+                let mut response = json!({ "watch": self.absolute(root), "watcher": "mtfs" });
+                let relative = path.strip_prefix(root).unwrap();
+                if !relative.as_os_str().is_empty() {
+                    response["relative_path"] = json!(relative.to_string_lossy());
+                }
+                Ok(response)
+            }
+            "clock" => {
+                let root = self.root(args)?;
+                Ok(json!({ "clock": self.changes.since(&root, None).token }))
+            }
+            "query" => {
+                let root = self.root(args)?;
+                let spec = match args.get(1) {
+                    Some(Value::Object(spec)) => spec,
+                    _ => return Err("query requires a query specification object".to_owned()),
+                };
+                self.query(&root, spec)
+            }
+            _ => Err(format!("unknown command {}", name)),
+        }
+    }
+
+    /// The store-relative directory named by the first argument of a command.
+    fn root(&self, args: &[Value]) -> Result<PathBuf, String> {
+        let Some(Value::String(path)) = args.first() else {
+            return Err("expected the path of a directory".to_owned());
+        };
+        let relative = Path::new(path)
+            .strip_prefix(&self.mountpoint)
+            .ok()
+            .filter(|relative| normal(relative))
+            .ok_or_else(|| {
+                format!(
+                    "unable to resolve root {}: not in the MTFS mount at {}",
+                    path,
+                    self.mountpoint.display()
+                )
+            })?;
+        match fs::symlink_metadata(self.store.root().join(relative)) {
+            Ok(meta) if meta.is_dir() => Ok(relative.to_owned()),
+            Ok(_) => Err(format!("unable to resolve root {}: not a directory", path)),
+            Err(err) => Err(format!("unable to resolve root {}: {}", path, err)),
+        }
+    }
+
+    fn absolute(&self, root: &Path) -> String {
+        self.mountpoint.join(root).to_string_lossy().into_owned()
+    }
+
+    fn query(&self, root: &Path, spec: &Map<String, Value>) -> Result<Value, String> {
+        let base = match spec.get("relative_root") {
+            None => root.to_owned(),
+            Some(Value::String(relative)) if normal(Path::new(relative)) => root.join(relative),
+            Some(_) => return Err("relative_root must be a path below the root".to_owned()),
+        };
+        let fields: Vec<&str> = match spec.get("fields") {
+            None => DEFAULT_FIELDS.to_vec(),
+            Some(Value::Array(fields)) => fields
+                .iter()
+                .map(|field| match field.as_str() {
+                    Some(field) if FIELDS.contains(&field) => Ok(field),
+                    _ => Err(format!("unknown field name {}", field)),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err("fields must be an array of field names".to_owned()),
+        };
+        let mut terms = Vec::new();
+        if let Some(expression) = spec.get("expression") {
+            terms.push(Term::parse(expression)?);
+        }
+        if let Some(suffix) = spec.get("suffix") {
+            terms.push(Term::parse(&json!(["suffix", suffix]))?);
+        }
+        let term = Term::AllOf(terms);
+
+        let since = spec.get("since").and_then(Value::as_str);
+        let changed = self.changes.since(&base, since);
+        let dir = self.store.root().join(&base);
+        let mut files = Vec::new();
+        let fresh = changed.paths.is_none();
+        match changed.paths {
+            Some(paths) => {
+                for changed in paths.into_iter().filter(|changed| !in_vcs(&changed.path)) {
+                    let meta = fs::symlink_metadata(dir.join(&changed.path)).ok();
+                    let subtree = changed.subtree && meta.as_ref().is_some_and(Metadata::is_dir);
+                    files.push((changed.path.clone(), meta));
+                    if subtree {
+                        self.walk(&dir, &changed.path, &mut files)
+                            .map_err(|err| err.to_string())?;
+                    }
+                }
+            }
+            None if spec.get("empty_on_fresh_instance") == Some(&Value::Bool(true)) => {}
+            None => self
+                .walk(&dir, Path::new(""), &mut files)
+                .map_err(|err| err.to_string())?,
+        }
+
+        let files: Vec<Value> = files
+            .iter()
+            .filter(|(name, meta)| term.matches(name.as_os_str().as_bytes(), meta.as_ref()))
+            .map(|(name, meta)| {
+                let values = fields.iter().map(|field| {
+                    let value = self.field(field, &dir, name, meta.as_ref(), &changed.token);
+                    (field.to_string(), value)
+                });
+                match &fields[..] {
+                    [_] => values.map(|(_, value)| value).next().unwrap(),
+                    _ => Value::Object(values.collect()),
+                }
+            })
+            .collect();
+        Ok(json!({
+            "clock": changed.token,
+            "is_fresh_instance": fresh,
+            "files": files,
+        }))
+    }
+
+    /// Adds everything below `dir.join(relative)` to `files`.
+    fn walk(
+        &self,
+        dir: &Path,
+        relative: &Path,
+        files: &mut Vec<(PathBuf, Option<Metadata>)>,
+    ) -> io::Result<()> {
+        let entries = match fs::read_dir(dir.join(relative)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let top = dir == self.store.root() && relative.as_os_str().is_empty();
+        for entry in entries {
+            let entry = entry?;
+            if top && entry.file_name() == META_DIR {
+                continue;
+            }
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let name = relative.join(entry.file_name());
+            let is_dir = meta.is_dir() && !IGNORE_VCS.iter().any(|vcs| entry.file_name() == *vcs);
+            files.push((name.clone(), Some(meta)));
+            if is_dir {
+                self.walk(dir, &name, files)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn field(
+        &self,
+        field: &str,
+        dir: &Path,
+        name: &Path,
+        meta: Option<&Metadata>,
+        clock: &str,
+    ) -> Value {
+        match field {
+            "name" => return json!(name.to_string_lossy()),
+            "exists" => return json!(meta.is_some()),
+            // The log cannot tell a new file from a changed one.
+            "new" => return json!(false),
+            "cclock" | "oclock" => return json!(clock),
+            _ => {}
+        }
+        let Some(meta) = meta else {
+            return Value::Null;
+        };
+        let time = |secs: i64, nsecs: i64, unit: &str| match unit {
+            "" => json!(secs),
+            "_ms" => json!(secs * 1_000 + nsecs / 1_000_000),
+            "_us" => json!(secs * 1_000_000 + nsecs / 1_000),
+            "_ns" => json!(secs * 1_000_000_000 + nsecs),
+            _ => json!(secs as f64 + nsecs as f64 / 1e9),
+        };
+        match field {
+            "type" => json!(type_char(meta).to_string()),
+            "size" => json!(meta.len()),
+            "mode" => json!(meta.mode()),
+            "uid" => json!(meta.uid()),
+            "gid" => json!(meta.gid()),
+            "ino" => json!(meta.ino()),
+            "dev" => json!(meta.dev()),
+            "nlink" => json!(meta.nlink()),
+            "symlink_target" if meta.file_type().is_symlink() => fs::read_link(dir.join(name))
+                .map_or(Value::Null, |target| json!(target.to_string_lossy())),
+            "symlink_target" => Value::Null,
+            _ => match field.split_at(5) {
+                ("mtime", unit) => time(meta.mtime(), meta.mtime_nsec(), unit),
+                (_, unit) => time(meta.ctime(), meta.ctime_nsec(), unit),
+            },
+        }
+    }
+}
+
+/// Answers `version`, checking any capabilities the client asks about.
+fn version(args: Option<&Value>) -> Result<Value, String> {
+    let Some(args) = args else {
+        return Ok(json!({}));
+    };
+    let names = |key: &str| -> Vec<String> {
+        args.get(key)
+            .and_then(Value::as_array)
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let mut capabilities = Map::new();
+    for name in names("optional").into_iter().chain(names("required")) {
+        capabilities.insert(name.clone(), json!(CAPABILITIES.contains(&&*name)));
+    }
+    if let Some(missing) = names("required")
+        .into_iter()
+        .find(|name| !CAPABILITIES.contains(&&**name))
+    {
+        return Err(format!(
+            "client required capability `{}` is not supported by this server",
+            missing
+        ));
+    }
+    Ok(json!({ "capabilities": capabilities }))
+}
+
+/// Whether `path` is inside a directory whose contents are not reported.
+fn in_vcs(path: &Path) -> bool {
+    let mut parents = path.components();
+    parents.next_back();
+    parents.any(|component| IGNORE_VCS.iter().any(|vcs| component.as_os_str() == *vcs))
+}
+
+/// Whether `path` is relative and goes only downwards.
+fn normal(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn type_char(meta: &Metadata) -> char {
+    let kind = meta.file_type();
+    if kind.is_file() {
+        'f'
+    } else if kind.is_dir() {
+        'd'
+    } else if kind.is_symlink() {
+        'l'
+    } else if kind.is_block_device() {
+        'b'
+    } else if kind.is_char_device() {
+        'c'
+    } else if kind.is_fifo() {
+        'p'
+    } else {
+        's'
+    }
+}
+
+enum Term {
+    True,
+    False,
+    Exists,
+    AllOf(Vec<Term>),
+    AnyOf(Vec<Term>),
+    Not(Box<Term>),
+    Type(char),
+    /// Lowercase, without the dot.
+    Suffix(Vec<String>),
+    Name {
+        names: Vec<String>,
+        whole: bool,
+        fold_case: bool,
+    },
+    Match {
+        pattern: String,
+        whole: bool,
+        options: glob::Options,
+    },
+    Dirname {
+        dir: String,
+        fold_case: bool,
+    },
+}
+
+impl Term {
+    fn parse(expression: &Value) -> Result<Term, String> {
+        let (name, args) = match expression {
+            Value::String(name) => (name.as_str(), &[][..]),
+            Value::Array(terms) => match terms.split_first() {
+                Some((Value::String(name), args)) => (name.as_str(), args),
+                _ => return Err("expected a term name".to_owned()),
+            },
+            _ => return Err(format!("invalid expression term {}", expression)),
+        };
+        let strings = |value: Option<&Value>| -> Result<Vec<String>, String> {
+            match value {
+                Some(Value::String(string)) => Ok(vec![string.clone()]),
+                Some(Value::Array(values)) => values
+                    .iter()
+                    .map(|value| {
+                        value
+                            .as_str()
+                            .map(str::to_owned)
+                            .ok_or_else(|| format!("{} expects strings", name))
+                    })
+                    .collect(),
+                _ => Err(format!("{} expects a string or an array of strings", name)),
+            }
+        };
+        let scope = |value: Option<&Value>| match value.and_then(Value::as_str) {
+            None | Some("basename") => Ok(false),
+            Some("wholename") => Ok(true),
+            Some(scope) => Err(format!("invalid scope {} for {}", scope, name)),
+        };
+        Ok(match name {
+            "true" => Term::True,
+            "false" => Term::False,
+            "exists" => Term::Exists,
+            "allof" | "anyof" => {
+                let terms = args.iter().map(Term::parse).collect::<Result<_, _>>()?;
+                if name == "allof" {
+                    Term::AllOf(terms)
+                } else {
+                    Term::AnyOf(terms)
+                }
+            }
+            "not" => match args {
+                [term] => Term::Not(Box::new(Term::parse(term)?)),
+                _ => return Err("not expects one term".to_owned()),
+            },
+            "type" => match args.first().and_then(Value::as_str) {
+                Some(kind) if kind.len() == 1 && "bcdfpls".contains(kind) => {
+                    Term::Type(kind.chars().next().unwrap())
+                }
+                _ => return Err("type expects one of b, c, d, f, p, l or s".to_owned()),
+            },
+            "suffix" => Term::Suffix(
+                strings(args.first())?
+                    .into_iter()
+                    .map(|suffix| suffix.to_ascii_lowercase())
+                    .collect(),
+            ),
+            "name" | "iname" => Term::Name {
+                names: strings(args.first())?,
+                whole: scope(args.get(1))?,
+                fold_case: name == "iname",
+            },
+            "match" | "imatch" => Term::Match {
+                pattern: args
+                    .first()
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| format!("{} expects a pattern", name))?
+                    .to_owned(),
+                whole: scope(args.get(1))?,
+                options: glob::Options {
+                    fold_case: name == "imatch",
+                    hide_dotfiles: args
+                        .get(2)
+                        .and_then(|options| options.get("includedotfiles"))
+                        != Some(&Value::Bool(true)),
+                },
+            },
+            "dirname" | "idirname" => match args {
+                [Value::String(dir)] => Term::Dirname {
+                    dir: dir.trim_end_matches('/').to_owned(),
+                    fold_case: name == "idirname",
+                },
+                _ => return Err(format!("{} expects a directory and no depth", name)),
+            },
+            _ => return Err(format!("unknown expression term {}", name)),
+        })
+    }
+
+    /// Whether the file at the relative path `name` matches, given its
+    /// metadata if it exists.
+    fn matches(&self, name: &[u8], meta: Option<&Metadata>) -> bool {
+        let basename = name.rsplit(|&b| b == b'/').next().unwrap_or(name);
+        let same = |a: &[u8], b: &[u8], fold_case: bool| {
+            if fold_case {
+                a.eq_ignore_ascii_case(b)
+            } else {
+                a == b
+            }
+        };
+        match self {
+            Term::True => true,
+            Term::False => false,
+            Term::Exists => meta.is_some(),
+            Term::AllOf(terms) => terms.iter().all(|term| term.matches(name, meta)),
+            Term::AnyOf(terms) => terms.iter().any(|term| term.matches(name, meta)),
+            Term::Not(term) => !term.matches(name, meta),
+            Term::Type(kind) => meta.is_none_or(|meta| type_char(meta) == *kind),
+            Term::Suffix(suffixes) => match basename.iter().rposition(|&b| b == b'.') {
+                Some(dot) => {
+                    let suffix = basename[dot + 1..].to_ascii_lowercase();
+                    suffixes.iter().any(|s| s.as_bytes() == suffix)
+                }
+                None => false,
+            },
+            Term::Name {
+                names,
+                whole,
+                fold_case,
+            } => {
+                let target = if *whole { name } else { basename };
+                names
+                    .iter()
+                    .any(|name| same(name.as_bytes(), target, *fold_case))
+            }
+            Term::Match {
+                pattern,
+                whole,
+                options,
+            } => {
+                let target = if *whole { name } else { basename };
+                glob::matches(pattern.as_bytes(), target, *options)
+            }
+            Term::Dirname { dir, fold_case } => {
+                dir.is_empty()
+                    || (name.len() > dir.len()
+                        && name[dir.len()] == b'/'
+                        && same(&name[..dir.len()], dir.as_bytes(), *fold_case))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn watchman(dir: &Path) -> Watchman {
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("b.rs"), "b").unwrap();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/C.TXT"), "c").unwrap();
+        let store = Arc::new(Store::open(dir.to_owned()).unwrap());
+        let changes = Arc::new(Changes::open(&store).unwrap());
+        Watchman::new(store, changes, PathBuf::from("/mnt"))
+    }
+
+    fn query(watchman: &Watchman, spec: Value) -> Result<Value, String> {
+        watchman.handle(json!(["query", "/mnt", spec]).as_array().unwrap())
+    }
+
+    /// The sorted names a query with `spec` lists.
+    fn names(watchman: &Watchman, mut spec: Value) -> Vec<String> {
+        spec["fields"] = json!(["name"]);
+        let response = query(watchman, spec).unwrap();
+        let mut names: Vec<String> = response["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|name| name.as_str().unwrap().to_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn matching(watchman: &Watchman, expression: Value) -> Vec<String> {
+        names(watchman, json!({ "expression": expression }))
+    }
+
+    #[test]
+    fn since_a_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let watchman = watchman(dir.path());
+        let response = query(&watchman, json!({ "fields": ["name"] })).unwrap();
+        assert_eq!(response["is_fresh_instance"], json!(true));
+        assert_eq!(
+            names(&watchman, json!({})),
+            ["a.txt", "b.rs", "sub", "sub/C.TXT"]
+        );
+
+        let clock = watchman.handle(&[json!("clock"), json!("/mnt")]).unwrap()["clock"].clone();
+        fs::remove_file(dir.path().join("a.txt")).unwrap();
+        watchman
+            .changes
+            .record(&[&dir.path().join("a.txt")], false, None);
+        let response = query(
+            &watchman,
+            json!({ "since": clock, "fields": ["name", "exists", "type"] }),
+        )
+        .unwrap();
+        assert_eq!(response["is_fresh_instance"], json!(false));
+        assert_eq!(
+            response["files"],
+            json!([{ "name": "a.txt", "exists": false, "type": null }])
+        );
+        // Deleted files match any type, having none left.
+        assert_eq!(
+            names(
+                &watchman,
+                json!({ "since": clock, "expression": ["type", "d"] })
+            ),
+            ["a.txt"]
+        );
+        assert_ne!(response["clock"], clock);
+        let response = query(&watchman, json!({ "since": response["clock"] })).unwrap();
+        assert_eq!(response["files"], json!([]));
+
+        // A clock from elsewhere cannot be answered, so everything is listed.
+        let foreign = json!({ "since": "c:1234:5" });
+        assert_eq!(names(&watchman, foreign), ["b.rs", "sub", "sub/C.TXT"]);
+        let response = query(
+            &watchman,
+            json!({ "since": "c:1234:5", "empty_on_fresh_instance": true }),
+        )
+        .unwrap();
+        assert_eq!(response["is_fresh_instance"], json!(true));
+        assert_eq!(response["files"], json!([]));
+    }
+
+    #[test]
+    fn expression_terms() {
+        let dir = tempfile::tempdir().unwrap();
+        let watchman = watchman(dir.path());
+        assert_eq!(matching(&watchman, json!(["type", "d"])), ["sub"]);
+        assert_eq!(matching(&watchman, json!(["not", ["type", "f"]])), ["sub"]);
+        assert_eq!(
+            matching(&watchman, json!(["suffix", "txt"])),
+            ["a.txt", "sub/C.TXT"]
+        );
+        assert_eq!(
+            matching(&watchman, json!(["suffix", ["rs", "md"]])),
+            ["b.rs"]
+        );
+        assert_eq!(names(&watchman, json!({ "suffix": ["rs"] })), ["b.rs"]);
+        assert_eq!(matching(&watchman, json!(["match", "*.txt"])), ["a.txt"]);
+        assert_eq!(
+            matching(&watchman, json!(["imatch", "*.txt"])),
+            ["a.txt", "sub/C.TXT"]
+        );
+        assert_eq!(
+            matching(&watchman, json!(["match", "sub/*", "wholename"])),
+            ["sub/C.TXT"]
+        );
+        assert_eq!(
+            matching(&watchman, json!(["dirname", "sub/"])),
+            ["sub/C.TXT"]
+        );
+        assert_eq!(
+            matching(&watchman, json!(["dirname", "SUB"])),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            matching(&watchman, json!(["idirname", "SUB"])),
+            ["sub/C.TXT"]
+        );
+        assert_eq!(
+            matching(
+                &watchman,
+                json!(["anyof", ["iname", "c.txt"], ["name", "b.rs", "wholename"]])
+            ),
+            ["b.rs", "sub/C.TXT"]
+        );
+    }
+
+    #[test]
+    fn relative_roots_stay_below_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let watchman = watchman(dir.path());
+        assert_eq!(
+            names(&watchman, json!({ "relative_root": "sub" })),
+            ["C.TXT"]
+        );
+        for relative in ["..", "sub/../..", "/etc"] {
+            assert_eq!(
+                query(&watchman, json!({ "relative_root": relative })),
+                Err("relative_root must be a path below the root".to_owned())
+            );
+        }
+        assert!(normal(Path::new("a/b")));
+        assert!(!normal(Path::new("a/../b")));
+        assert!(!normal(Path::new("/a")));
+    }
+
+    #[test]
+    fn fields_are_projected() {
+        let dir = tempfile::tempdir().unwrap();
+        let watchman = watchman(dir.path());
+        let meta = fs::symlink_metadata(dir.path().join("a.txt")).unwrap();
+        let response = query(
+            &watchman,
+            json!({
+                "expression": ["name", "a.txt"],
+                "fields": ["name", "size", "type", "mtime", "mtime_ms", "ctime_ns", "ctime_f"],
+            }),
+        )
+        .unwrap();
+        let file = &response["files"][0];
+        assert_eq!(file["name"], json!("a.txt"));
+        assert_eq!(file["size"], json!(1));
+        assert_eq!(file["type"], json!("f"));
+        assert_eq!(file["mtime"], json!(meta.mtime()));
+        assert_eq!(
+            file["mtime_ms"],
+            json!(meta.mtime() * 1_000 + meta.mtime_nsec() / 1_000_000)
+        );
+        assert_eq!(
+            file["ctime_ns"],
+            json!(meta.ctime() * 1_000_000_000 + meta.ctime_nsec())
+        );
+        assert_eq!(
+            file["ctime_f"],
+            json!(meta.ctime() as f64 + meta.ctime_nsec() as f64 / 1e9)
+        );
+    }
+
+    #[test]
+    fn malformed_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let watchman = watchman(dir.path());
+        let handle = |command: Value| watchman.handle(command.as_array().unwrap()).unwrap_err();
+        assert_eq!(handle(json!([])), "expected a command name");
+        assert_eq!(handle(json!(["bogus"])), "unknown command bogus");
+        assert_eq!(handle(json!(["query"])), "expected the path of a directory");
+        assert_eq!(
+            handle(json!(["query", "/elsewhere", {}])),
+            "unable to resolve root /elsewhere: not in the MTFS mount at /mnt"
+        );
+        assert_eq!(
+            handle(json!(["query", "/mnt/a.txt", {}])),
+            "unable to resolve root /mnt/a.txt: not a directory"
+        );
+        assert_eq!(
+            handle(json!(["query", "/mnt"])),
+            "query requires a query specification object"
+        );
+        assert_eq!(
+            handle(json!(["version", { "required": ["term-pcre"] }])),
+            "client required capability `term-pcre` is not supported by this server"
+        );
+
+        let error = |spec: Value| query(&watchman, spec).unwrap_err();
+        assert_eq!(
+            error(json!({ "fields": "name" })),
+            "fields must be an array of field names"
+        );
+        assert_eq!(
+            error(json!({ "fields": ["name", "color"] })),
+            "unknown field name \"color\""
+        );
+        let term = |expression: Value| error(json!({ "expression": expression }));
+        assert_eq!(term(json!(3)), "invalid expression term 3");
+        assert_eq!(term(json!([])), "expected a term name");
+        assert_eq!(term(json!(["pcre", "x"])), "unknown expression term pcre");
+        assert_eq!(term(json!(["not"])), "not expects one term");
+        assert_eq!(
+            term(json!(["type", "x"])),
+            "type expects one of b, c, d, f, p, l or s"
+        );
+        assert_eq!(
+            term(json!(["name", 3])),
+            "name expects a string or an array of strings"
+        );
+        assert_eq!(term(json!(["suffix", ["rs", 3]])), "suffix expects strings");
+        assert_eq!(term(json!(["match"])), "match expects a pattern");
+        assert_eq!(
+            term(json!(["imatch", "*", "everywhere"])),
+            "invalid scope everywhere for imatch"
+        );
+        assert_eq!(
+            term(json!(["dirname", "sub", ["depth", "ge", 1]])),
+            "dirname expects a directory and no depth"
+        );
+    }
+
+    #[test]
+    fn serves_lines_of_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let watchman = watchman(dir.path());
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let thread = thread::spawn(move || watchman.serve(server));
+        client
+            .write_all(b"[\"version\"]\n\n{}\nnot json\n")
+            .unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let responses: Vec<Value> = BufReader::new(client)
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        thread.join().unwrap().unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0], json!({ "version": VERSION }));
+        assert_eq!(responses[1]["error"], json!("commands must be arrays"));
+        assert!(responses[2]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid JSON: "));
+    }
+}